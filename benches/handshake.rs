@@ -0,0 +1,129 @@
+use std::io::IoSlice;
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use noise::{Dh, DynCipher, Initiator, NoiseCodec, ProtocolVersion, Responder, Secp256k1, X25519};
+
+const CERT_VALIDITY: u32 = 31449600;
+
+/// `AEAD_CIPHERS` offering AES-256-GCM
+const CIPHER_LIST: [u8; 5] = [1, 0x47, 0x53, 0x45, 0x41];
+
+/// Full handshake with the allocation free API, return the initiator codec
+fn handshake<D: Dh>(
+    mut initiator: Initiator<DynCipher, D>,
+    mut responder: Responder<DynCipher, D>,
+) -> NoiseCodec {
+    let mut first_message = [0; 64];
+    let mut second_message = [0; 512];
+    let mut cipher_list = [0; 129];
+    let mut cipher_choice = [0; 5];
+    let len = initiator.step_0_slice(&mut first_message).unwrap();
+    let len = responder
+        .step_1_slice(&first_message[..len], CERT_VALIDITY, &mut second_message)
+        .unwrap();
+    let len = initiator
+        .step_2_slice(&second_message[..len], &mut cipher_list)
+        .unwrap();
+    let (len, _) = responder
+        .step_3_slice(&cipher_list[..len], &mut cipher_choice)
+        .unwrap();
+    initiator.step_4_slice(&cipher_choice[..len]).unwrap()
+}
+
+fn bench_handshake(c: &mut Criterion) {
+    let authority = Secp256k1::generate_key();
+    let mut group = c.benchmark_group("handshake");
+    group.throughput(Throughput::Elements(1));
+    for (name, version) in [
+        ("secp256k1", ProtocolVersion::default()),
+        ("secp256k1_ellswift", ProtocolVersion::EllSwift),
+    ] {
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                handshake(
+                    *Initiator::new_with_version(authority.x_only_public_key().0, version),
+                    *Responder::new_with_version(authority, version),
+                )
+            })
+        });
+    }
+    group.bench_function("x25519", |b| {
+        b.iter(|| {
+            handshake(
+                *Initiator::with_dh(authority.x_only_public_key().0, X25519),
+                *Responder::with_dh(X25519.generate_keypair(), authority, X25519),
+            )
+        })
+    });
+    group.finish();
+}
+
+/// Work done by a responder for each connecting initiator
+fn bench_responder(c: &mut Criterion) {
+    let authority = Secp256k1::generate_key();
+    let mut group = c.benchmark_group("responder");
+    group.throughput(Throughput::Elements(1));
+    group.bench_function("secp256k1", |b| {
+        b.iter_batched(
+            || {
+                let mut initiator = Initiator::<DynCipher>::new(authority.x_only_public_key().0);
+                let mut first_message = [0; 32];
+                initiator.step_0_slice(&mut first_message).unwrap();
+                first_message
+            },
+            |first_message: [u8; 32]| {
+                let mut responder = Responder::<DynCipher>::new(authority);
+                let mut second_message = [0; 234];
+                responder
+                    .step_1_slice(&first_message, CERT_VALIDITY, &mut second_message)
+                    .unwrap();
+                let mut cipher_choice = [0; 5];
+                responder
+                    .step_3_slice(&CIPHER_LIST, &mut cipher_choice)
+                    .unwrap()
+            },
+            BatchSize::SmallInput,
+        )
+    });
+    group.finish();
+}
+
+/// Many small messages, such as share submissions, one at a time and in a batch
+fn bench_transport(c: &mut Criterion) {
+    const MESSAGES: usize = 100;
+    const MESSAGE_LEN: usize = 48;
+    let authority = Secp256k1::generate_key();
+    let mut codec = handshake(
+        *Initiator::new(authority.x_only_public_key().0),
+        *Responder::new(authority),
+    );
+    let messages = [[7; MESSAGE_LEN]; MESSAGES];
+    let slices: Vec<IoSlice> = messages.iter().map(|m| IoSlice::new(m)).collect();
+    let mut out = vec![0; MESSAGES * (MESSAGE_LEN + 16)];
+
+    let mut group = c.benchmark_group("transport");
+    group.throughput(Throughput::Elements(MESSAGES as u64));
+    group.bench_function("encrypt_vec", |b| {
+        b.iter(|| {
+            for message in &messages {
+                let mut message = message.to_vec();
+                codec.encrypt(&mut message).unwrap();
+            }
+        })
+    });
+    group.bench_function("encrypt_to", |b| {
+        b.iter(|| {
+            let mut offset = 0;
+            for message in &messages {
+                offset += codec.encrypt_to(message, &mut out[offset..]).unwrap();
+            }
+        })
+    });
+    group.bench_function("encrypt_batch", |b| {
+        b.iter(|| codec.encrypt_batch(&slices, &mut out).unwrap())
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_handshake, bench_responder, bench_transport);
+criterion_main!(benches);
@@ -1,70 +1,341 @@
+use chacha20poly1305::aead::consts::U16;
+use chacha20poly1305::aead::generic_array::typenum::Unsigned;
+use chacha20poly1305::aead::{Buffer, Nonce};
+use chacha20poly1305::{AeadInPlace, ChaCha20Poly1305, KeyInit, XChaCha20Poly1305};
+
 use aes_gcm::Aes256Gcm;
-use chacha20poly1305::aead::Buffer;
-use chacha20poly1305::AeadInPlace;
-use chacha20poly1305::ChaCha20Poly1305;
-use chacha20poly1305::ChaChaPoly1305;
-use chacha20poly1305::KeyInit;
+use aes_gcm_siv::Aes256GcmSiv;
+use std::alloc::{self, Layout};
+use std::{mem, ptr};
+
+use crate::cipher_registry::{CipherCode, CipherRegistry, AESG, ASIV, XCHA};
+
+/// Longest nonce of the ciphers of this crate, XChaCha20-Poly1305 use 24 bytes
+pub const MAX_NONCE_LEN: usize = 24;
 
+/// AEAD with 16 bytes tags and nonces of [`AeadCipher::nonce_len`] bytes. It is implemented for
+/// every `aead` cipher such as `ChaCha20Poly1305`, `Aes256Gcm`, `Aes256GcmSiv` and
+/// `XChaCha20Poly1305`, and it can be used as a trait object.
 pub trait AeadCipher {
-    fn from_key(k: [u8; 32]) -> Self;
+    /// Length of the nonce, between 8 and [`MAX_NONCE_LEN`] bytes
+    fn nonce_len(&self) -> usize;
+
+    /// Encrypt `data` in place and return the tag, never allocate. Fail if `nonce` is not
+    /// [`AeadCipher::nonce_len`] bytes.
+    fn encrypt_detached(
+        &mut self,
+        nonce: &[u8],
+        ad: &[u8],
+        data: &mut [u8],
+    ) -> Result<[u8; 16], aes_gcm::Error>;
 
+    /// Decrypt `data` in place checking it against `tag`, never allocate
+    fn decrypt_detached(
+        &mut self,
+        nonce: &[u8],
+        ad: &[u8],
+        data: &mut [u8],
+        tag: &[u8; 16],
+    ) -> Result<(), aes_gcm::Error>;
+
+    /// Encrypt `data` in place and append the tag
     fn encrypt<T: Buffer>(
         &mut self,
-        nonce: &[u8; 12],
+        nonce: &[u8],
         ad: &[u8],
         data: &mut T,
-    ) -> Result<(), aes_gcm::Error>;
+    ) -> Result<(), aes_gcm::Error>
+    where
+        Self: Sized,
+    {
+        let tag = self.encrypt_detached(nonce, ad, data.as_mut())?;
+        data.extend_from_slice(&tag)
+    }
 
+    /// Decrypt `data` in place and remove the tag
     fn decrypt<T: Buffer>(
         &mut self,
-        nonce: &[u8; 12],
+        nonce: &[u8],
         ad: &[u8],
         data: &mut T,
-    ) -> Result<(), aes_gcm::Error>;
+    ) -> Result<(), aes_gcm::Error>
+    where
+        Self: Sized,
+    {
+        let len = data.len().checked_sub(16).ok_or(aes_gcm::Error)?;
+        let (msg, tag) = data.as_mut().split_at_mut(len);
+        let tag: &[u8; 16] = (&tag[..]).try_into().unwrap();
+        self.decrypt_detached(nonce, ad, msg, tag)?;
+        data.truncate(len);
+        Ok(())
+    }
 }
 
-impl AeadCipher for ChaCha20Poly1305 {
-    fn from_key(k: [u8; 32]) -> Self {
-        ChaChaPoly1305::new(&k.into())
+impl<T: AeadInPlace<TagSize = U16>> AeadCipher for T {
+    fn nonce_len(&self) -> usize {
+        T::NonceSize::USIZE
     }
 
-    fn encrypt<T: Buffer>(
+    fn encrypt_detached(
         &mut self,
-        nonce: &[u8; 12],
+        nonce: &[u8],
         ad: &[u8],
-        data: &mut T,
-    ) -> Result<(), aes_gcm::Error> {
-        self.encrypt_in_place(nonce.into(), ad, data)
+        data: &mut [u8],
+    ) -> Result<[u8; 16], aes_gcm::Error> {
+        if nonce.len() != T::NonceSize::USIZE {
+            return Err(aes_gcm::Error);
+        }
+        self.encrypt_in_place_detached(Nonce::<T>::from_slice(nonce), ad, data)
+            .map(|tag| tag.into())
     }
 
-    fn decrypt<T: Buffer>(
+    fn decrypt_detached(
         &mut self,
-        nonce: &[u8; 12],
+        nonce: &[u8],
         ad: &[u8],
-        data: &mut T,
+        data: &mut [u8],
+        tag: &[u8; 16],
     ) -> Result<(), aes_gcm::Error> {
-        self.decrypt_in_place(nonce.into(), ad, data)
+        if nonce.len() != T::NonceSize::USIZE {
+            return Err(aes_gcm::Error);
+        }
+        self.decrypt_in_place_detached(Nonce::<T>::from_slice(nonce), ad, data, tag.into())
     }
 }
 
-impl AeadCipher for Aes256Gcm {
-    fn from_key(k: [u8; 32]) -> Self {
-        Aes256Gcm::new(&k.into())
+/// Cipher negotiated at runtime, see [`crate::CipherRegistry`]
+pub struct DynCipher(pub Box<dyn AeadCipher + Send>);
+
+impl AeadCipher for DynCipher {
+    fn nonce_len(&self) -> usize {
+        self.0.nonce_len()
     }
-    fn encrypt<T: Buffer>(
+
+    fn encrypt_detached(
         &mut self,
-        nonce: &[u8; 12],
+        nonce: &[u8],
         ad: &[u8],
-        data: &mut T,
-    ) -> Result<(), aes_gcm::Error> {
-        self.encrypt_in_place(nonce.into(), ad, data)
+        data: &mut [u8],
+    ) -> Result<[u8; 16], aes_gcm::Error> {
+        self.0.encrypt_detached(nonce, ad, data)
     }
-    fn decrypt<T: Buffer>(
+
+    fn decrypt_detached(
         &mut self,
-        nonce: &[u8; 12],
+        nonce: &[u8],
         ad: &[u8],
-        data: &mut T,
+        data: &mut [u8],
+        tag: &[u8; 16],
     ) -> Result<(), aes_gcm::Error> {
-        self.decrypt_in_place(nonce.into(), ad, data)
+        self.0.decrypt_detached(nonce, ad, data, tag)
+    }
+}
+
+impl Drop for DynCipher {
+    fn drop(&mut self) {
+        let cipher = mem::replace(&mut self.0, Box::new(Erased));
+        let layout = Layout::for_value(&*cipher);
+        let cipher = Box::into_raw(cipher);
+        unsafe {
+            drop_and_wipe(cipher);
+            if layout.size() != 0 {
+                alloc::dealloc(cipher as *mut u8, layout);
+            }
+        }
+    }
+}
+
+/// Placeholder left in a [`DynCipher`] while it is dropped
+struct Erased;
+
+impl AeadCipher for Erased {
+    fn nonce_len(&self) -> usize {
+        12
+    }
+
+    fn encrypt_detached(
+        &mut self,
+        _: &[u8],
+        _: &[u8],
+        _: &mut [u8],
+    ) -> Result<[u8; 16], aes_gcm::Error> {
+        Err(aes_gcm::Error)
+    }
+
+    fn decrypt_detached(
+        &mut self,
+        _: &[u8],
+        _: &[u8],
+        _: &mut [u8],
+        _: &[u8; 16],
+    ) -> Result<(), aes_gcm::Error> {
+        Err(aes_gcm::Error)
+    }
+}
+
+/// Drop the value at `value` and overwrite with zeros the memory where it was stored. The
+/// ciphers erase their key schedule on drop, but copies of the key can be left in the padding
+/// and in the unused variants of the unions of the backends (eg AES soft and AES-NI).
+///
+/// # Safety
+///
+/// `value` must be valid for writes and it must not be used after this call.
+pub(crate) unsafe fn drop_and_wipe<T: ?Sized>(value: *mut T) {
+    let size = mem::size_of_val(&*value);
+    ptr::drop_in_place(value);
+    let bytes = value as *mut u8;
+    for i in 0..size {
+        ptr::write_volatile(bytes.add(i), 0);
+    }
+}
+
+/// Cipher of a [`crate::NoiseCodec`], built with the key derived by the handshake once the
+/// cipher is negotiated. The code `None` is ChaChaPoly.
+///
+/// [`DynCipher`] can be any cipher of the [`CipherRegistry`], the other implementations only
+/// support their own code so the initiator and the responder never negotiate anything else.
+pub trait TransportCipher: AeadCipher + Sized {
+    /// True if the cipher negotiated as `code` can be a `Self`
+    fn supports(code: Option<CipherCode>) -> bool;
+
+    /// Build the cipher negotiated as `code`, `registry` is the one used for the negotiation
+    fn build(registry: &CipherRegistry, code: Option<CipherCode>, k: [u8; 32]) -> Option<Self>;
+}
+
+impl TransportCipher for DynCipher {
+    fn supports(_code: Option<CipherCode>) -> bool {
+        true
+    }
+
+    fn build(registry: &CipherRegistry, code: Option<CipherCode>, k: [u8; 32]) -> Option<Self> {
+        match code {
+            None => Some(DynCipher(Box::new(ChaCha20Poly1305::new(&k.into())))),
+            Some(code) => registry.build(code, k),
+        }
+    }
+}
+
+fn build_typed<C: TransportCipher + KeyInit>(code: Option<CipherCode>, k: [u8; 32]) -> Option<C> {
+    match C::supports(code) {
+        true => C::new_from_slice(&k).ok(),
+        false => None,
+    }
+}
+
+impl TransportCipher for ChaCha20Poly1305 {
+    fn supports(code: Option<CipherCode>) -> bool {
+        code.is_none()
+    }
+
+    fn build(_: &CipherRegistry, code: Option<CipherCode>, k: [u8; 32]) -> Option<Self> {
+        build_typed(code, k)
+    }
+}
+
+impl TransportCipher for Aes256Gcm {
+    fn supports(code: Option<CipherCode>) -> bool {
+        code == Some(AESG)
+    }
+
+    fn build(_: &CipherRegistry, code: Option<CipherCode>, k: [u8; 32]) -> Option<Self> {
+        build_typed(code, k)
+    }
+}
+
+impl TransportCipher for Aes256GcmSiv {
+    fn supports(code: Option<CipherCode>) -> bool {
+        code == Some(ASIV)
+    }
+
+    fn build(_: &CipherRegistry, code: Option<CipherCode>, k: [u8; 32]) -> Option<Self> {
+        build_typed(code, k)
+    }
+}
+
+impl TransportCipher for XChaCha20Poly1305 {
+    fn supports(code: Option<CipherCode>) -> bool {
+        code == Some(XCHA)
+    }
+
+    fn build(_: &CipherRegistry, code: Option<CipherCode>, k: [u8; 32]) -> Option<Self> {
+        build_typed(code, k)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn check_vector<C: AeadCipher + KeyInit + Send + 'static>(
+        key: &[u8],
+        nonce: &[u8],
+        ad: &[u8],
+        plaintext: &[u8],
+        ciphertext: &[u8],
+    ) {
+        let mut cipher = DynCipher(Box::new(C::new_from_slice(key).unwrap()));
+        assert!(cipher.nonce_len() == nonce.len());
+        let mut data = plaintext.to_vec();
+        cipher.encrypt(nonce, ad, &mut data).unwrap();
+        assert!(data == ciphertext);
+        cipher.decrypt(nonce, ad, &mut data).unwrap();
+        assert!(data == plaintext);
+
+        // wrong nonce length
+        assert!(cipher.encrypt(&nonce[1..], ad, &mut data).is_err());
+        // tampered ciphertext
+        let mut data = ciphertext.to_vec();
+        data[0] ^= 1;
+        assert!(cipher.decrypt(nonce, ad, &mut data).is_err());
+    }
+
+    #[test]
+    fn test_aes_256_gcm_siv_vectors() {
+        // RFC 8452 Appendix C.2
+        let mut key = [0; 32];
+        key[0] = 1;
+        let mut nonce = [0; 12];
+        nonce[0] = 3;
+        check_vector::<Aes256GcmSiv>(
+            &key,
+            &nonce,
+            &[],
+            &[],
+            &hex::decode("07f5f4169bbf55a8400cd47ea6fd400f").unwrap(),
+        );
+        check_vector::<Aes256GcmSiv>(
+            &key,
+            &nonce,
+            &[],
+            &hex::decode("0100000000000000").unwrap(),
+            &hex::decode("c2ef328e5c71c83b843122130f7364b761e0b97427e3df28").unwrap(),
+        );
+        check_vector::<Aes256GcmSiv>(
+            &key,
+            &nonce,
+            &[],
+            &hex::decode("01000000000000000000000000000000").unwrap(),
+            &hex::decode("85a01b63025ba19b7fd3ddfc033b3e76c9eac6fa700942702e90862383c6c366")
+                .unwrap(),
+        );
+    }
+
+    #[test]
+    fn test_xchacha20_poly1305_vector() {
+        // draft-arciszewski-xchacha-03 Appendix A.3.1
+        let key: Vec<u8> = (0x80..0xa0).collect();
+        let nonce: Vec<u8> = (0x40..0x58).collect();
+        let ad = hex::decode("50515253c0c1c2c3c4c5c6c7").unwrap();
+        let plaintext = b"Ladies and Gentlemen of the class of '99: \
+            If I could offer you only one tip for the future, sunscreen would be it.";
+        let ciphertext = hex::decode(
+            "bd6d179d3e83d43b9576579493c0e939572a1700252bfaccbed2902c21396cbb\
+             731c7f1b0b4aa6440bf3a82f4eda7e39ae64c6708c54c216cb96b72e1213b452\
+             2f8c9ba40db5d945b11b69b982c1bb9e3f3fac2bc369488f76b2383565d3fff9\
+             21f9664c97637da9768812f615c68b13b52e\
+             c0875924c1c7987947deafd8780acf49",
+        )
+        .unwrap();
+        check_vector::<XChaCha20Poly1305>(&key, &nonce, &ad, plaintext, &ciphertext);
     }
 }
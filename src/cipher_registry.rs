@@ -0,0 +1,270 @@
+use aes_gcm::aead::consts::U32;
+use aes_gcm::{Aes256Gcm, KeyInit};
+use aes_gcm_siv::Aes256GcmSiv;
+use chacha20poly1305::XChaCha20Poly1305;
+
+use crate::aed_cipher::{AeadCipher, DynCipher};
+use crate::error::{Error, ErrorBytes, ErrorKind};
+
+/// 4 bytes code that identifies an AEAD cipher in `AEAD_CIPHERS` and `CIPHER_CHOICE`
+pub type CipherCode = [u8; 4];
+
+/// AES-256 with GCM, 0x47534541
+pub const AESG: CipherCode = [0x47, 0x53, 0x45, 0x41];
+
+/// AES-256 with GCM-SIV, nonce-misuse resistant, 0x56495341 (b"ASIV")
+pub const ASIV: CipherCode = [0x56, 0x49, 0x53, 0x41];
+
+/// XChaCha20-Poly1305 with 24 bytes nonces, 0x41484358 (b"XCHA")
+pub const XCHA: CipherCode = [0x41, 0x48, 0x43, 0x58];
+
+/// `AEAD_CIPHERS` is a SEQ0_32: at most 32 codes, so the message is at most 1 + 32 * 4 = 129 bytes
+pub const MAX_CIPHERS: usize = 32;
+
+/// Build the cipher from the key derived by the handshake
+pub type CipherBuilder = fn([u8; 32]) -> DynCipher;
+
+fn build<C: AeadCipher + KeyInit<KeySize = U32> + Send + 'static>(k: [u8; 32]) -> DynCipher {
+    DynCipher(Box::new(C::new(&k.into())))
+}
+
+/// AEAD ciphers other than ChaChaPoly that can be negotiated after the handshake, ordered by
+/// preference (first is the preferred one). ChaChaPoly is always supported and is used when
+/// nothing else is negotiated.
+#[derive(Clone)]
+pub struct CipherRegistry {
+    ciphers: Vec<(CipherCode, CipherBuilder)>,
+}
+
+impl Default for CipherRegistry {
+    /// Only AES-256-GCM
+    fn default() -> Self {
+        let mut registry = Self::new();
+        registry.register::<Aes256Gcm>(AESG).unwrap();
+        registry
+    }
+}
+
+impl CipherRegistry {
+    /// Empty registry, only ChaChaPoly is used
+    pub fn new() -> Self {
+        Self { ciphers: vec![] }
+    }
+
+    /// Every cipher implemented by this crate: AES-256-GCM, AES-256-GCM-SIV and
+    /// XChaCha20-Poly1305
+    pub fn all() -> Self {
+        let mut registry = Self::default();
+        registry.register::<Aes256GcmSiv>(ASIV).unwrap();
+        registry.register::<XChaCha20Poly1305>(XCHA).unwrap();
+        registry
+    }
+
+    /// Add `C` with the least preference. If `code` is already registered the cipher is replaced
+    /// and keeps its preference.
+    pub fn register<C: AeadCipher + KeyInit<KeySize = U32> + Send + 'static>(
+        &mut self,
+        code: CipherCode,
+    ) -> Result<(), Error> {
+        self.register_with(code, build::<C>)
+    }
+
+    /// Same as [`CipherRegistry::register`] with a custom builder
+    pub fn register_with(&mut self, code: CipherCode, builder: CipherBuilder) -> Result<(), Error> {
+        if let Some(entry) = self.ciphers.iter_mut().find(|(c, _)| *c == code) {
+            entry.1 = builder;
+            return Ok(());
+        }
+        if self.ciphers.len() == MAX_CIPHERS {
+            return Err(ErrorKind::CipherListTooLong(self.ciphers.len() + 1).into());
+        }
+        self.ciphers.push((code, builder));
+        Ok(())
+    }
+
+    /// Remove `code` from the registry
+    pub fn unregister(&mut self, code: CipherCode) {
+        self.ciphers.retain(|(c, _)| *c != code);
+    }
+
+    /// Registered codes ordered by preference
+    pub fn codes(&self) -> impl ExactSizeIterator<Item = CipherCode> + '_ {
+        self.ciphers.iter().map(|(code, _)| *code)
+    }
+
+    pub fn contains(&self, code: CipherCode) -> bool {
+        self.ciphers.iter().any(|(c, _)| *c == code)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ciphers.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.ciphers.len()
+    }
+
+    /// Length of the encoded `AEAD_CIPHERS` message
+    pub fn encoded_len(&self) -> usize {
+        1 + 4 * self.ciphers.len()
+    }
+
+    /// Write the registered codes as `AEAD_CIPHERS` in `out`, return the number of bytes written
+    pub fn encode(&self, out: &mut [u8]) -> Result<usize, Error> {
+        encode_cipher_list(self.codes(), out)
+    }
+
+    /// First registered code, in preference order, that is also in `offered`
+    pub fn choose(&self, offered: &[u8]) -> Result<Option<CipherCode>, Error> {
+        self.choose_where(offered, |_| true)
+    }
+
+    /// Same as [`CipherRegistry::choose`] but skip the codes that are not `accepted`
+    pub(crate) fn choose_where(
+        &self,
+        offered: &[u8],
+        accepted: impl Fn(CipherCode) -> bool,
+    ) -> Result<Option<CipherCode>, Error> {
+        let offered = decode_cipher_list(offered)?;
+        Ok(self
+            .codes()
+            .filter(|code| accepted(*code))
+            .find(|code| offered.clone().any(|c| c == *code)))
+    }
+
+    pub(crate) fn build(&self, code: CipherCode, k: [u8; 32]) -> Option<DynCipher> {
+        self.ciphers
+            .iter()
+            .find(|(c, _)| *c == code)
+            .map(|(_, builder)| builder(k))
+    }
+}
+
+/// Encode `codes` as SEQ0_32[u32]: one byte with the number of codes followed by the codes
+pub fn encode_cipher_list(
+    codes: impl ExactSizeIterator<Item = CipherCode>,
+    out: &mut [u8],
+) -> Result<usize, Error> {
+    let n = codes.len();
+    if n > MAX_CIPHERS {
+        return Err(ErrorKind::CipherListTooLong(n).into());
+    }
+    let len = 1 + 4 * n;
+    if out.len() < len {
+        return Err(ErrorKind::OutputBufferTooSmall(len).into());
+    }
+    out[0] = n as u8;
+    for (i, code) in codes.enumerate() {
+        out[1 + 4 * i..5 + 4 * i].copy_from_slice(&code);
+    }
+    Ok(len)
+}
+
+/// Decode a SEQ0_32[u32], fail if the length byte does not match the length of `list`
+pub fn decode_cipher_list(
+    list: &[u8],
+) -> Result<impl Iterator<Item = CipherCode> + Clone + '_, Error> {
+    match list.split_first() {
+        Some((&n, codes)) if n as usize <= MAX_CIPHERS && n as usize * 4 == codes.len() => {
+            Ok(codes.chunks_exact(4).map(|c| c.try_into().unwrap()))
+        }
+        _ => Err(ErrorKind::InvalidCipherList(ErrorBytes::new(list)).into()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chacha20poly1305::ChaCha20Poly1305;
+
+    #[test]
+    fn test_encode_decode() {
+        let codes: Vec<CipherCode> = (0..MAX_CIPHERS as u32).map(|i| i.to_le_bytes()).collect();
+        let mut out = [0; 129];
+        let len = encode_cipher_list(codes.iter().copied(), &mut out).unwrap();
+        assert!(len == 129);
+        assert!(out[0] == 32);
+        let decoded: Vec<CipherCode> = decode_cipher_list(&out).unwrap().collect();
+        assert!(decoded == codes);
+
+        let len = encode_cipher_list([].into_iter(), &mut out).unwrap();
+        assert!(out[..len] == [0]);
+        assert!(decode_cipher_list(&out[..len]).unwrap().count() == 0);
+    }
+
+    #[test]
+    fn test_encode_invalid() {
+        let codes = vec![AESG; MAX_CIPHERS + 1];
+        let mut out = [0; 133];
+        assert!(matches!(
+            encode_cipher_list(codes.into_iter(), &mut out).map_err(Error::into_kind),
+            Err(ErrorKind::CipherListTooLong(33))
+        ));
+        assert!(matches!(
+            encode_cipher_list([AESG].into_iter(), &mut out[..4]).map_err(Error::into_kind),
+            Err(ErrorKind::OutputBufferTooSmall(5))
+        ));
+    }
+
+    #[test]
+    fn test_decode_invalid() {
+        let mut too_long = vec![33];
+        too_long.extend_from_slice(&[0; 33 * 4]);
+        for list in [
+            &[][..],
+            &[1, 0x47, 0x53, 0x45],
+            &[1, 0x47, 0x53, 0x45, 0x41, 0],
+            &[2, 0x47, 0x53, 0x45, 0x41],
+            &too_long,
+        ] {
+            assert!(matches!(
+                decode_cipher_list(list).map_err(Error::into_kind),
+                Err(ErrorKind::InvalidCipherList(l)) if l == list[..list.len().min(ErrorBytes::CAPACITY)]
+            ));
+        }
+    }
+
+    #[test]
+    fn test_registry() {
+        let other = *b"TEST";
+        let mut registry = CipherRegistry::default();
+        assert!(registry.codes().collect::<Vec<_>>() == vec![AESG]);
+        registry.register::<ChaCha20Poly1305>(other).unwrap();
+        // already registered, keep its preference
+        registry.register::<Aes256Gcm>(AESG).unwrap();
+        assert!(registry.codes().collect::<Vec<_>>() == vec![AESG, other]);
+
+        let mut out = [0; 9];
+        assert!(registry.encode(&mut out).unwrap() == registry.encoded_len());
+        assert!(out == [2, 0x47, 0x53, 0x45, 0x41, b'T', b'E', b'S', b'T']);
+
+        // our preference wins
+        assert!(
+            registry
+                .choose(&[2, b'T', b'E', b'S', b'T', 0x47, 0x53, 0x45, 0x41])
+                .unwrap()
+                == Some(AESG)
+        );
+        assert!(registry.choose(&[1, b'T', b'E', b'S', b'T']).unwrap() == Some(other));
+        assert!(registry.choose(&[1, 1, 2, 3, 4]).unwrap().is_none());
+        assert!(registry.choose(&[0]).unwrap().is_none());
+
+        registry.unregister(AESG);
+        assert!(!registry.contains(AESG));
+        assert!(registry.build(AESG, [0; 32]).is_none());
+        assert!(registry.build(other, [0; 32]).is_some());
+
+        assert!(CipherRegistry::all().codes().collect::<Vec<_>>() == vec![AESG, ASIV, XCHA]);
+
+        let mut registry = CipherRegistry::new();
+        for code in 0..MAX_CIPHERS as u32 {
+            registry.register::<Aes256Gcm>(code.to_le_bytes()).unwrap();
+        }
+        assert!(matches!(
+            registry
+                .register::<Aes256Gcm>(*b"FULL")
+                .map_err(Error::into_kind),
+            Err(ErrorKind::CipherListTooLong(33))
+        ));
+    }
+}
@@ -1,9 +1,12 @@
-use std::marker::PhantomData;
-use std::ptr;
+use std::fmt;
+use std::io::IoSlice;
+use std::mem::ManuallyDrop;
 
-use crate::aed_cipher::AeadCipher;
-use aes_gcm::Aes256Gcm;
-use chacha20poly1305::{aead::Buffer, ChaCha20Poly1305};
+use crate::aed_cipher::{drop_and_wipe, AeadCipher, TransportCipher, MAX_NONCE_LEN};
+use crate::cipher_registry::{CipherCode, CipherRegistry};
+use crate::secret::{CipherName, Secret};
+use chacha20poly1305::aead::Buffer;
+use zeroize::Zeroize;
 
 pub trait CipherState<Cipher_: AeadCipher>
 where
@@ -15,28 +18,15 @@ where
     fn set_n(&mut self, n: u64);
     fn get_cipher(&mut self) -> &mut Option<Cipher_>;
 
-    fn nonce_to_bytes(&self) -> [u8; 12] {
-        let mut res = [0u8; 12];
-        let n = self.get_n();
-        let bytes = n.to_le_bytes();
-        for b in bytes {
-            res[1 + 4] = b;
-        }
-        res
-    }
-
-    fn into_aesg(mut self) -> Option<Cipher<Aes256Gcm>> {
-        #[allow(clippy::clone_on_copy)]
-        let k = self.get_k().clone()?;
-        let c = Aes256Gcm::from_key(k);
-        Some(Cipher::from_cipher(c))
+    /// Nonce for the next message, n is only incremented by the caller when the AEAD operation
+    /// succeed. 2^64-1 is reserved so we fail before reaching it.
+    fn next_nonce(&self, len: usize) -> Result<[u8; MAX_NONCE_LEN], aes_gcm::Error> {
+        next_nonce(self.get_n(), len)
     }
 
-    fn into_chacha(mut self) -> Option<Cipher<ChaCha20Poly1305>> {
-        #[allow(clippy::clone_on_copy)]
-        let k = self.get_k().clone()?;
-        let c = ChaCha20Poly1305::from_key(k);
-        Some(Cipher::from_cipher(c))
+    /// Nonce length of the cipher, 12 bytes when there is no cipher
+    fn cipher_nonce_len(&mut self) -> usize {
+        self.get_cipher().as_ref().map_or(12, |c| c.nonce_len())
     }
 
     fn encrypt_with_ad<T: Buffer>(
@@ -44,20 +34,13 @@ where
         ad: &[u8],
         data: &mut T,
     ) -> Result<(), aes_gcm::Error> {
-        self.set_n(self.get_n() + 1);
-        let n = self.nonce_to_bytes();
+        let nonce_len = self.cipher_nonce_len();
+        let n = self.next_nonce(nonce_len)?;
         if let Some(c) = self.get_cipher() {
-            match c.encrypt(&n, ad, data) {
-                Ok(_) => Ok(()),
-                Err(e) => {
-                    self.set_n(self.get_n() - 1);
-                    Err(e)
-                }
-            }
-        } else {
-            self.set_n(self.get_n() - 1);
-            Ok(())
+            c.encrypt(&n[..nonce_len], ad, data)?;
+            self.set_n(self.get_n() + 1);
         }
+        Ok(())
     }
 
     fn decrypt_with_ad<T: Buffer>(
@@ -65,131 +48,276 @@ where
         ad: &[u8],
         data: &mut T,
     ) -> Result<(), aes_gcm::Error> {
-        self.set_n(self.get_n() + 1);
-        let n = self.nonce_to_bytes();
+        let nonce_len = self.cipher_nonce_len();
+        let n = self.next_nonce(nonce_len)?;
         if let Some(c) = self.get_cipher() {
-            match c.decrypt(&n, ad, data) {
-                Ok(_) => Ok(()),
-                Err(e) => {
-                    self.set_n(self.get_n() - 1);
-                    Err(e)
-                }
-            }
-        } else {
-            self.set_n(self.get_n() - 1);
-            Ok(())
+            c.decrypt(&n[..nonce_len], ad, data)?;
+            self.set_n(self.get_n() + 1);
         }
+        Ok(())
     }
-}
 
-#[allow(clippy::large_enum_variant)]
-pub enum GenericCipher<A: AeadCipher> {
-    ChaCha20Poly1305(Cipher<ChaCha20Poly1305>),
-    Aes256Gcm(Cipher<Aes256Gcm>),
-    #[allow(dead_code)]
-    Phantom(PhantomData<A>),
-}
-
-impl<C: AeadCipher> Drop for GenericCipher<C> {
-    fn drop(&mut self) {
-        self.erase_k();
+    /// Encrypt the first `len` bytes of `buf` in place and write the tag right after them.
+    /// Return the length of the ciphertext (tag included), `buf` must have room for the tag.
+    fn encrypt_with_ad_slice(
+        &mut self,
+        ad: &[u8],
+        buf: &mut [u8],
+        len: usize,
+    ) -> Result<usize, aes_gcm::Error> {
+        let nonce_len = self.cipher_nonce_len();
+        let n = self.next_nonce(nonce_len)?;
+        match self.get_cipher() {
+            Some(c) => {
+                if len.checked_add(16).is_none_or(|needed| buf.len() < needed) {
+                    return Err(aes_gcm::Error);
+                }
+                let (data, rest) = buf.split_at_mut(len);
+                let tag = c.encrypt_detached(&n[..nonce_len], ad, data)?;
+                rest[..tag.len()].copy_from_slice(&tag);
+                self.set_n(self.get_n() + 1);
+                Ok(len + tag.len())
+            }
+            None => Ok(len),
+        }
     }
-}
 
-impl<C: AeadCipher> GenericCipher<C> {
-    pub fn encrypt<T: Buffer>(&mut self, msg: &mut T) -> Result<(), aes_gcm::Error> {
-        match self {
-            GenericCipher::ChaCha20Poly1305(c) => c.encrypt_with_ad(&[], msg),
-            GenericCipher::Aes256Gcm(c) => c.encrypt_with_ad(&[], msg),
-            GenericCipher::Phantom(_) => unreachable!(),
-        }
-    }
-    pub fn decrypt<T: Buffer>(&mut self, msg: &mut T) -> Result<(), aes_gcm::Error> {
-        match self {
-            GenericCipher::ChaCha20Poly1305(c) => c.decrypt_with_ad(&[], msg),
-            GenericCipher::Aes256Gcm(c) => c.decrypt_with_ad(&[], msg),
-            GenericCipher::Phantom(_) => unreachable!(),
-        }
-    }
-    pub fn erase_k(&mut self) {
-        match self {
-            GenericCipher::ChaCha20Poly1305(c) => {
-                if let Some(k) = c.k.as_mut() {
-                    for b in k {
-                        unsafe { ptr::write_volatile(b, 0) };
-                    }
-                    c.k = None;
+    /// Decrypt `buf` (ciphertext followed by the tag) in place. Return the length of the
+    /// plaintext that is left at the start of `buf`.
+    fn decrypt_with_ad_slice(
+        &mut self,
+        ad: &[u8],
+        buf: &mut [u8],
+    ) -> Result<usize, aes_gcm::Error> {
+        let nonce_len = self.cipher_nonce_len();
+        let n = self.next_nonce(nonce_len)?;
+        match self.get_cipher() {
+            Some(c) => {
+                if buf.len() < 16 {
+                    return Err(aes_gcm::Error);
                 }
-            }
-            GenericCipher::Aes256Gcm(c) => {
-                if let Some(k) = c.k.as_mut() {
-                    for b in k {
-                        unsafe { ptr::write_volatile(b, 0) };
-                    }
-                    c.k = None;
+                let len = buf.len() - 16;
+                let (data, tag) = buf.split_at_mut(len);
+                let tag: &[u8; 16] = (&tag[..]).try_into().unwrap();
+                if let Err(e) = c.decrypt_detached(&n[..nonce_len], ad, data, tag) {
+                    // the ciphers check the tag before decrypting so `data` is still the
+                    // ciphertext, zero it anyway in case a cipher does not
+                    data.fill(0);
+                    return Err(e);
                 }
+                self.set_n(self.get_n() + 1);
+                Ok(len)
             }
-            GenericCipher::Phantom(_) => unreachable!(),
+            None => Ok(buf.len()),
         }
     }
 
-    pub fn into_aesg(mut self) -> GenericCipher<C> {
-        match &mut self {
-            GenericCipher::ChaCha20Poly1305(c) => {
-                let c = Cipher::from_cipher(Aes256Gcm::from_key(c.get_k().unwrap()));
-                self.erase_k();
-                GenericCipher::Aes256Gcm(c)
-            }
-            GenericCipher::Aes256Gcm(_) => {
-                self.erase_k();
-                self
-            }
-            GenericCipher::Phantom(_) => unreachable!(),
+    /// Encrypt `data` in place and return the tag. Fail if there is no cipher.
+    fn encrypt_with_ad_detached(
+        &mut self,
+        ad: &[u8],
+        data: &mut [u8],
+    ) -> Result<[u8; 16], aes_gcm::Error> {
+        let nonce_len = self.cipher_nonce_len();
+        let n = self.next_nonce(nonce_len)?;
+        let c = self.get_cipher().as_mut().ok_or(aes_gcm::Error)?;
+        let tag = c.encrypt_detached(&n[..nonce_len], ad, data)?;
+        self.set_n(self.get_n() + 1);
+        Ok(tag)
+    }
+
+    /// Decrypt `data` in place checking it against `tag`. Fail if there is no cipher.
+    fn decrypt_with_ad_detached(
+        &mut self,
+        ad: &[u8],
+        data: &mut [u8],
+        tag: &[u8; 16],
+    ) -> Result<(), aes_gcm::Error> {
+        let nonce_len = self.cipher_nonce_len();
+        let n = self.next_nonce(nonce_len)?;
+        let c = self.get_cipher().as_mut().ok_or(aes_gcm::Error)?;
+        if let Err(e) = c.decrypt_detached(&n[..nonce_len], ad, data, tag) {
+            // defence in depth, the supported ciphers leave the ciphertext untouched
+            data.fill(0);
+            return Err(e);
         }
+        self.set_n(self.get_n() + 1);
+        Ok(())
     }
-}
 
-impl CipherState<Aes256Gcm> for GenericCipher<Aes256Gcm> {
-    fn get_k(&mut self) -> &mut Option<[u8; 32]> {
-        match self {
-            GenericCipher::Aes256Gcm(c) => c.get_k(),
-            _ => unreachable!(),
+    /// Write the encryption of `msg` followed by the tag in `out`, that must be at least
+    /// `msg.len() + 16` bytes. Return the number of bytes written.
+    fn encrypt_with_ad_to(
+        &mut self,
+        ad: &[u8],
+        msg: &[u8],
+        out: &mut [u8],
+    ) -> Result<usize, aes_gcm::Error> {
+        let len = msg.len();
+        if out.len() < len + 16 {
+            return Err(aes_gcm::Error);
         }
+        let (data, rest) = out.split_at_mut(len);
+        data.copy_from_slice(msg);
+        let tag = self.encrypt_with_ad_detached(ad, data)?;
+        rest[..16].copy_from_slice(&tag);
+        Ok(len + 16)
     }
 
-    fn set_k(&mut self, k: Option<[u8; 32]>) {
-        match self {
-            GenericCipher::Aes256Gcm(c) => c.set_k(k),
-            _ => unreachable!(),
+    /// Write the decryption of `ciphertext` (tag included) in `out`, that must be at least
+    /// `ciphertext.len() - 16` bytes. Return the number of bytes written.
+    fn decrypt_with_ad_to(
+        &mut self,
+        ad: &[u8],
+        ciphertext: &[u8],
+        out: &mut [u8],
+    ) -> Result<usize, aes_gcm::Error> {
+        let len = ciphertext.len().checked_sub(16).ok_or(aes_gcm::Error)?;
+        if out.len() < len {
+            return Err(aes_gcm::Error);
         }
+        let (data, tag) = ciphertext.split_at(len);
+        let tag: &[u8; 16] = tag.try_into().unwrap();
+        out[..len].copy_from_slice(data);
+        if let Err(e) = self.decrypt_with_ad_detached(ad, &mut out[..len], tag) {
+            // `out` holds the ciphertext copied above, clear it so that it is not mistaken
+            // for a plaintext
+            out[..len].fill(0);
+            return Err(e);
+        }
+        Ok(len)
     }
 
-    fn get_n(&self) -> u64 {
-        match self {
-            GenericCipher::Aes256Gcm(c) => c.get_n(),
-            _ => unreachable!(),
+    /// Encrypt each of `msgs` with sequential nonces and write them one after the other in
+    /// `out`, each one followed by its tag. Return the number of bytes written. `out` must be at
+    /// least the sum of the message lengths plus 16 bytes per message, on error the messages
+    /// before the failing one are already encrypted.
+    fn encrypt_with_ad_batch(
+        &mut self,
+        ad: &[u8],
+        msgs: &[IoSlice<'_>],
+        out: &mut [u8],
+    ) -> Result<usize, aes_gcm::Error> {
+        let total: usize = msgs.iter().map(|msg| msg.len() + 16).sum();
+        if out.len() < total {
+            return Err(aes_gcm::Error);
         }
+        let nonce_len = self.cipher_nonce_len();
+        let mut n = self.get_n();
+        let c = self.get_cipher().as_mut().ok_or(aes_gcm::Error)?;
+        let mut offset = 0;
+        let mut result = Ok(total);
+        for msg in msgs {
+            let (data, rest) = out[offset..].split_at_mut(msg.len());
+            data.copy_from_slice(msg);
+            match next_nonce(n, nonce_len)
+                .and_then(|nonce| c.encrypt_detached(&nonce[..nonce_len], ad, data))
+            {
+                Ok(tag) => rest[..16].copy_from_slice(&tag),
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            }
+            offset += msg.len() + 16;
+            n += 1;
+        }
+        self.set_n(n);
+        result
     }
 
-    fn set_n(&mut self, n: u64) {
-        match self {
-            GenericCipher::Aes256Gcm(c) => c.set_n(n),
-            _ => unreachable!(),
+    /// Gather `parts` in `out` and encrypt them as a single message followed by the tag. Return
+    /// the number of bytes written.
+    fn encrypt_with_ad_vectored(
+        &mut self,
+        ad: &[u8],
+        parts: &[IoSlice<'_>],
+        out: &mut [u8],
+    ) -> Result<usize, aes_gcm::Error> {
+        let len: usize = parts.iter().map(|part| part.len()).sum();
+        if out.len() < len + 16 {
+            return Err(aes_gcm::Error);
         }
+        let mut offset = 0;
+        for part in parts {
+            out[offset..offset + part.len()].copy_from_slice(part);
+            offset += part.len();
+        }
+        let (data, rest) = out.split_at_mut(len);
+        let tag = self.encrypt_with_ad_detached(ad, data)?;
+        rest[..16].copy_from_slice(&tag);
+        Ok(len + 16)
     }
 
-    fn get_cipher(&mut self) -> &mut Option<Aes256Gcm> {
-        match self {
-            GenericCipher::Aes256Gcm(c) => c.get_cipher(),
-            _ => unreachable!(),
+    /// Decrypt in place the messages that are one after the other in `buf`, `lens` are their
+    /// lengths tag included. The plaintexts are moved to the start of `buf` one after the other
+    /// and `lens` is updated with their lengths. Return the total length of the plaintexts, on
+    /// error the messages before the failing one are already decrypted and the failing one is
+    /// zeroed.
+    fn decrypt_with_ad_batch(
+        &mut self,
+        ad: &[u8],
+        buf: &mut [u8],
+        lens: &mut [usize],
+    ) -> Result<usize, aes_gcm::Error> {
+        let total = lens
+            .iter()
+            .try_fold(0usize, |total, len| total.checked_add(*len));
+        if total.is_none_or(|total| buf.len() < total) || lens.iter().any(|len| *len < 16) {
+            return Err(aes_gcm::Error);
+        }
+        let nonce_len = self.cipher_nonce_len();
+        let mut n = self.get_n();
+        let c = self.get_cipher().as_mut().ok_or(aes_gcm::Error)?;
+        let mut read = 0;
+        let mut written = 0;
+        let mut result = Ok(());
+        for len in lens.iter_mut() {
+            let plain_len = *len - 16;
+            let (data, tag) = buf[read..read + *len].split_at_mut(plain_len);
+            let tag: &[u8; 16] = (&tag[..]).try_into().unwrap();
+            result = next_nonce(n, nonce_len)
+                .and_then(|nonce| c.decrypt_detached(&nonce[..nonce_len], ad, data, tag));
+            if result.is_err() {
+                // same as `decrypt_with_ad_slice`, the failing message is zeroed even though
+                // the ciphers do not decrypt it before checking the tag
+                data.fill(0);
+                break;
+            }
+            buf.copy_within(read..read + plain_len, written);
+            read += *len;
+            written += plain_len;
+            *len = plain_len;
+            n += 1;
         }
+        self.set_n(n);
+        result.map(|_| written)
     }
 }
 
+/// Zeros followed by the little-endian encoding of n, `len` bytes in total. For 12 bytes nonces
+/// it is 32 bits of zeros followed by n.
+fn nonce_to_bytes(n: u64, len: usize) -> [u8; MAX_NONCE_LEN] {
+    let mut res = [0u8; MAX_NONCE_LEN];
+    res[len - 8..len].copy_from_slice(&n.to_le_bytes());
+    res
+}
+
+/// Nonce for the message `n`, fail for the reserved 2^64-1
+fn next_nonce(n: u64, len: usize) -> Result<[u8; MAX_NONCE_LEN], aes_gcm::Error> {
+    if n == u64::MAX {
+        return Err(aes_gcm::Error);
+    }
+    Ok(nonce_to_bytes(n, len))
+}
+
 pub struct Cipher<C: AeadCipher> {
-    k: Option<[u8; 32]>,
+    k: Secret<Option<[u8; 32]>>,
     n: u64,
-    cipher: Option<C>,
+    // Negotiated cipher, None is ChaChaPoly
+    code: Option<CipherCode>,
+    // Dropped and wiped by the Drop of Cipher
+    cipher: ManuallyDrop<Option<C>>,
 }
 
 /// Make sure that Cipher is not sync so we do not need to worry about what other memory accessor see
@@ -200,27 +328,62 @@ impl<C: AeadCipher> !Copy for Cipher<C> {}
 
 impl<C: AeadCipher> Cipher<C> {
     /// Internal use only, we need k for handshake
+    #[cfg(test)]
     pub fn from_key_and_cipher(k: [u8; 32], c: C) -> Self {
         Self {
-            k: Some(k),
+            k: Secret::new(Some(k)),
             n: 0,
-            cipher: Some(c),
+            code: None,
+            cipher: ManuallyDrop::new(Some(c)),
         }
     }
 
-    /// At the end of the handshake we return a cipher with hidden key
-    pub fn from_cipher(c: C) -> Self {
+    /// At the end of the handshake we return a cipher with hidden key, `code` is the negotiated
+    /// cipher
+    pub fn from_cipher(code: Option<CipherCode>, c: C) -> Self {
         Self {
-            k: None,
+            k: Secret::new(None),
             n: 0,
-            cipher: Some(c),
+            code,
+            cipher: ManuallyDrop::new(Some(c)),
         }
     }
 }
 
+impl<C: TransportCipher> Cipher<C> {
+    /// Build the transport cipher negotiated as `code` with the key in `k`, then erase `k`.
+    /// Return `None` if `k` is not set or if `C` can not be built for `code`.
+    pub(crate) fn negotiated(
+        registry: &CipherRegistry,
+        code: Option<CipherCode>,
+        k: &mut Option<[u8; 32]>,
+    ) -> Option<Self> {
+        let cipher = C::build(registry, code, (*k)?);
+        k.zeroize();
+        cipher.map(|cipher| Self::from_cipher(code, cipher))
+    }
+}
+
+impl<C: AeadCipher> fmt::Debug for Cipher<C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Cipher")
+            .field("cipher", &CipherName(self.code))
+            .field("n", &self.n)
+            .field("k", &self.k)
+            .finish()
+    }
+}
+
+impl<C: AeadCipher> Drop for Cipher<C> {
+    fn drop(&mut self) {
+        // Safety: the field is never used again and, being ManuallyDrop, it is not dropped twice
+        unsafe { drop_and_wipe::<Option<C>>(&mut *self.cipher) };
+    }
+}
+
 impl<C: AeadCipher> CipherState<C> for Cipher<C> {
     fn get_k(&mut self) -> &mut Option<[u8; 32]> {
-        &mut self.k
+        self.k.expose_mut()
     }
     fn get_n(&self) -> u64 {
         self.n
@@ -233,6 +396,6 @@ impl<C: AeadCipher> CipherState<C> for Cipher<C> {
     }
 
     fn set_k(&mut self, k: Option<[u8; 32]>) {
-        self.k = k;
+        *self.k.expose_mut() = k;
     }
 }
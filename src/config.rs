@@ -0,0 +1,121 @@
+use crate::cipher_registry::{CipherCode, CipherRegistry};
+use crate::error::{Error, ErrorBytes, ErrorKind};
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+cpufeatures::new!(aes_hw, "aes", "pclmulqdq");
+#[cfg(target_arch = "aarch64")]
+cpufeatures::new!(aes_hw, "aes");
+
+/// True if the CPU has AES instructions, so AES-GCM is faster than ChaChaPoly
+pub fn has_aes_hardware() -> bool {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64", target_arch = "aarch64"))]
+    {
+        aes_hw::get()
+    }
+    #[cfg(not(any(target_arch = "x86", target_arch = "x86_64", target_arch = "aarch64")))]
+    {
+        false
+    }
+}
+
+/// Cipher policy of the [`crate::Responder`]
+#[derive(Clone, Default)]
+pub struct ResponderConfig {
+    ciphers: CipherRegistry,
+    require_cipher: bool,
+}
+
+impl ResponderConfig {
+    /// `ciphers` are the ciphers other than ChaChaPoly that can be chosen, in preference order.
+    /// When the initiator offers none of them the responder reply with an empty `CIPHER_CHOICE`
+    /// and the session stays on ChaChaPoly.
+    pub fn new(ciphers: CipherRegistry) -> Self {
+        Self {
+            ciphers,
+            require_cipher: false,
+        }
+    }
+
+    /// Never upgrade, always reply with an empty `CIPHER_CHOICE`
+    pub fn chacha_only() -> Self {
+        Self::new(CipherRegistry::new())
+    }
+
+    /// If `require` is true initiators that offer none of the allowed ciphers are rejected with
+    /// [`crate::Error::UnsupportedCiphers`] instead of staying on ChaChaPoly
+    pub fn with_required_cipher(mut self, require: bool) -> Self {
+        self.require_cipher = require;
+        self
+    }
+
+    pub fn ciphers(&self) -> &CipherRegistry {
+        &self.ciphers
+    }
+
+    pub fn is_cipher_required(&self) -> bool {
+        self.require_cipher
+    }
+}
+
+/// Cipher policy of the [`crate::Initiator`]
+#[derive(Clone, Default)]
+pub struct InitiatorConfig {
+    ciphers: CipherRegistry,
+    min_cipher: Option<CipherCode>,
+}
+
+impl InitiatorConfig {
+    /// `ciphers` are offered in `AEAD_CIPHERS` in preference order, ChaChaPoly is always accepted
+    pub fn new(ciphers: CipherRegistry) -> Self {
+        Self {
+            ciphers,
+            min_cipher: None,
+        }
+    }
+
+    /// Offer nothing, the session always stays on ChaChaPoly
+    pub fn chacha_only() -> Self {
+        Self::new(CipherRegistry::new())
+    }
+
+    /// Offer AES-GCM only when the CPU accelerates it, see [`has_aes_hardware`]
+    pub fn auto() -> Self {
+        if has_aes_hardware() {
+            Self::default()
+        } else {
+            Self::chacha_only()
+        }
+    }
+
+    /// Reject a `CIPHER_CHOICE` that is ChaChaPoly or that the offered list ranks below `code`.
+    /// Fail if `code` is not offered.
+    pub fn with_min_cipher(mut self, code: CipherCode) -> Result<Self, Error> {
+        if !self.ciphers.contains(code) {
+            return Err(ErrorKind::UnsupportedCiphers(ErrorBytes::new(&code)).into());
+        }
+        self.min_cipher = Some(code);
+        Ok(self)
+    }
+
+    pub fn ciphers(&self) -> &CipherRegistry {
+        &self.ciphers
+    }
+
+    pub fn min_cipher(&self) -> Option<CipherCode> {
+        self.min_cipher
+    }
+
+    /// Check the cipher chosen by the responder, `None` is ChaChaPoly
+    pub(crate) fn check_choice(&self, chosen: Option<CipherCode>) -> Result<(), Error> {
+        let min = match self.min_cipher {
+            Some(min) => min,
+            None => return Ok(()),
+        };
+        let rank = |code| self.ciphers.codes().position(|c| c == code);
+        match chosen.map(|code| (code, rank(code))) {
+            Some((_, Some(rank_chosen))) if Some(rank_chosen) <= rank(min) => Ok(()),
+            Some((code, _)) => Err(ErrorKind::CipherBelowMinimum(ErrorBytes::new(&code)).into()),
+            None => Err(ErrorKind::CipherBelowMinimum(ErrorBytes::new(&[])).into()),
+        }
+    }
+}
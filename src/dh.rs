@@ -0,0 +1,508 @@
+use crate::error::{Error, ErrorKind};
+use crate::secret::erase_keypair;
+use crate::ProtocolVersion;
+use secp256k1::ecdh::shared_secret_point;
+use secp256k1::ellswift::{ElligatorSwift, ElligatorSwiftParty};
+use secp256k1::rand::Rng;
+use secp256k1::{rand, Keypair, SecretKey, XOnlyPublicKey, SECP256K1};
+use x25519_dalek::{PublicKey, StaticSecret};
+use zeroize::Zeroizing;
+
+/// Max length of a public key as sent on the wire for every supported DH function
+pub const MAX_PUB_KEY_LEN: usize = 64;
+/// Max length of a shared secret for every supported DH function
+pub const MAX_DH_LEN: usize = 64;
+
+/// Diffie-Hellman function used by the NX handshake. An implementor can hold configuration,
+/// every method takes `&self`.
+pub trait Dh {
+    type KeyPair;
+
+    /// Name of the DH function as used in the noise protocol name
+    fn name(&self) -> &'static str;
+
+    /// Length of a public key as sent on the wire, at most [`MAX_PUB_KEY_LEN`]
+    fn pub_key_len(&self) -> usize;
+
+    /// Length of the shared secret, at most [`MAX_DH_LEN`]
+    fn dh_len(&self) -> usize;
+
+    fn generate_keypair(&self) -> Self::KeyPair;
+
+    /// Public key of `keypair` as sent on the wire
+    fn pub_key<'a>(&self, keypair: &'a Self::KeyPair) -> &'a [u8];
+
+    /// Key sent on the wire as `pub_key` in a form that does not depend on the encoding, so it
+    /// is the same every time the key is sent. Certificates are signed over it and static keys
+    /// are revoked, recorded and pinned in this form. A key that is already in this form is
+    /// validated and returned as it is.
+    fn canonical_pub_key(&self, pub_key: &[u8]) -> Result<[u8; 32], Error> {
+        pub_key
+            .try_into()
+            .map_err(|_| ErrorKind::InvalidPublicKey.into())
+    }
+
+    /// Write DH(keypair, remote) in the first `dh_len` bytes of `out`. `initiator` tells whether
+    /// `keypair` belongs to the initiator of the handshake.
+    fn dh(
+        &self,
+        keypair: &Self::KeyPair,
+        remote: &[u8],
+        initiator: bool,
+        out: &mut [u8; MAX_DH_LEN],
+    ) -> Result<(), Error>;
+
+    /// Length of the initiator ephemeral public key message
+    fn first_message_len(&self) -> usize {
+        self.pub_key_len()
+    }
+
+    /// Length of the NX-handshake part 2 message: ephemeral key, encrypted static key and MAC,
+    /// encrypted SIGNATURE_NOISE_MESSAGE and MAC
+    fn second_message_len(&self) -> usize {
+        self.pub_key_len() * 2 + crate::CHACHA_POLY_TAG_SIZE + 74 + crate::CHACHA_POLY_TAG_SIZE
+    }
+}
+
+/// secp256k1 DH, keys are encoded as defined by the [`ProtocolVersion`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Secp256k1 {
+    version: ProtocolVersion,
+}
+
+/// secp256k1 keypair together with its public key as sent on the wire
+pub struct Secp256k1KeyPair {
+    kp: Keypair,
+    encoded: [u8; MAX_PUB_KEY_LEN],
+}
+
+impl Drop for Secp256k1KeyPair {
+    fn drop(&mut self) {
+        erase_keypair(&mut self.kp);
+    }
+}
+
+impl Secp256k1 {
+    pub fn new(version: ProtocolVersion) -> Self {
+        Self { version }
+    }
+
+    pub fn version(&self) -> ProtocolVersion {
+        self.version
+    }
+
+    /// Generate a keypair with even parity, that is what x-only keys are lifted to
+    pub fn generate_key() -> Keypair {
+        let kp = Keypair::new(SECP256K1, &mut rand::thread_rng());
+        if kp.x_only_public_key().1 == crate::PARITY {
+            kp
+        } else {
+            Self::generate_key()
+        }
+    }
+
+    /// Wrap `kp` encoding its public key as required by the protocol version
+    pub fn keypair(&self, kp: Keypair) -> Secp256k1KeyPair {
+        let mut encoded = [0; MAX_PUB_KEY_LEN];
+        match self.version {
+            ProtocolVersion::XOnly(_) => {
+                encoded[..32].copy_from_slice(&kp.x_only_public_key().0.serialize());
+            }
+            ProtocolVersion::EllSwift => {
+                let aux_rand: [u8; 32] = rand::thread_rng().gen();
+                encoded = ElligatorSwift::from_seckey(SECP256K1, kp.secret_key(), Some(aux_rand))
+                    .to_array();
+            }
+        }
+        Secp256k1KeyPair { kp, encoded }
+    }
+
+    /// ECDH on x-only keys, return the serialized shared point (x || y). What is fed in `MixKey`
+    /// depends on the [`crate::EcdhOutput`] of the protocol.
+    pub(crate) fn ecdh(private: &SecretKey, public: &[u8]) -> Result<[u8; 64], Error> {
+        let x_public =
+            XOnlyPublicKey::from_slice(public).map_err(|_| ErrorKind::InvalidPublicKey)?;
+        Ok(shared_secret_point(
+            &x_public.public_key(crate::PARITY),
+            private,
+        ))
+    }
+
+    /// x-only ECDH between ElligatorSwift encoded keys as defined in BIP324. `ellswift_a` is the
+    /// initiator key and `ellswift_b` the responder one, `party` tells which one `private` belongs to.
+    pub(crate) fn ellswift_ecdh(
+        private: &SecretKey,
+        ellswift_a: &[u8],
+        ellswift_b: &[u8],
+        party: ElligatorSwiftParty,
+    ) -> Result<[u8; 32], Error> {
+        let ellswift_a = ellswift_a
+            .try_into()
+            .map_err(|_| ErrorKind::InvalidPublicKey)?;
+        let ellswift_b = ellswift_b
+            .try_into()
+            .map_err(|_| ErrorKind::InvalidPublicKey)?;
+        Ok(ElligatorSwift::shared_secret(
+            ElligatorSwift::from_array(ellswift_a),
+            ElligatorSwift::from_array(ellswift_b),
+            *private,
+            party,
+            None,
+        )
+        .to_secret_bytes())
+    }
+}
+
+impl Dh for Secp256k1 {
+    type KeyPair = Secp256k1KeyPair;
+
+    fn name(&self) -> &'static str {
+        match self.version {
+            ProtocolVersion::XOnly(_) => "secp256k1",
+            ProtocolVersion::EllSwift => "Secp256k1+EllSwift",
+        }
+    }
+
+    fn pub_key_len(&self) -> usize {
+        self.version.pub_key_len()
+    }
+
+    fn dh_len(&self) -> usize {
+        self.version.dh_len()
+    }
+
+    fn generate_keypair(&self) -> Self::KeyPair {
+        let kp = match self.version {
+            ProtocolVersion::XOnly(_) => Self::generate_key(),
+            // Any parity is fine since the encoding carries the full point
+            ProtocolVersion::EllSwift => Keypair::new(SECP256K1, &mut rand::thread_rng()),
+        };
+        self.keypair(kp)
+    }
+
+    fn pub_key<'a>(&self, keypair: &'a Self::KeyPair) -> &'a [u8] {
+        &keypair.encoded[..self.pub_key_len()]
+    }
+
+    /// The x-only key, ElligatorSwift encodings of the same key are randomized
+    fn canonical_pub_key(&self, pub_key: &[u8]) -> Result<[u8; 32], Error> {
+        let key = match self.version {
+            ProtocolVersion::XOnly(_) | ProtocolVersion::EllSwift if pub_key.len() == 32 => {
+                XOnlyPublicKey::from_slice(pub_key).map_err(|_| ErrorKind::InvalidPublicKey)?
+            }
+            ProtocolVersion::XOnly(_) => return Err(ErrorKind::InvalidPublicKey.into()),
+            ProtocolVersion::EllSwift => {
+                let encoded = pub_key
+                    .try_into()
+                    .map_err(|_| ErrorKind::InvalidPublicKey)?;
+                let key = secp256k1::PublicKey::from_ellswift(ElligatorSwift::from_array(encoded));
+                key.x_only_public_key().0
+            }
+        };
+        Ok(key.serialize())
+    }
+
+    fn dh(
+        &self,
+        keypair: &Self::KeyPair,
+        remote: &[u8],
+        initiator: bool,
+        out: &mut [u8; MAX_DH_LEN],
+    ) -> Result<(), Error> {
+        let private = keypair.kp.secret_key();
+        match self.version {
+            ProtocolVersion::XOnly(output) => {
+                let point = Zeroizing::new(Self::ecdh(&private, remote)?);
+                let shared_secret = output.shared_secret(&point);
+                out[..shared_secret.len()].copy_from_slice(shared_secret);
+            }
+            ProtocolVersion::EllSwift => {
+                let local = self.pub_key(keypair);
+                let shared_secret = Zeroizing::new(if initiator {
+                    Self::ellswift_ecdh(&private, local, remote, ElligatorSwiftParty::A)?
+                } else {
+                    Self::ellswift_ecdh(&private, remote, local, ElligatorSwiftParty::B)?
+                });
+                out[..32].copy_from_slice(&shared_secret[..]);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Curve25519 DH as defined by the noise specification
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct X25519;
+
+pub struct X25519KeyPair {
+    secret: StaticSecret,
+    public: [u8; 32],
+}
+
+impl X25519KeyPair {
+    pub fn from_secret(secret: [u8; 32]) -> Self {
+        let secret = StaticSecret::from(secret);
+        let public = PublicKey::from(&secret).to_bytes();
+        Self { secret, public }
+    }
+}
+
+impl Dh for X25519 {
+    type KeyPair = X25519KeyPair;
+
+    fn name(&self) -> &'static str {
+        "25519"
+    }
+
+    fn pub_key_len(&self) -> usize {
+        32
+    }
+
+    fn dh_len(&self) -> usize {
+        32
+    }
+
+    fn generate_keypair(&self) -> Self::KeyPair {
+        let secret = StaticSecret::random_from_rng(rand::thread_rng());
+        let public = PublicKey::from(&secret).to_bytes();
+        X25519KeyPair { secret, public }
+    }
+
+    fn pub_key<'a>(&self, keypair: &'a Self::KeyPair) -> &'a [u8] {
+        &keypair.public
+    }
+
+    fn dh(
+        &self,
+        keypair: &Self::KeyPair,
+        remote: &[u8],
+        _initiator: bool,
+        out: &mut [u8; MAX_DH_LEN],
+    ) -> Result<(), Error> {
+        let remote: [u8; 32] = remote.try_into().map_err(|_| ErrorKind::InvalidPublicKey)?;
+        let shared_secret = keypair.secret.diffie_hellman(&PublicKey::from(remote));
+        out[..32].copy_from_slice(shared_secret.as_bytes());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::EcdhOutput;
+    use quickcheck::{Arbitrary, TestResult};
+
+    #[test]
+    fn test_ecdh() {
+        let key_pair_1 = Secp256k1::generate_key();
+        let key_pair_2 = Secp256k1::generate_key();
+
+        let secret_1 = key_pair_1.secret_key();
+        let secret_2 = key_pair_2.secret_key();
+
+        let pub_1 = key_pair_1.x_only_public_key();
+        let pub_2 = key_pair_2.x_only_public_key();
+
+        let ecdh_1 = Secp256k1::ecdh(&secret_1, &pub_2.0.serialize()).unwrap();
+        let ecdh_2 = Secp256k1::ecdh(&secret_2, &pub_1.0.serialize()).unwrap();
+
+        assert!(ecdh_1 == ecdh_2);
+    }
+
+    // Known answer vectors computed with an independent secp256k1 implementation (pyca/cryptography)
+    const ECDH_PRIVATE: [u8; 32] = [
+        69, 35, 55, 14, 180, 70, 51, 125, 151, 64, 60, 211, 101, 69, 198, 183, 37, 76, 156, 230,
+        31, 192, 208, 128, 8, 164, 120, 248, 7, 17, 3, 14,
+    ];
+    const ECDH_PUBLIC: [u8; 32] = [
+        40, 24, 177, 166, 244, 72, 250, 61, 233, 102, 72, 66, 110, 164, 145, 239, 146, 169, 138, 8,
+        120, 60, 167, 64, 172, 0, 131, 154, 167, 249, 124, 197,
+    ];
+    const ECDH_SHARED_X: [u8; 32] = [
+        210, 156, 92, 114, 17, 77, 142, 0, 217, 236, 211, 102, 152, 154, 160, 57, 194, 248, 253,
+        30, 113, 127, 129, 0, 78, 67, 195, 194, 232, 77, 52, 10,
+    ];
+    const ECDH_SHARED_Y: [u8; 32] = [
+        72, 78, 12, 176, 102, 61, 95, 146, 15, 97, 147, 146, 234, 180, 181, 179, 65, 138, 12, 224,
+        169, 230, 229, 194, 174, 168, 146, 254, 100, 44, 142, 204,
+    ];
+
+    #[test]
+    fn test_canonical_pub_key() {
+        let kp = Secp256k1::generate_key();
+        let x_only = kp.x_only_public_key().0.serialize();
+        let dh = Secp256k1::new(ProtocolVersion::EllSwift);
+        let (a, b) = (dh.keypair(kp), dh.keypair(kp));
+        assert!(dh.pub_key(&a) != dh.pub_key(&b));
+        assert!(dh.canonical_pub_key(dh.pub_key(&a)).unwrap() == x_only);
+        assert!(dh.canonical_pub_key(dh.pub_key(&b)).unwrap() == x_only);
+        assert!(dh.canonical_pub_key(&x_only).unwrap() == x_only);
+        assert!(dh.canonical_pub_key(&[0xff; 32]).is_err());
+
+        let dh = Secp256k1::default();
+        assert!(dh.canonical_pub_key(dh.pub_key(&dh.keypair(kp))).unwrap() == x_only);
+        assert!(dh.canonical_pub_key(&[0xff; 32]).is_err());
+        assert!(X25519.canonical_pub_key(&[2; 33]).is_err());
+    }
+
+    #[test]
+    fn test_ecdh_x_coordinate_kat() {
+        let private = SecretKey::from_slice(&ECDH_PRIVATE).unwrap();
+        let ecdh = Secp256k1::ecdh(&private, &ECDH_PUBLIC).unwrap();
+        let shared_secret = EcdhOutput::XCoordinate.shared_secret(&ecdh);
+        assert!(shared_secret == ECDH_SHARED_X);
+    }
+
+    #[test]
+    fn test_ecdh_shared_point_kat() {
+        let private = SecretKey::from_slice(&ECDH_PRIVATE).unwrap();
+        let ecdh = Secp256k1::ecdh(&private, &ECDH_PUBLIC).unwrap();
+        let shared_secret = EcdhOutput::SharedPoint.shared_secret(&ecdh);
+        assert!(shared_secret[..32] == ECDH_SHARED_X);
+        assert!(shared_secret[32..] == ECDH_SHARED_Y);
+    }
+
+    #[test]
+    fn test_ellswift_ecdh_bip324_kat() {
+        // Last xswiftec_inv/ellswift_xdh vector from BIP324, we are the initiator
+        let private = [
+            0xf9, 0x0e, 0x08, 0x0c, 0x64, 0xb0, 0x58, 0x24, 0xc5, 0xa2, 0x4b, 0x25, 0x01, 0xd5,
+            0xae, 0xaf, 0x08, 0xaf, 0x38, 0x72, 0xee, 0x86, 0x0a, 0xa8, 0x0b, 0xdc, 0xd4, 0x30,
+            0xf7, 0xb6, 0x34, 0x94,
+        ];
+        let ellswift_ours = [
+            0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+            0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+            0x11, 0x51, 0x73, 0x76, 0x5d, 0xc2, 0x02, 0xcf, 0x02, 0x9a, 0xd3, 0xf1, 0x54, 0x79,
+            0x73, 0x5d, 0x57, 0x69, 0x7a, 0xf1, 0x2b, 0x01, 0x31, 0xdd, 0x21, 0x43, 0x0d, 0x57,
+            0x72, 0xe4, 0xef, 0x11, 0x47, 0x4d, 0x58, 0xb9,
+        ];
+        let ellswift_theirs = [
+            0x12, 0xa5, 0x0f, 0x3f, 0xaf, 0xea, 0x7c, 0x1e, 0xea, 0xda, 0x4c, 0xf8, 0xd3, 0x37,
+            0x77, 0x70, 0x4b, 0x77, 0x36, 0x14, 0x53, 0xaf, 0xc8, 0x3b, 0xda, 0x91, 0xee, 0xf3,
+            0x49, 0xae, 0x04, 0x4d, 0x20, 0x12, 0x6c, 0x62, 0x00, 0x54, 0x7e, 0xa5, 0xa6, 0x91,
+            0x17, 0x76, 0xc0, 0x5d, 0xee, 0x2a, 0x7f, 0x1a, 0x9b, 0xa7, 0xdf, 0xba, 0xbb, 0xbd,
+            0x27, 0x3c, 0x3e, 0xf2, 0x9e, 0xf4, 0x6e, 0x46,
+        ];
+        let expected = [
+            0xe2, 0x54, 0x61, 0xfb, 0x0e, 0x4c, 0x16, 0x2e, 0x18, 0x12, 0x3e, 0xcd, 0xe8, 0x83,
+            0x42, 0xd5, 0x4d, 0x44, 0x96, 0x31, 0xe9, 0xb7, 0x5a, 0x26, 0x6f, 0xd9, 0x26, 0x0c,
+            0x2b, 0xb2, 0xf4, 0x1d,
+        ];
+        let private = SecretKey::from_slice(&private).unwrap();
+        let ecdh = Secp256k1::ellswift_ecdh(
+            &private,
+            &ellswift_ours,
+            &ellswift_theirs,
+            ElligatorSwiftParty::A,
+        )
+        .unwrap();
+        assert!(ecdh == expected);
+    }
+
+    #[test]
+    fn test_ellswift_ecdh() {
+        let dh = Secp256k1::new(ProtocolVersion::EllSwift);
+        let key_pair_1 = dh.generate_keypair();
+        let key_pair_2 = dh.generate_keypair();
+
+        let mut ecdh_1 = [0; MAX_DH_LEN];
+        let mut ecdh_2 = [0; MAX_DH_LEN];
+        dh.dh(&key_pair_1, dh.pub_key(&key_pair_2), true, &mut ecdh_1)
+            .unwrap();
+        dh.dh(&key_pair_2, dh.pub_key(&key_pair_1), false, &mut ecdh_2)
+            .unwrap();
+
+        assert!(ecdh_1 == ecdh_2);
+    }
+
+    #[test]
+    fn test_x25519_dh() {
+        let key_pair_1 = X25519.generate_keypair();
+        let key_pair_2 = X25519.generate_keypair();
+
+        let mut dh_1 = [0; MAX_DH_LEN];
+        let mut dh_2 = [0; MAX_DH_LEN];
+        X25519
+            .dh(&key_pair_1, X25519.pub_key(&key_pair_2), true, &mut dh_1)
+            .unwrap();
+        X25519
+            .dh(&key_pair_2, X25519.pub_key(&key_pair_1), false, &mut dh_2)
+            .unwrap();
+
+        assert!(dh_1 == dh_2);
+    }
+
+    #[test]
+    fn test_invalid_public_key() {
+        let dh = Secp256k1::default();
+        let key_pair = dh.generate_keypair();
+        let mut out = [0; MAX_DH_LEN];
+        // x = p is not a valid coordinate
+        let mut invalid = [0xff; 32];
+        invalid[31] = 0x2f;
+        invalid[27] = 0xfe;
+        assert!(matches!(
+            dh.dh(&key_pair, &invalid, true, &mut out)
+                .map_err(Error::into_kind),
+            Err(ErrorKind::InvalidPublicKey)
+        ));
+        assert!(matches!(
+            X25519
+                .dh(&X25519.generate_keypair(), &[0; 31], true, &mut out)
+                .map_err(Error::into_kind),
+            Err(ErrorKind::InvalidPublicKey)
+        ));
+    }
+
+    #[derive(Clone, Debug)]
+    struct KeyPairWrapper(pub Option<Keypair>);
+
+    impl Arbitrary for KeyPairWrapper {
+        fn arbitrary(g: &mut quickcheck::Gen) -> Self {
+            let secp = secp256k1::Secp256k1::new();
+            let mut secret = Vec::<u8>::arbitrary(g);
+            if secret.len() < 32 {
+                while secret.len() < 32 {
+                    secret.push(0)
+                }
+            }
+            if secret.len() > 32 {
+                secret.truncate(32);
+            }
+            assert!(secret.len() == 32);
+            let secret: [u8; 32] = secret.try_into().unwrap();
+            match SecretKey::from_slice(&secret) {
+                Ok(secret) => KeyPairWrapper(Some(Keypair::from_secret_key(&secp, &secret))),
+                Err(_) => KeyPairWrapper(None),
+            }
+        }
+    }
+
+    #[quickcheck_macros::quickcheck]
+    fn test_ecdh_1(kp1: KeyPairWrapper, kp2: KeyPairWrapper) -> TestResult {
+        let (kp1, kp2) = match (kp1.0, kp2.0) {
+            (Some(kp1), Some(kp2)) => (kp1, kp2),
+            _ => return TestResult::discard(),
+        };
+        if kp1.x_only_public_key().1 == crate::PARITY && kp2.x_only_public_key().1 == crate::PARITY
+        {
+            let secret_1 = kp1.secret_key();
+            let secret_2 = kp2.secret_key();
+
+            let pub_1 = kp1.x_only_public_key();
+            let pub_2 = kp2.x_only_public_key();
+
+            let ecdh_1 = Secp256k1::ecdh(&secret_1, &pub_2.0.serialize()).unwrap();
+            let ecdh_2 = Secp256k1::ecdh(&secret_2, &pub_1.0.serialize()).unwrap();
+
+            if ecdh_1 == ecdh_2 {
+                TestResult::passed()
+            } else {
+                TestResult::failed()
+            }
+        } else {
+            TestResult::discard()
+        }
+    }
+}
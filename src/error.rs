@@ -1,19 +1,321 @@
+use crate::secret::Hex;
+use crate::signature_message::CertificateError;
 use aes_gcm::Error as AesGcm;
+use std::fmt;
+use std::ops::Deref;
 
+/// Error with, when it is returned by a handshake step, the role and the phase where it
+/// happened. Both are stored inline so that a failing step does not allocate.
 #[derive(Debug)]
-pub enum Error {
+pub struct Error {
+    kind: ErrorKind,
+    context: Option<(Role, Phase)>,
+}
+
+/// What went wrong, without the handshake context
+#[derive(Debug)]
+pub enum ErrorKind {
     HandshakeNotFinalized,
     CipherListMustBeNonEmpty,
-    UnsupportedCiphers(Vec<u8>),
-    InvalidCipherList(Vec<u8>),
-    InvalidCipherChosed(Vec<u8>),
+    UnsupportedCiphers(ErrorBytes),
+    InvalidCipherList(ErrorBytes),
+    InvalidCipherChosed(ErrorBytes),
     AesGcm(AesGcm),
     InvalidCipherState,
-    InvalidCertificate([u8; 74]),
+    /// A handshake message failed the MAC check
+    DecryptionFailed,
+    /// The SIGNATURE_NOISE_MESSAGE of the responder is rejected
+    InvalidCertificate(CertificateError),
+    /// A public key received from the peer can not be used for DH
+    InvalidPublicKey,
+    /// A received message does not have the expected length
+    InvalidMessageLength(usize),
+    /// The output buffer can not contain the message, the required length is reported
+    OutputBufferTooSmall(usize),
+    /// The string is not a valid or supported Noise protocol name
+    InvalidProtocolName(String),
+    /// The protocol name does not match the DH and hash functions of the handshake
+    UnsupportedProtocolName(String),
+    /// A SEQ0_32 cipher list can not have more than 32 entries
+    CipherListTooLong(usize),
+    /// The responder chose a cipher that the initiator ranks below its minimum, empty for
+    /// ChaChaPoly
+    CipherBelowMinimum(ErrorBytes),
+    /// A revocation list can not be parsed or is not signed by the authority
+    InvalidRevocationList(CertificateError),
+    /// A revocation list is older than the one already held for the same authority, it may be
+    /// replayed to lift newer revocations
+    OutdatedRevocationList {
+        issued_at: u32,
+        latest: u32,
+    },
+    /// The static key of the responder, in canonical form, is not one of the pinned keys
+    StaticKeyNotPinned([u8; 32]),
+    /// The keys of a known server are not the ones recorded on the first connection, someone may
+    /// be impersonating it
+    KnownServerChanged(String),
+    /// Entry of the known servers file that can not be parsed or written
+    InvalidKnownServers(String),
+    Io(std::io::Error),
+}
+
+/// Cipher list or cipher choice reported by an error. It is stored inline so that the handshake
+/// steps do not allocate when they fail, longer inputs are truncated to
+/// [`ErrorBytes::CAPACITY`] bytes.
+#[derive(Clone, Copy)]
+pub struct ErrorBytes {
+    bytes: [u8; ErrorBytes::CAPACITY],
+    len: u8,
+}
+
+impl ErrorBytes {
+    /// Length byte and first 8 codes of a `SEQ0_32[u32]`, it keeps `Error` small
+    pub const CAPACITY: usize = 1 + 4 * 8;
+
+    pub(crate) fn new(value: &[u8]) -> Self {
+        let len = value.len().min(Self::CAPACITY);
+        let mut bytes = [0; Self::CAPACITY];
+        bytes[..len].copy_from_slice(&value[..len]);
+        Self {
+            bytes,
+            len: len as u8,
+        }
+    }
+}
+
+impl Deref for ErrorBytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.bytes[..self.len as usize]
+    }
+}
+
+impl fmt::Debug for ErrorBytes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl PartialEq for ErrorBytes {
+    fn eq(&self, other: &Self) -> bool {
+        **self == **other
+    }
+}
+
+impl Eq for ErrorBytes {}
+
+impl PartialEq<[u8]> for ErrorBytes {
+    fn eq(&self, other: &[u8]) -> bool {
+        **self == *other
+    }
+}
+
+impl<const N: usize> PartialEq<[u8; N]> for ErrorBytes {
+    fn eq(&self, other: &[u8; N]) -> bool {
+        **self == other[..]
+    }
+}
+
+impl PartialEq<Vec<u8>> for ErrorBytes {
+    fn eq(&self, other: &Vec<u8>) -> bool {
+        **self == other[..]
+    }
+}
+
+/// Side of the handshake
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Initiator,
+    Responder,
+}
+
+/// Part of the NX handshake
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    /// `-> e` and `<- e, ee`
+    EphemeralKey,
+    /// `<- s, es`
+    StaticKey,
+    /// `SIGNATURE_NOISE_MESSAGE`
+    Certificate,
+    /// `AEAD_CIPHERS` and `CIPHER_CHOICE`
+    CipherNegotiation,
+}
+
+impl Error {
+    /// Add the role and the phase of the handshake, an error that already has them is returned
+    /// as it is
+    pub(crate) fn at(mut self, role: Role, phase: Phase) -> Self {
+        self.context.get_or_insert((role, phase));
+        self
+    }
+
+    /// The error without the handshake context
+    pub fn kind(&self) -> &ErrorKind {
+        &self.kind
+    }
+
+    /// Same as [`Error::kind`] but take ownership
+    pub fn into_kind(self) -> ErrorKind {
+        self.kind
+    }
+
+    /// Role of the handshake that failed, if the error comes from a handshake step
+    pub fn role(&self) -> Option<Role> {
+        self.context.map(|(role, _)| role)
+    }
+
+    /// Phase of the handshake that failed, if the error comes from a handshake step
+    pub fn phase(&self) -> Option<Phase> {
+        self.context.map(|(_, phase)| phase)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.context {
+            Some((role, phase)) => write!(f, "{} failed at {}: {}", role, phase, self.kind),
+            None => self.kind.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self.context {
+            Some(_) => Some(&self.kind),
+            None => self.kind.source(),
+        }
+    }
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::HandshakeNotFinalized => f.write_str("handshake not finalized"),
+            Self::CipherListMustBeNonEmpty => f.write_str("cipher list must be non empty"),
+            Self::UnsupportedCiphers(list) => write!(f, "no supported cipher in {:?}", list),
+            Self::InvalidCipherList(list) => write!(f, "invalid cipher list {:?}", list),
+            Self::InvalidCipherChosed(choice) => write!(f, "invalid cipher choice {:?}", choice),
+            Self::AesGcm(_) => f.write_str("AEAD operation failed"),
+            Self::InvalidCipherState => f.write_str("invalid cipher state"),
+            Self::DecryptionFailed => f.write_str("decryption failed"),
+            Self::InvalidCertificate(e) => write!(f, "invalid certificate: {}", e),
+            Self::InvalidPublicKey => f.write_str("invalid public key"),
+            Self::InvalidMessageLength(len) => write!(f, "invalid message length {}", len),
+            Self::OutputBufferTooSmall(len) => {
+                write!(f, "output buffer too small, {} bytes are needed", len)
+            }
+            Self::InvalidProtocolName(name) => write!(f, "invalid protocol name {}", name),
+            Self::UnsupportedProtocolName(name) => write!(f, "unsupported protocol name {}", name),
+            Self::CipherListTooLong(len) => write!(f, "cipher list too long: {} entries", len),
+            Self::CipherBelowMinimum(code) => write!(f, "cipher {:?} below the minimum", code),
+            Self::InvalidRevocationList(e) => write!(f, "invalid revocation list: {}", e),
+            Self::OutdatedRevocationList { issued_at, latest } => write!(
+                f,
+                "revocation list issued at {} is older than the one issued at {}",
+                issued_at, latest
+            ),
+            Self::StaticKeyNotPinned(key) => {
+                write!(f, "static key {:?} is not pinned", Hex(key))
+            }
+            Self::KnownServerChanged(endpoint) => write!(
+                f,
+                "keys of known server {} changed, it may be impersonated",
+                endpoint
+            ),
+            Self::InvalidKnownServers(entry) => {
+                write!(f, "invalid known servers entry {:?}", entry)
+            }
+            Self::Io(e) => write!(f, "I/O error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ErrorKind {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::AesGcm(e) => Some(e),
+            Self::InvalidCertificate(e) | Self::InvalidRevocationList(e) => Some(e),
+            Self::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Initiator => f.write_str("initiator"),
+            Self::Responder => f.write_str("responder"),
+        }
+    }
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::EphemeralKey => f.write_str("ephemeral key"),
+            Self::StaticKey => f.write_str("static key"),
+            Self::Certificate => f.write_str("certificate"),
+            Self::CipherNegotiation => f.write_str("cipher negotiation"),
+        }
+    }
+}
+
+impl From<ErrorKind> for Error {
+    fn from(kind: ErrorKind) -> Self {
+        Self {
+            kind,
+            context: None,
+        }
+    }
+}
+
+impl From<CertificateError> for Error {
+    fn from(value: CertificateError) -> Self {
+        ErrorKind::InvalidCertificate(value).into()
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(value: std::io::Error) -> Self {
+        ErrorKind::Io(value).into()
+    }
 }
 
 impl From<AesGcm> for Error {
     fn from(value: AesGcm) -> Self {
-        Self::AesGcm(value)
+        ErrorKind::AesGcm(value).into()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::error::Error as _;
+
+    #[test]
+    fn test_context() {
+        let e = Error::from(ErrorKind::DecryptionFailed)
+            .at(Role::Initiator, Phase::StaticKey)
+            .at(Role::Responder, Phase::Certificate);
+        assert!(e.role() == Some(Role::Initiator));
+        assert!(e.phase() == Some(Phase::StaticKey));
+        assert!(matches!(e.kind(), ErrorKind::DecryptionFailed));
+        assert!(e.to_string() == "initiator failed at static key: decryption failed");
+        assert!(e.source().unwrap().to_string() == "decryption failed");
+        assert!(matches!(e.into_kind(), ErrorKind::DecryptionFailed));
+    }
+
+    #[test]
+    fn test_error_bytes() {
+        let list = ErrorBytes::new(&[1, b'A', b'E', b'S', b'G']);
+        assert!(list == [1, b'A', b'E', b'S', b'G']);
+        assert!(format!("{:?}", list) == "[1, 65, 69, 83, 71]");
+        let long = [7; 100];
+        assert!(ErrorBytes::new(&long) == long[..ErrorBytes::CAPACITY]);
+        assert!(ErrorBytes::new(&[]).is_empty());
     }
 }
@@ -1,130 +1,100 @@
 use crate::aed_cipher::AeadCipher;
 use crate::cipher_state::CipherState;
-use crate::HASHED_PROTOCOL_NAME_CHACHA;
-use chacha20poly1305::ChaCha20Poly1305;
-use secp256k1::ecdh::shared_secret_point;
-use secp256k1::hashes::{sha256::Hash as Sha256Hash, Hash};
-use secp256k1::{rand, KeyPair, Secp256k1, SecretKey, XOnlyPublicKey};
+use crate::error::{Error, ErrorKind};
+use crate::hash::{NoiseHash, MAX_HASH_LEN};
+use crate::protocol_name::ProtocolName;
+use chacha20poly1305::{ChaCha20Poly1305, KeyInit};
+use zeroize::Zeroizing;
 
 pub trait HandshakeOp<Cipher: AeadCipher>: CipherState<Cipher> {
+    /// Hash function of the symmetric state
+    type Hash: NoiseHash;
+
+    #[allow(dead_code)]
     fn name(&self) -> String;
-    fn get_h(&mut self) -> &mut [u8; 32];
+    fn get_h(&mut self) -> &mut [u8; MAX_HASH_LEN];
 
-    fn get_ck(&mut self) -> &mut [u8; 32];
+    fn get_ck(&mut self) -> &mut [u8; MAX_HASH_LEN];
 
-    fn set_h(&mut self, data: [u8; 32]);
+    fn set_h(&mut self, data: [u8; MAX_HASH_LEN]);
 
-    fn set_ck(&mut self, data: [u8; 32]);
+    fn set_ck(&mut self, data: [u8; MAX_HASH_LEN]);
 
     fn mix_hash(&mut self, data: &[u8]) {
         let h = self.get_h();
-        let mut to_hash = Vec::with_capacity(32 + data.len());
-        to_hash.extend_from_slice(h);
-        to_hash.extend_from_slice(data);
-        *h = Sha256Hash::hash(&to_hash).to_byte_array();
-    }
-
-    fn generate_key() -> KeyPair {
-        let secp = Secp256k1::new();
-        let (secret_key, _) = secp.generate_keypair(&mut rand::thread_rng());
-        let kp = KeyPair::from_secret_key(&secp, &secret_key);
-        if kp.x_only_public_key().1 == crate::PARITY {
-            kp
-        } else {
-            Self::generate_key()
-        }
-    }
-
-    fn hmac_hash(key: &[u8; 32], data: &[u8]) -> [u8; 32] {
-        #[allow(clippy::identity_op)]
-        let mut ipad = [(0 ^ 0x36); 64];
-        #[allow(clippy::identity_op)]
-        let mut opad = [(0 ^ 0x5c); 64];
-        for i in 0..32 {
-            ipad[i] = key[i] ^ 0x36;
-        }
-        for i in 0..32 {
-            opad[i] = key[i] ^ 0x5c;
-        }
-
-        let mut to_hash = Vec::with_capacity(64 + data.len());
-        to_hash.extend_from_slice(&ipad);
-        to_hash.extend_from_slice(data);
-        let temp = Sha256Hash::hash(&to_hash).to_byte_array();
-
-        to_hash.clear();
-        to_hash.extend_from_slice(&opad);
-        to_hash.extend_from_slice(&temp);
-
-        Sha256Hash::hash(&to_hash).to_byte_array()
-    }
-
-    fn hkdf_2(chaining_key: &[u8; 32], input_key_material: &[u8]) -> ([u8; 32], [u8; 32]) {
-        let temp_key = Self::hmac_hash(chaining_key, input_key_material);
-        let out_1 = Self::hmac_hash(&temp_key, &[0x1]);
-        let out_2 = Self::hmac_hash(&temp_key, &[&out_1[..], &[0x2][..]].concat());
-        (out_1, out_2)
+        *h = Self::Hash::hash(&[&h[..Self::Hash::HASH_LEN], data]);
     }
 
-    fn hkdf_3(
-        chaining_key: &[u8; 32],
+    fn hkdf_2(
+        chaining_key: &[u8],
         input_key_material: &[u8],
-    ) -> ([u8; 32], [u8; 32], [u8; 32]) {
-        let temp_key = Self::hmac_hash(chaining_key, input_key_material);
-        let out_1 = Self::hmac_hash(&temp_key, &[0x1]);
-        let out_2 = Self::hmac_hash(&temp_key, &[&out_1[..], &[0x2][..]].concat());
-        let out_3 = Self::hmac_hash(&temp_key, &[&out_2[..], &[0x3][..]].concat());
-        (out_1, out_2, out_3)
+    ) -> ([u8; MAX_HASH_LEN], [u8; MAX_HASH_LEN]) {
+        Self::Hash::hkdf_2(chaining_key, input_key_material)
     }
 
     fn mix_key(&mut self, input_key_material: &[u8]) {
-        let ck = self.get_ck();
-        let (ck, temp_k) = Self::hkdf_2(ck, input_key_material);
+        let ck = Zeroizing::new(*self.get_ck());
+        let (ck, temp_k) = Self::hkdf_2(&ck[..Self::Hash::HASH_LEN], input_key_material);
+        let temp_k = Zeroizing::new(temp_k);
         self.set_ck(ck);
-        self.initialize_key(temp_k);
+        // If HASHLEN is 64, then truncates temp_k to 32 bytes
+        self.initialize_key(temp_k[..32].try_into().unwrap());
     }
 
-    fn encrypt_and_hash(&mut self, plaintext: &mut Vec<u8>) -> Result<(), aes_gcm::Error> {
-        if self.get_k().is_some() {
-            #[allow(clippy::clone_on_copy)]
-            let h = self.get_h().clone();
-            self.encrypt_with_ad(&h, plaintext)?;
-        };
-        let ciphertext = plaintext;
-        self.mix_hash(ciphertext);
-        Ok(())
+    /// `temp_k1, temp_k2 = HKDF(ck, zerolen, 2)` truncated to 32 bytes, used by `Split()`
+    fn split_keys(&mut self) -> ([u8; 32], [u8; 32]) {
+        let ck = Zeroizing::new(*self.get_ck());
+        let (temp_k1, temp_k2) = Self::hkdf_2(&ck[..Self::Hash::HASH_LEN], &[]);
+        let (temp_k1, temp_k2) = (Zeroizing::new(temp_k1), Zeroizing::new(temp_k2));
+        (
+            temp_k1[..32].try_into().unwrap(),
+            temp_k2[..32].try_into().unwrap(),
+        )
     }
 
-    fn decrypt_and_hash(&mut self, ciphertext: &mut Vec<u8>) -> Result<(), aes_gcm::Error> {
-        let encrypted = ciphertext.clone();
-        if self.get_k().is_some() {
-            #[allow(clippy::clone_on_copy)]
-            let h = self.get_h().clone();
-            self.decrypt_with_ad(&h, ciphertext)?;
+    /// Encrypt the first `len` bytes of `buf` in place, `buf` must have room for the MAC when k is
+    /// set. Return the length of the ciphertext.
+    fn encrypt_and_hash(&mut self, buf: &mut [u8], len: usize) -> Result<usize, aes_gcm::Error> {
+        let len = if self.get_k().is_some() {
+            let h = *self.get_h();
+            self.encrypt_with_ad_slice(&h[..Self::Hash::HASH_LEN], buf, len)?
+        } else {
+            len
         };
-        self.mix_hash(&encrypted);
-        Ok(())
+        self.mix_hash(&buf[..len]);
+        Ok(len)
     }
 
-    fn ecdh(private: &[u8], public: &[u8]) -> [u8; 64] {
-        let private = SecretKey::from_slice(private).expect("Wrong key");
-        let x_public = XOnlyPublicKey::from_slice(public).expect("Wrong key");
-        shared_secret_point(&x_public.public_key(crate::PARITY), &private)
+    /// Decrypt `buf` in place. Return the length of the plaintext left at the start of `buf`.
+    fn decrypt_and_hash(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        let h = *self.get_h();
+        let h = &h[..Self::Hash::HASH_LEN];
+        // h must be computed over the ciphertext, that is overwritten by the decryption
+        let next_h = Self::Hash::hash(&[h, buf]);
+        let len = if self.get_k().is_some() {
+            self.decrypt_with_ad_slice(h, buf)
+                .map_err(|_| ErrorKind::DecryptionFailed)?
+        } else {
+            buf.len()
+        };
+        self.set_h(next_h);
+        Ok(len)
     }
 
     /// Prior to starting first round of NX-handshake, both initiator and responder initializes
     /// handshake variables h (hash output), ck (chaining key) and k (encryption key):
-    fn initialize_self(&mut self) {
-        let ck = HASHED_PROTOCOL_NAME_CHACHA;
-        let h = Sha256Hash::hash(&ck[..]);
-        self.set_h(h.to_byte_array());
+    /// `h = ck = pad_or_hash(protocol_name)` followed by `MixHash` with the empty prologue.
+    fn initialize_self(&mut self, protocol_name: &ProtocolName) {
+        let ck = protocol_name.pad_or_hash::<Self::Hash>();
+        self.set_h(ck);
         self.set_ck(ck);
+        self.mix_hash(&[]);
         self.set_k(None);
     }
 
     fn initialize_key(&mut self, key: [u8; 32]) {
         self.set_n(0);
-        let cipher = ChaCha20Poly1305::from_key(key);
+        let cipher = ChaCha20Poly1305::new(&key.into());
         self.set_handshake_cipher(cipher);
         if let Some(k) = self.get_k() {
             *k = key;
@@ -140,32 +110,44 @@ pub trait HandshakeOp<Cipher: AeadCipher>: CipherState<Cipher> {
 #[cfg(test)]
 mod test {
     use super::*;
-    use quickcheck::{Arbitrary, TestResult};
-    use quickcheck_macros;
+    use crate::hash::{Blake2b, Blake2s, Sha256, Sha512};
+    use crate::CHACHA_POLY_TAG_SIZE;
+    use secp256k1::hashes::{sha256::Hash as Sha256Hash, Hash};
+    use std::marker::PhantomData;
 
-    struct TestHandShake {
+    struct TestHandShake<H: NoiseHash = Sha256> {
         k: Option<[u8; 32]>,
         n: u64,
         cipher: Option<ChaCha20Poly1305>,
-        h: [u8; 32],
-        ck: [u8; 32],
+        h: [u8; MAX_HASH_LEN],
+        ck: [u8; MAX_HASH_LEN],
+        hash: PhantomData<H>,
     }
 
-    impl TestHandShake {
+    impl<H: NoiseHash> TestHandShake<H> {
         pub fn new() -> Self {
+            Self::with_dh_name("secp256k1")
+        }
+
+        pub fn with_dh_name(dh_name: &str) -> Self {
+            Self::with_protocol_name(&ProtocolName::nx(dh_name, H::NAME))
+        }
+
+        pub fn with_protocol_name(protocol_name: &ProtocolName) -> Self {
             let mut self_ = TestHandShake {
                 k: None,
                 n: 0,
                 cipher: None,
-                h: [0; 32],
-                ck: [0; 32],
+                h: [0; MAX_HASH_LEN],
+                ck: [0; MAX_HASH_LEN],
+                hash: PhantomData,
             };
-            self_.initialize_self();
+            self_.initialize_self(protocol_name);
             self_
         }
     }
 
-    impl CipherState<ChaCha20Poly1305> for TestHandShake {
+    impl<H: NoiseHash> CipherState<ChaCha20Poly1305> for TestHandShake<H> {
         fn get_k(&mut self) -> &mut Option<[u8; 32]> {
             &mut self.k
         }
@@ -187,24 +169,26 @@ mod test {
         }
     }
 
-    impl HandshakeOp<ChaCha20Poly1305> for TestHandShake {
+    impl<H: NoiseHash> HandshakeOp<ChaCha20Poly1305> for TestHandShake<H> {
+        type Hash = H;
+
         fn name(&self) -> String {
             "Test".to_string()
         }
 
-        fn get_h(&mut self) -> &mut [u8; 32] {
+        fn get_h(&mut self) -> &mut [u8; MAX_HASH_LEN] {
             &mut self.h
         }
 
-        fn get_ck(&mut self) -> &mut [u8; 32] {
+        fn get_ck(&mut self) -> &mut [u8; MAX_HASH_LEN] {
             &mut self.ck
         }
 
-        fn set_h(&mut self, data: [u8; 32]) {
+        fn set_h(&mut self, data: [u8; MAX_HASH_LEN]) {
             self.h = data
         }
 
-        fn set_ck(&mut self, data: [u8; 32]) {
+        fn set_ck(&mut self, data: [u8; MAX_HASH_LEN]) {
             self.ck = data
         }
 
@@ -215,8 +199,8 @@ mod test {
 
     #[test]
     fn is_a_cypher() {
-        let mut cipher_1 = TestHandShake::new();
-        let mut cipher_2 = TestHandShake::new();
+        let mut cipher_1 = TestHandShake::<Sha256>::new();
+        let mut cipher_2 = TestHandShake::<Sha256>::new();
         cipher_1.initialize_key([0; 32]);
         cipher_2.initialize_key([0; 32]);
 
@@ -235,7 +219,7 @@ mod test {
     fn test_hmac_hash_with_0s() {
         let k = [0; 32];
         let data = [0; 90];
-        let value = TestHandShake::hmac_hash(&k, &data);
+        let value = Sha256::hmac(&k, &[&data]);
 
         // xor padded key with repeted 0x36
         let xored = [0x36; 64];
@@ -258,19 +242,19 @@ mod test {
         }
         let expected = Sha256Hash::hash(&to_hash).to_byte_array();
 
-        assert!(value == expected);
+        assert!(value[..32] == expected);
     }
 
     #[test]
     fn test_hkdf2() {
         let chaining_key = [0; 32];
         let input_key_material = [0; 32];
-        let temp_k = TestHandShake::hmac_hash(&chaining_key, &input_key_material);
-        let expected_1 = TestHandShake::hmac_hash(&temp_k, &[0x1]);
-        let mut temp_2 = expected_1.to_vec();
+        let temp_k = Sha256::hmac(&chaining_key, &[&input_key_material]);
+        let expected_1 = Sha256::hmac(&temp_k[..32], &[&[0x1]]);
+        let mut temp_2 = expected_1[..32].to_vec();
         temp_2.push(0x2);
-        let expected_2 = TestHandShake::hmac_hash(&temp_k, &temp_2);
-        let (out_1, out_2) = TestHandShake::hkdf_2(&chaining_key, &input_key_material);
+        let expected_2 = Sha256::hmac(&temp_k[..32], &[&temp_2]);
+        let (out_1, out_2) = TestHandShake::<Sha256>::hkdf_2(&chaining_key, &input_key_material);
         assert!(out_1 == expected_1);
         assert!(out_2 == expected_2);
     }
@@ -278,121 +262,227 @@ mod test {
     #[test]
     fn test_mix_key() {
         let input_key_material = [0; 32];
-        let ck = [0; 32];
-        let mut tester = TestHandShake::new();
+        let ck = [0; MAX_HASH_LEN];
+        let mut tester = TestHandShake::<Sha256>::new();
         tester.set_ck(ck);
 
-        let (mut ck, temp_k) = TestHandShake::hkdf_2(&ck, &input_key_material);
+        let (mut ck, temp_k) = TestHandShake::<Sha256>::hkdf_2(&ck[..32], &input_key_material);
 
         tester.mix_key(&input_key_material);
 
         assert!(tester.get_ck() == &mut ck);
-        assert!(tester.get_k().unwrap() == temp_k);
+        assert!(tester.get_k().unwrap() == temp_k[..32]);
     }
 
     #[test]
     fn test_mix_hash() {
         let data = [0; 32];
-        let h = [0; 32];
-        let mut tester = TestHandShake::new();
+        let h = [0; MAX_HASH_LEN];
+        let mut tester = TestHandShake::<Sha256>::new();
         tester.set_h(h);
 
-        let mut to_hash = h.to_vec();
+        let mut to_hash = h[..32].to_vec();
         to_hash.extend_from_slice(&data);
-        let mut expected = Sha256Hash::hash(&to_hash).to_byte_array();
+        let expected = Sha256Hash::hash(&to_hash).to_byte_array();
 
         tester.mix_hash(&data);
 
-        assert!(tester.get_h() == &mut expected);
+        assert!(tester.get_h()[..32] == expected);
     }
 
     #[test]
     fn test_decrypt_encrypt_with_hash() {
-        let mut cipher_1 = TestHandShake::new();
-        let mut cipher_2 = TestHandShake::new();
+        let mut cipher_1 = TestHandShake::<Sha256>::new();
+        let mut cipher_2 = TestHandShake::<Sha256>::new();
         cipher_1.initialize_key([0; 32]);
         cipher_2.initialize_key([0; 32]);
 
-        cipher_1.set_h([0; 32]);
-        cipher_2.set_h([0; 32]);
+        cipher_1.set_h([0; MAX_HASH_LEN]);
+        cipher_2.set_h([0; MAX_HASH_LEN]);
 
-        let data = vec![1, 7, 92, 3, 4, 5];
+        let data = [1, 7, 92, 3, 4, 5];
 
-        let mut encrypted = data.clone();
-        cipher_1.encrypt_and_hash(&mut encrypted).unwrap();
-        assert!(encrypted != data);
+        let mut encrypted = [0; 6 + CHACHA_POLY_TAG_SIZE];
+        encrypted[..6].copy_from_slice(&data);
+        let len = cipher_1.encrypt_and_hash(&mut encrypted, 6).unwrap();
+        assert!(len == encrypted.len());
+        assert!(encrypted[..6] != data);
 
-        cipher_2.decrypt_and_hash(&mut encrypted).unwrap();
+        let len = cipher_2.decrypt_and_hash(&mut encrypted).unwrap();
 
-        assert!(encrypted == data);
+        assert!(encrypted[..len] == data);
         assert!(cipher_1.get_h() == cipher_2.get_h());
     }
 
     #[test]
-    fn test_ecdh() {
-        let key_pair_1 = TestHandShake::generate_key();
-        let key_pair_2 = TestHandShake::generate_key();
+    fn test_initialize_self() {
+        let name = crate::PROTOCOL_NAME_CHACHA.parse().unwrap();
+        let mut tester = TestHandShake::<Sha256>::with_protocol_name(&name);
+        let ck = name.pad_or_hash::<Sha256>();
+        assert!(tester.get_ck() == &ck);
+        assert!(tester.get_h()[..32] == Sha256Hash::hash(&ck[..32]).to_byte_array());
+        assert!(tester.get_k().is_none());
+    }
 
-        let secret_1 = key_pair_1.secret_bytes();
-        let secret_2 = key_pair_2.secret_bytes();
+    fn from_hex(s: &str) -> Vec<u8> {
+        hex::decode(s).unwrap()
+    }
 
-        let pub_1 = key_pair_1.x_only_public_key();
-        let pub_2 = key_pair_2.x_only_public_key();
+    const NX_PROLOGUE: &str = "4a6f686e2047616c74";
+    const NX_INIT_EPHEMERAL: &str =
+        "893e28b9dc6ca8d611ab664754b8ceb7bac5117349a4439a6b0569da977c464a";
+    const NX_RESP_STATIC: &str = "4a3acbfdb163dec651dfa3194dece676d437029c62a408b4c5ea9114246e4893";
+    const NX_RESP_EPHEMERAL: &str =
+        "bbdb4cdbd309f1a1f2e1456967fe288cadd6f712d65dc7b7793d5e63da6b375b";
+    const NX_PAYLOADS: [&str; 6] = [
+        "4c756477696720766f6e204d69736573",
+        "4d757272617920526f746862617264",
+        "462e20412e20486179656b",
+        "4361726c204d656e676572",
+        "4a65616e2d426170746973746520536179",
+        "457567656e2042f6686d20766f6e2042617765726b",
+    ];
+
+    /// Run a `Noise_NX_25519_ChaChaPoly_<H>` vector from cacophony. The keys, the prologue and the
+    /// payloads are the same for every hash function.
+    fn check_nx_25519_vector<H: NoiseHash>(handshake_hash: &str, ciphertexts: [&str; 6]) {
+        use crate::cipher_state::Cipher;
+        use crate::dh::{Dh, X25519KeyPair, MAX_DH_LEN, X25519};
+
+        let key = |s| X25519KeyPair::from_secret(from_hex(s).try_into().unwrap());
+        let init_e = key(NX_INIT_EPHEMERAL);
+        let resp_s = key(NX_RESP_STATIC);
+        let resp_e = key(NX_RESP_EPHEMERAL);
+        let dh = X25519;
+        let mut shared = [0; MAX_DH_LEN];
+
+        let mut initiator = TestHandShake::<H>::with_dh_name(dh.name());
+        let mut responder = TestHandShake::<H>::with_dh_name(dh.name());
+        // initialize_self already called MixHash with the empty prologue
+        for state in [&mut initiator, &mut responder] {
+            let ck = *state.get_ck();
+            state.set_h(ck);
+            state.mix_hash(&from_hex(NX_PROLOGUE));
+        }
 
-        let ecdh_1 = TestHandShake::ecdh(&secret_1, &pub_2.0.serialize());
-        let ecdh_2 = TestHandShake::ecdh(&secret_2, &pub_1.0.serialize());
+        // -> e
+        let mut message = dh.pub_key(&init_e).to_vec();
+        initiator.mix_hash(&message);
+        let mut payload = from_hex(NX_PAYLOADS[0]);
+        let len = payload.len();
+        initiator.encrypt_and_hash(&mut payload, len).unwrap();
+        message.extend_from_slice(&payload);
+        assert!(message == from_hex(ciphertexts[0]));
+
+        responder.mix_hash(&message[..32]);
+        responder.decrypt_and_hash(&mut message[32..]).unwrap();
+
+        // <- e, ee, s, es
+        let payload = from_hex(NX_PAYLOADS[1]);
+        let mut message = vec![0; 32 + 48 + payload.len() + 16];
+        message[..32].copy_from_slice(dh.pub_key(&resp_e));
+        responder.mix_hash(&message[..32]);
+        dh.dh(&resp_e, dh.pub_key(&init_e), false, &mut shared)
+            .unwrap();
+        responder.mix_key(&shared[..32]);
+        message[32..64].copy_from_slice(dh.pub_key(&resp_s));
+        responder
+            .encrypt_and_hash(&mut message[32..80], 32)
+            .unwrap();
+        dh.dh(&resp_s, dh.pub_key(&init_e), false, &mut shared)
+            .unwrap();
+        responder.mix_key(&shared[..32]);
+        message[80..80 + payload.len()].copy_from_slice(&payload);
+        responder
+            .encrypt_and_hash(&mut message[80..], payload.len())
+            .unwrap();
+        assert!(message == from_hex(ciphertexts[1]));
+
+        initiator.mix_hash(&message[..32]);
+        dh.dh(&init_e, &message[..32], true, &mut shared).unwrap();
+        initiator.mix_key(&shared[..32]);
+        let len = initiator.decrypt_and_hash(&mut message[32..80]).unwrap();
+        assert!(&message[32..32 + len] == dh.pub_key(&resp_s));
+        dh.dh(&init_e, dh.pub_key(&resp_s), true, &mut shared)
+            .unwrap();
+        initiator.mix_key(&shared[..32]);
+        let len = initiator.decrypt_and_hash(&mut message[80..]).unwrap();
+        assert!(message[80..80 + len] == payload[..]);
+
+        let handshake_hash = from_hex(handshake_hash);
+        assert!(initiator.get_h()[..H::HASH_LEN] == handshake_hash[..]);
+        assert!(responder.get_h()[..H::HASH_LEN] == handshake_hash[..]);
+
+        // Split() and transport messages, initiator and responder alternate
+        let (k1, k2) = initiator.split_keys();
+        assert!((k1, k2) == responder.split_keys());
+        let mut c1 = Cipher::from_key_and_cipher(k1, ChaCha20Poly1305::new(&k1.into()));
+        let mut c2 = Cipher::from_key_and_cipher(k2, ChaCha20Poly1305::new(&k2.into()));
+        for i in 2..6 {
+            let cipher = if i % 2 == 0 { &mut c1 } else { &mut c2 };
+            let mut message = from_hex(NX_PAYLOADS[i]);
+            cipher.encrypt_with_ad(&[], &mut message).unwrap();
+            assert!(message == from_hex(ciphertexts[i]));
+        }
+    }
 
-        assert!(ecdh_1 == ecdh_2);
+    #[test]
+    fn test_nx_25519_sha256_cacophony_vector() {
+        check_nx_25519_vector::<Sha256>(
+            "6959d38aed4b70824a50c722b47c07e00e88eb3eb14f351c11cbee4f56dac33b",
+            [
+                "ca35def5ae56cec33dc2036731ab14896bc4c75dbb07a61f879f8e3afa4c79444c756477696720766f6e204d69736573",
+                "95ebc60d2b1fa672c1f46a8aa265ef51bfe38e7ccb39ec5be34069f1448088430da8899553a0e2d18bb3bcdf632634e25dd60e400ecc50c371de2cd83257c7636c5913e463b6bd3f3efe3eb1c9e92f10dde5d45c312e42ff98cfadd9f9e92b01ec7604e5d2150eef5db0aed53ab203",
+                "deefd230bea16077f1ceecaad5e4284c3bf2c564e20f694a61b9d4",
+                "6bfa60de93cf432f460dcc86cf66716c22ffb502125832433808c0",
+                "9c9608d8fc3ef689ae393775e8bb60c16f28ab12ff5c94015961e54addb3d64983",
+                "2490983755cc8a904f08a5876acb67db6821de003421b2f72f9f2389b21105ed4d43c4c799",
+            ],
+        );
     }
 
-    #[derive(Clone, Debug)]
-    struct KeyPairWrapper(pub Option<KeyPair>);
-
-    impl Arbitrary for KeyPairWrapper {
-        fn arbitrary(g: &mut quickcheck::Gen) -> Self {
-            let secp = Secp256k1::new();
-            let mut secret = Vec::<u8>::arbitrary(g);
-            if secret.len() < 32 {
-                while secret.len() < 32 {
-                    secret.push(0)
-                }
-            }
-            if secret.len() > 32 {
-                secret.truncate(32);
-            }
-            assert!(secret.len() == 32);
-            let secret: [u8; 32] = secret.try_into().unwrap();
-            match SecretKey::from_slice(&secret) {
-                Ok(secret) => KeyPairWrapper(Some(KeyPair::from_secret_key(&secp, &secret))),
-                Err(_) => KeyPairWrapper(None),
-            }
-        }
+    #[test]
+    fn test_nx_25519_sha512_cacophony_vector() {
+        check_nx_25519_vector::<Sha512>(
+            "275fe48ac79c2f38c81445930a07a9d3a85f2a8441e3b28d63850a6cbbaf0f868b1191976cd536b77f5d8a32d17d767eadd8c9da6044bc199310ee4a103abf7b",
+            [
+                "ca35def5ae56cec33dc2036731ab14896bc4c75dbb07a61f879f8e3afa4c79444c756477696720766f6e204d69736573",
+                "95ebc60d2b1fa672c1f46a8aa265ef51bfe38e7ccb39ec5be34069f144808843d7033a16d017752931eda232f53de376186ee0789814f8602f8846caed931f8b37a56e1332567e55be4ce8d6dae2d01cbca863ca486278a09b3fc8c31c483f257c05eb929eb7f996a03873bd816bf4",
+                "ca2b559d543393f35f58c3aeeb5f63f2175d93c64221b16ef2c8eb",
+                "7031c94c6b57ceb356700623c3ac019480b1c0c81c23c07806b08b",
+                "d1bc169936cf4d4e983d7605506abc57b0079e8c0d62cb3ac1d2097a79675bc33d",
+                "bf377483f96ef5a77c8cd85524ee8f246c4e30d8c85a406f5a2bddd9ce86c1590cd334af2c",
+            ],
+        );
     }
 
-    #[quickcheck_macros::quickcheck]
-    fn test_ecdh_1(kp1: KeyPairWrapper, kp2: KeyPairWrapper) -> TestResult {
-        let (kp1, kp2) = match (kp1.0, kp2.0) {
-            (Some(kp1), Some(kp2)) => (kp1, kp2),
-            _ => return TestResult::discard(),
-        };
-        if kp1.x_only_public_key().1 == crate::PARITY && kp2.x_only_public_key().1 == crate::PARITY
-        {
-            let secret_1 = kp1.secret_bytes();
-            let secret_2 = kp2.secret_bytes();
-
-            let pub_1 = kp1.x_only_public_key();
-            let pub_2 = kp2.x_only_public_key();
-
-            let ecdh_1 = TestHandShake::ecdh(&secret_1, &pub_2.0.serialize());
-            let ecdh_2 = TestHandShake::ecdh(&secret_2, &pub_1.0.serialize());
-
-            if ecdh_1 == ecdh_2 {
-                TestResult::passed()
-            } else {
-                TestResult::failed()
-            }
-        } else {
-            TestResult::discard()
-        }
+    #[test]
+    fn test_nx_25519_blake2s_cacophony_vector() {
+        check_nx_25519_vector::<Blake2s>(
+            "ea36347617d324907de1d80582ea1fcd4a535cabb321876a517a4ca498a083cd",
+            [
+                "ca35def5ae56cec33dc2036731ab14896bc4c75dbb07a61f879f8e3afa4c79444c756477696720766f6e204d69736573",
+                "95ebc60d2b1fa672c1f46a8aa265ef51bfe38e7ccb39ec5be34069f1448088431b7ab475ba0987fba04b749be49e6b43fe538cfca25a1c591a7ed09f19c9b9e7d042761a2fd2762cf2cb2062ce2c61253452b8383eb2ddc9ba2237b96d97b4e866ba73f55165a736ad03e68594ce25",
+                "5ab8adddb31ab4f1086c55c3f3ed053f4d78eca7aaf7ba09d486f8",
+                "f3bbada5c0a4cd615bed55ee18046ad55efc4f30d318c57b4941e1",
+                "c1372cf03d2727f6b74f656b587735109ebb6159434a40a65e2e6095c12db5f01c",
+                "de040777d38c7bf60c4b8c0ca730a9526ff067db990848ac33e9e9970b01efdf00bab518d0",
+            ],
+        );
+    }
+
+    #[test]
+    fn test_nx_25519_blake2b_cacophony_vector() {
+        check_nx_25519_vector::<Blake2b>(
+            "3ff872f53b259bc2261e0dd9acf12e7d2b2c22a32ebafc0474e26c47826d533fd0b744fc10bc9f5892d450059aa234c23f65818d647bcad3f8681a652157da34",
+            [
+                "ca35def5ae56cec33dc2036731ab14896bc4c75dbb07a61f879f8e3afa4c79444c756477696720766f6e204d69736573",
+                "95ebc60d2b1fa672c1f46a8aa265ef51bfe38e7ccb39ec5be34069f144808843fdaf85de47075ed5e21615917ab0102033d6d1386f48638b4a85564f1241fc9724442a45c3a4593c2807c7535264cb7c9e9cb8f278497efc16ced9b2105e10b89323f13d60f847508a32c040a993a5",
+                "bbedcc446e8aea8a083113d1b32a290ba453cbdb7f18b3cbdc9e84",
+                "a1a6f9f34b07e415516191df57e5dbc7c7e520e59077cf2b8bcd5f",
+                "b570f24ec64f2f1edc98a361c1e67e8df01e0c2267a59481ad41bb4ad0cbac11de",
+                "4492601a1fde255be23a84a895cf8581ede55d853e1e9e9a98930b8119b117dc6442d86252",
+            ],
+        );
     }
 }
@@ -0,0 +1,215 @@
+use blake2::{Blake2b512, Blake2s256, Digest};
+use secp256k1::hashes::{sha256, sha512, Hash, HashEngine};
+use zeroize::Zeroizing;
+
+/// Biggest HASHLEN of the supported hash functions, h and ck are stored in arrays of this size
+pub const MAX_HASH_LEN: usize = 64;
+/// Biggest block size of the supported hash functions
+pub const MAX_BLOCK_LEN: usize = 128;
+
+/// Hash function used by the symmetric state (`MixHash`, `MixKey`, `HKDF`). Outputs are returned
+/// in `[u8; MAX_HASH_LEN]` arrays where only the first `HASH_LEN` bytes are meaningful.
+pub trait NoiseHash {
+    type Engine: Default;
+
+    /// Name of the hash function in the protocol name
+    const NAME: &'static str;
+    const HASH_LEN: usize;
+    /// Block size used by HMAC
+    const BLOCK_LEN: usize;
+
+    fn input(engine: &mut Self::Engine, data: &[u8]);
+
+    fn finalize(engine: Self::Engine) -> [u8; MAX_HASH_LEN];
+
+    /// HASH of the concatenation of `parts`
+    fn hash(parts: &[&[u8]]) -> [u8; MAX_HASH_LEN] {
+        let mut engine = Self::Engine::default();
+        for part in parts {
+            Self::input(&mut engine, part);
+        }
+        Self::finalize(engine)
+    }
+
+    /// HMAC-HASH(key, data) from RFC 2104, `data` is the concatenation of `parts`. A `key` longer
+    /// than `BLOCK_LEN` is hashed first, in Noise it is always a `HASH_LEN` output.
+    fn hmac(key: &[u8], parts: &[&[u8]]) -> [u8; MAX_HASH_LEN] {
+        let hashed_key;
+        let key = if key.len() > Self::BLOCK_LEN {
+            hashed_key = Zeroizing::new(Self::hash(&[key]));
+            &hashed_key[..Self::HASH_LEN]
+        } else {
+            key
+        };
+        let mut ipad = Zeroizing::new([0x36; MAX_BLOCK_LEN]);
+        let mut opad = Zeroizing::new([0x5c; MAX_BLOCK_LEN]);
+        for (i, b) in key.iter().enumerate() {
+            ipad[i] ^= b;
+            opad[i] ^= b;
+        }
+
+        let mut inner = Self::Engine::default();
+        Self::input(&mut inner, &ipad[..Self::BLOCK_LEN]);
+        for part in parts {
+            Self::input(&mut inner, part);
+        }
+        let inner = Zeroizing::new(Self::finalize(inner));
+        Self::hash(&[&opad[..Self::BLOCK_LEN], &inner[..Self::HASH_LEN]])
+    }
+
+    /// HKDF(chaining_key, input_key_material, 2)
+    fn hkdf_2(
+        chaining_key: &[u8],
+        input_key_material: &[u8],
+    ) -> ([u8; MAX_HASH_LEN], [u8; MAX_HASH_LEN]) {
+        let temp_key = Zeroizing::new(Self::hmac(chaining_key, &[input_key_material]));
+        let temp_key = &temp_key[..Self::HASH_LEN];
+        let out_1 = Self::hmac(temp_key, &[&[0x1]]);
+        let out_2 = Self::hmac(temp_key, &[&out_1[..Self::HASH_LEN], &[0x2]]);
+        (out_1, out_2)
+    }
+}
+
+pub struct Sha256;
+
+impl NoiseHash for Sha256 {
+    type Engine = sha256::HashEngine;
+
+    const NAME: &'static str = "SHA256";
+    const HASH_LEN: usize = 32;
+    const BLOCK_LEN: usize = 64;
+
+    fn input(engine: &mut Self::Engine, data: &[u8]) {
+        engine.input(data);
+    }
+
+    fn finalize(engine: Self::Engine) -> [u8; MAX_HASH_LEN] {
+        let mut out = [0; MAX_HASH_LEN];
+        out[..32].copy_from_slice(&sha256::Hash::from_engine(engine).to_byte_array());
+        out
+    }
+}
+
+pub struct Sha512;
+
+impl NoiseHash for Sha512 {
+    type Engine = sha512::HashEngine;
+
+    const NAME: &'static str = "SHA512";
+    const HASH_LEN: usize = 64;
+    const BLOCK_LEN: usize = 128;
+
+    fn input(engine: &mut Self::Engine, data: &[u8]) {
+        engine.input(data);
+    }
+
+    fn finalize(engine: Self::Engine) -> [u8; MAX_HASH_LEN] {
+        sha512::Hash::from_engine(engine).to_byte_array()
+    }
+}
+
+pub struct Blake2s;
+
+impl NoiseHash for Blake2s {
+    type Engine = Blake2s256;
+
+    const NAME: &'static str = "BLAKE2s";
+    const HASH_LEN: usize = 32;
+    const BLOCK_LEN: usize = 64;
+
+    fn input(engine: &mut Self::Engine, data: &[u8]) {
+        engine.update(data);
+    }
+
+    fn finalize(engine: Self::Engine) -> [u8; MAX_HASH_LEN] {
+        let mut out = [0; MAX_HASH_LEN];
+        out[..32].copy_from_slice(&engine.finalize());
+        out
+    }
+}
+
+pub struct Blake2b;
+
+impl NoiseHash for Blake2b {
+    type Engine = Blake2b512;
+
+    const NAME: &'static str = "BLAKE2b";
+    const HASH_LEN: usize = 64;
+    const BLOCK_LEN: usize = 128;
+
+    fn input(engine: &mut Self::Engine, data: &[u8]) {
+        engine.update(data);
+    }
+
+    fn finalize(engine: Self::Engine) -> [u8; MAX_HASH_LEN] {
+        let mut out = [0; MAX_HASH_LEN];
+        out.copy_from_slice(&engine.finalize());
+        out
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn hmac_hex<H: NoiseHash>(key: &[u8], data: &[u8]) -> String {
+        hex::encode(&H::hmac(key, &[data])[..H::HASH_LEN])
+    }
+
+    /// RFC 4231 test case 2
+    #[test]
+    fn test_hmac_sha2_rfc4231() {
+        let key = b"Jefe";
+        let data = b"what do ya want for nothing?";
+        assert!(
+            hmac_hex::<Sha256>(key, data)
+                == "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+        assert!(
+            hmac_hex::<Sha512>(key, data)
+                == "164b7a7bfcf819e2e395fbe73b56e0a387bd64222e831fd610270cd7ea2505549758bf75c05a994a6d034f65f8f0e6fdcaeab1a34d4a6b4b636e070a38bce737"
+        );
+    }
+
+    /// RFC 4231 test case 6, the key is longer than the block and is hashed first
+    #[test]
+    fn test_hmac_long_key() {
+        let key = [0xaa; 131];
+        let data = b"Test Using Larger Than Block-Size Key - Hash Key First";
+        assert!(
+            hmac_hex::<Sha256>(&key, data)
+                == "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54"
+        );
+        assert!(
+            hmac_hex::<Sha512>(&key, data)
+                == "80b24263c7c1a3ebb71493c1dd7be8b49b46d1f41b4aeec1121b013783f8f352\
+                    6b56d037e05f2598bd0fd2215d6a1e5295e64f73f63f0aec8b915a985d786598"
+        );
+        assert!(
+            hmac_hex::<Blake2s>(&key, data)
+                == "d23d79394f53d536a096e6514447eeaabb05ded01be32c1937da6a8f7103bc4e"
+        );
+        assert!(
+            hmac_hex::<Blake2b>(&key, data)
+                == "a54b2943b2a20227d41ca46c0945af09bc1faefb2f49894c23aebc557fb79c48\
+                    89dca74408dc865086667aedee4a3185c53a49c80b814c4c5813ea0c8b38a8f8"
+        );
+    }
+
+    /// HMAC split in several parts is the HMAC of the concatenation
+    #[test]
+    fn test_hmac_parts() {
+        let key = [7; 64];
+        let whole = Blake2b::hmac(&key, &[b"ciao come va"]);
+        let parts = Blake2b::hmac(&key, &[b"ciao", b" come", b" va"]);
+        assert!(whole == parts);
+    }
+
+    #[test]
+    fn test_output_len() {
+        assert!(Sha256::hash(&[b"ciao"])[32..] == [0; 32]);
+        assert!(Blake2s::hash(&[b"ciao"])[32..] == [0; 32]);
+        assert!(Sha512::hash(&[b"ciao"])[32..] != [0; 32]);
+        assert!(Blake2b::hash(&[b"ciao"])[32..] != [0; 32]);
+    }
+}
@@ -1,40 +1,67 @@
-use std::ptr;
+use std::fmt;
+use std::marker::PhantomData;
 
-use crate::aed_cipher::AeadCipher;
-use crate::cipher_state::{Cipher, CipherState, GenericCipher};
-use crate::error::Error;
+use crate::aed_cipher::{DynCipher, TransportCipher};
+use crate::cipher_registry::{decode_cipher_list, encode_cipher_list, CipherCode, MAX_CIPHERS};
+use crate::cipher_state::{Cipher, CipherState};
+use crate::config::InitiatorConfig;
+use crate::dh::{Dh, Secp256k1, MAX_DH_LEN, MAX_PUB_KEY_LEN};
+use crate::error::{Error, ErrorBytes, ErrorKind, Phase, Role};
 use crate::handshake::HandshakeOp;
-use crate::{signature_message::SignatureNoiseMessage, NoiseCodec};
-use aes_gcm::KeyInit;
+use crate::hash::{NoiseHash, Sha256, MAX_HASH_LEN};
+use crate::known_servers::{KnownServer, KnownServers};
+use crate::protocol_name::ProtocolName;
+use crate::revocation::RevocationSet;
+use crate::secret::{CipherName, Hex, Secret};
+use crate::signature_message::{SignatureNoiseMessage, MAX_CERTIFICATE_LEN, MAX_EXTENSIONS_LEN};
+use crate::trust_store::{AuthorityTrustStore, TrustedAuthority};
+use crate::{NoiseCodec, ProtocolVersion};
 use chacha20poly1305::ChaCha20Poly1305;
-use secp256k1::KeyPair;
 use secp256k1::XOnlyPublicKey;
+use zeroize::Zeroizing;
 
-pub struct Initiator<C: AeadCipher> {
+pub struct Initiator<C: TransportCipher = DynCipher, D: Dh = Secp256k1, H: NoiseHash = Sha256> {
     handshake_cipher: Option<ChaCha20Poly1305>,
-    k: Option<[u8; 32]>,
+    k: Secret<Option<[u8; 32]>>,
     n: u64,
     // Chaining key
-    ck: [u8; 32],
+    ck: Secret<[u8; MAX_HASH_LEN]>,
     // Handshake hash
-    h: [u8; 32],
+    h: Secret<[u8; MAX_HASH_LEN]>,
+    dh: D,
     // ephemeral keypair
-    e: KeyPair,
-    // upstream pub key
-    pk: XOnlyPublicKey,
-    c1: Option<GenericCipher<C>>,
-    c2: Option<GenericCipher<C>>,
+    e: D::KeyPair,
+    // Authority keys that can sign the responder certificate
+    authorities: AuthorityTrustStore,
+    // Static keys and certificates that are not accepted even if correctly signed
+    revocations: RevocationSet,
+    // Canonical static keys that the responder must have, any key if empty
+    pinned_static_keys: Vec<[u8; 32]>,
+    // Keys recorded on the first connection and the endpoint of the responder
+    known_servers: Option<(KnownServers, String)>,
+    // Certificate of the responder and the authority that signed it, set once it is verified
+    certificate: Option<(SignatureNoiseMessage, Option<TrustedAuthority>)>,
+    // Next step of the handshake: 0, 2 or 4
+    step: u8,
+    // Keys of the transport ciphers, they are built when the cipher is negotiated
+    k1: Secret<Option<[u8; 32]>>,
+    k2: Secret<Option<[u8; 32]>>,
+    // Ciphers offered after the handshake
+    config: InitiatorConfig,
+    // Type of the negotiated transport cipher
+    cipher: PhantomData<C>,
+    hash: PhantomData<H>,
 }
 
 /// Make sure that Initiator is not sync so we do not need to worry about what other memory accessor see
 /// after that we zeroize k is send cause if we send it the original thread can not access
 /// anymore it
-impl<C: AeadCipher> !Sync for Initiator<C> {}
-impl<C: AeadCipher> !Copy for Initiator<C> {}
+impl<C: TransportCipher, D: Dh, H: NoiseHash> !Sync for Initiator<C, D, H> {}
+impl<C: TransportCipher, D: Dh, H: NoiseHash> !Copy for Initiator<C, D, H> {}
 
-impl<C: AeadCipher> CipherState<ChaCha20Poly1305> for Initiator<C> {
+impl<C: TransportCipher, D: Dh, H: NoiseHash> CipherState<ChaCha20Poly1305> for Initiator<C, D, H> {
     fn get_k(&mut self) -> &mut Option<[u8; 32]> {
-        &mut self.k
+        self.k.expose_mut()
     }
     fn get_n(&self) -> u64 {
         self.n
@@ -47,28 +74,30 @@ impl<C: AeadCipher> CipherState<ChaCha20Poly1305> for Initiator<C> {
     }
 
     fn set_k(&mut self, k: Option<[u8; 32]>) {
-        self.k = k;
+        *self.k.expose_mut() = k;
     }
 }
 
-impl<C: AeadCipher> HandshakeOp<ChaCha20Poly1305> for Initiator<C> {
+impl<C: TransportCipher, D: Dh, H: NoiseHash> HandshakeOp<ChaCha20Poly1305> for Initiator<C, D, H> {
+    type Hash = H;
+
     fn name(&self) -> String {
         "Initiator".to_string()
     }
-    fn get_h(&mut self) -> &mut [u8; 32] {
-        &mut self.h
+    fn get_h(&mut self) -> &mut [u8; MAX_HASH_LEN] {
+        self.h.expose_mut()
     }
 
-    fn get_ck(&mut self) -> &mut [u8; 32] {
-        &mut self.ck
+    fn get_ck(&mut self) -> &mut [u8; MAX_HASH_LEN] {
+        self.ck.expose_mut()
     }
 
-    fn set_h(&mut self, data: [u8; 32]) {
-        self.h = data;
+    fn set_h(&mut self, data: [u8; MAX_HASH_LEN]) {
+        *self.h.expose_mut() = data;
     }
 
-    fn set_ck(&mut self, data: [u8; 32]) {
-        self.ck = data;
+    fn set_ck(&mut self, data: [u8; MAX_HASH_LEN]) {
+        *self.ck.expose_mut() = data;
     }
 
     fn set_handshake_cipher(&mut self, cipher: ChaCha20Poly1305) {
@@ -76,23 +105,160 @@ impl<C: AeadCipher> HandshakeOp<ChaCha20Poly1305> for Initiator<C> {
     }
 }
 
-impl<C: AeadCipher> Initiator<C> {
+impl<C: TransportCipher> Initiator<C> {
     pub fn new(pk: XOnlyPublicKey) -> Box<Self> {
+        Self::new_with_version(pk, ProtocolVersion::default())
+    }
+
+    /// Create an initiator that speaks the given revision of the handshake
+    pub fn new_with_version(pk: XOnlyPublicKey, version: ProtocolVersion) -> Box<Self> {
+        Self::with_dh(pk, Secp256k1::new(version))
+    }
+
+    /// Create an initiator for a server without a published authority key, it is trusted on
+    /// first use. See [`Initiator::set_known_servers`].
+    ///
+    /// The signature of a certificate without the
+    /// [`crate::CertificateExtension::AUTHORITY_KEY`] extension is not verified, only the
+    /// recorded static key protects later handshakes.
+    pub fn new_tofu(known_servers: KnownServers, endpoint: impl Into<String>) -> Box<Self> {
+        let dh = Secp256k1::new(ProtocolVersion::default());
+        let protocol_name = ProtocolName::nx(dh.name(), Sha256::NAME);
+        let mut self_ = Self::init(AuthorityTrustStore::new(), dh, &protocol_name);
+        self_.set_known_servers(known_servers, endpoint);
+        self_
+    }
+}
+
+impl<C: TransportCipher, D: Dh, H: NoiseHash> Initiator<C, D, H> {
+    /// Create an initiator that uses `dh` for the handshake, `pk` is the authority public key
+    /// that signs the responder certificate. See [`Initiator::set_trust_store`] to trust more
+    /// keys.
+    pub fn with_dh(pk: XOnlyPublicKey, dh: D) -> Box<Self> {
+        let protocol_name = ProtocolName::nx(dh.name(), H::NAME);
+        Self::init(pk.into(), dh, &protocol_name)
+    }
+
+    /// Same as [`Initiator::with_dh`] but check that `protocol_name` names the NX handshake with `dh`,
+    /// ChaChaPoly and `H`, then initialize the handshake from it.
+    pub fn with_protocol_name(
+        pk: XOnlyPublicKey,
+        dh: D,
+        protocol_name: &ProtocolName,
+    ) -> Result<Box<Self>, Error> {
+        protocol_name.ensure_nx(dh.name(), H::NAME)?;
+        Ok(Self::init(pk.into(), dh, protocol_name))
+    }
+
+    fn init(authorities: AuthorityTrustStore, dh: D, protocol_name: &ProtocolName) -> Box<Self> {
         let mut self_ = Self {
             handshake_cipher: None,
-            k: None,
+            k: Secret::new(None),
             n: 0,
-            ck: [0; 32],
-            h: [0; 32],
-            e: Self::generate_key(),
-            pk,
-            c1: None,
-            c2: None,
+            ck: Secret::new([0; MAX_HASH_LEN]),
+            h: Secret::new([0; MAX_HASH_LEN]),
+            e: dh.generate_keypair(),
+            dh,
+            authorities,
+            revocations: RevocationSet::new(),
+            pinned_static_keys: vec![],
+            known_servers: None,
+            certificate: None,
+            step: 0,
+            k1: Secret::new(None),
+            k2: Secret::new(None),
+            config: InitiatorConfig::default(),
+            cipher: PhantomData,
+            hash: PhantomData,
         };
-        self_.initialize_self();
+        self_.initialize_self(protocol_name);
         Box::new(self_)
     }
 
+    /// Ciphers other than ChaChaPoly offered to the responder and the minimum accepted one. The
+    /// default config offers AES-256-GCM and accepts anything, see [`InitiatorConfig::auto`] to
+    /// offer it only on CPUs with AES instructions.
+    pub fn set_config(&mut self, config: InitiatorConfig) {
+        self.config = config;
+    }
+
+    /// Authority keys that can sign the responder certificate, they replace the key given to
+    /// the constructor
+    pub fn set_trust_store(&mut self, authorities: AuthorityTrustStore) {
+        self.authorities = authorities;
+    }
+
+    /// Reject the responder if its static key or its certificate is revoked, with
+    /// [`crate::CertificateError::Revoked`]
+    pub fn set_revocations(&mut self, revocations: RevocationSet) {
+        self.revocations = revocations;
+    }
+
+    /// Accept only responders with one of `keys` as static public key. The key is checked
+    /// besides the certificate, so a leaked authority key is not enough to impersonate the
+    /// responder. Each key is either in the form of [`Dh::canonical_pub_key`] or encoded as
+    /// sent in the handshake, fail if one of them is not a valid public key.
+    pub fn set_pinned_static_keys(&mut self, keys: &[impl AsRef<[u8]>]) -> Result<(), Error> {
+        self.pinned_static_keys = keys
+            .iter()
+            .map(|key| self.dh.canonical_pub_key(key.as_ref()))
+            .collect::<Result<_, _>>()?;
+        Ok(())
+    }
+
+    /// Trust `endpoint` on first use: its static key is recorded in `known_servers` on the first
+    /// handshake and later handshakes fail with [`ErrorKind::KnownServerChanged`] if it is
+    /// different.
+    ///
+    /// With an empty trust store the certificate is verified with the authority key named by
+    /// its [`crate::CertificateExtension::AUTHORITY_KEY`] extension, that is recorded too. On
+    /// the first handshake that key is only asserted by the certificate itself, later handshakes
+    /// must present a certificate signed by the same key. Certificates without the extension
+    /// are only checked to be in their validity period, the static key is recorded anyway.
+    ///
+    /// Their signature is not verified at all: anyone who can answer the first handshake is
+    /// trusted, as with SSH host keys. Use a trust store when the server publishes an authority
+    /// key.
+    pub fn set_known_servers(&mut self, known_servers: KnownServers, endpoint: impl Into<String>) {
+        self.known_servers = Some((known_servers, endpoint.into()));
+    }
+
+    /// Certificate of the responder, available after [`Initiator::step_2`]. The caller can
+    /// check its extensions, e.g. the hostname binding.
+    pub fn certificate(&self) -> Option<&SignatureNoiseMessage> {
+        self.certificate
+            .as_ref()
+            .map(|(certificate, _)| certificate)
+    }
+
+    /// Authority that signed the certificate of the responder, available after
+    /// [`Initiator::step_2`]
+    pub fn authority(&self) -> Option<&TrustedAuthority> {
+        self.certificate.as_ref()?.1.as_ref()
+    }
+
+    /// Ciphers of the config that can be negotiated as `C`, in preference order
+    fn offered_ciphers(&self) -> ([CipherCode; MAX_CIPHERS], usize) {
+        let mut codes = [[0; 4]; MAX_CIPHERS];
+        let mut len = 0;
+        for code in self.config.ciphers().codes() {
+            if C::supports(Some(code)) {
+                codes[len] = code;
+                len += 1;
+            }
+        }
+        (codes, len)
+    }
+
+    /// `MixKey(DH(e.private_key, remote))`
+    fn mix_key_with_dh(&mut self, remote: &[u8]) -> Result<(), Error> {
+        let mut dh = Zeroizing::new([0; MAX_DH_LEN]);
+        self.dh.dh(&self.e, remote, true, &mut dh)?;
+        let len = self.dh.dh_len();
+        self.mix_key(&dh[..len]);
+        Ok(())
+    }
+
     /// #### 4.5.1.1 Initiator
     ///
     /// Initiator generates ephemeral keypair and sends the public key to the responder:
@@ -109,17 +275,32 @@ impl<C: AeadCipher> Initiator<C> {
     /// | ---------- | -------------------------------- |
     /// | PUBKEY     | Initiator's ephemeral public key |
     ///
-    /// Message length: 32 bytes
-    pub fn step_0(&mut self) -> Result<[u8; 32], aes_gcm::Error> {
-        let serialized = self.e.public_key().x_only_public_key().0.serialize();
-        self.mix_hash(&serialized);
-        self.encrypt_and_hash(&mut vec![])?;
-
-        let mut message = [0u8; 32];
-        message[..32].copy_from_slice(&serialized[..32]);
+    /// Message length: 32 bytes, 64 bytes with [`ProtocolVersion::EllSwift`]
+    pub fn step_0(&mut self) -> Result<Vec<u8>, Error> {
+        let mut message = vec![0u8; self.dh.first_message_len()];
+        self.step_0_slice(&mut message)?;
         Ok(message)
     }
 
+    /// Same as [`Initiator::step_0`] but write the message in `out` without allocating. Return the
+    /// number of bytes written.
+    pub fn step_0_slice(&mut self, out: &mut [u8]) -> Result<usize, Error> {
+        self.write_ephemeral_key(out)
+            .map_err(|e| e.at(Role::Initiator, Phase::EphemeralKey))
+    }
+
+    fn write_ephemeral_key(&mut self, out: &mut [u8]) -> Result<usize, Error> {
+        let len = self.dh.first_message_len();
+        if out.len() < len {
+            return Err(ErrorKind::OutputBufferTooSmall(len).into());
+        }
+        out[..len].copy_from_slice(self.dh.pub_key(&self.e));
+        self.mix_hash(&out[..len]);
+        self.encrypt_and_hash(&mut [], 0)?;
+        self.step = 2;
+        Ok(len)
+    }
+
     /// #### 4.5.2.2 Initiator
     ///
     /// 1. receives NX-handshake part 2 message
@@ -128,7 +309,7 @@ impl<C: AeadCipher> Initiator<C> {
     /// 4. calls `MixKey(ECDH(e.private_key, re.public_key))`
     /// 5. decrypts next 48 bytes with `DecryptAndHash()` and stores the results as `rs.public_key` which is **server's static public key** (note that 32 bytes is the public key and 16 bytes is MAC)
     /// 6. calls `MixKey(ECDH(e.private_key, rs.public_key)`
-    /// 7. decrypts next 90 bytes with `DecryptAndHash()` and deserialize plaintext into `SIGNATURE_NOISE_MESSAGE` (74 bytes data + 16 bytes MAC)
+    /// 7. decrypts the remaining bytes with `DecryptAndHash()` and deserialize plaintext into `SIGNATURE_NOISE_MESSAGE` (74 bytes data, the extensions of a version 1 certificate and 16 bytes MAC), then verifies that it is signed by the authority for `rs.public_key`
     /// 8. return pair of CipherState objects, the first for encrypting transport messages from initiator to responder, and the second for messages in the other direction:
     ///    1. sets `temp_k1, temp_k2 = HKDF(ck, zerolen, 2)`
     ///    2. creates two new CipherState objects `c1` and `c2`
@@ -152,45 +333,122 @@ impl<C: AeadCipher> Initiator<C> {
     /// | cipher code | Cipher description |
     /// | ----------- | ------------------ |
     /// | 0x47534541 (b"AESG") | AES-256 with with GCM from [7] |
+    /// | 0x56495341 (b"ASIV") | AES-256 with GCM-SIV (RFC 8452) |
+    /// | 0x41484358 (b"XCHA") | XChaCha20-Poly1305 with 24 bytes nonces |
     ///
-    pub fn step_2(&mut self, message: [u8; 170]) -> Result<[u8; 5], Error> {
+    /// The offered ciphers are the ones of [`Initiator::set_config`] in preference order, without
+    /// the ones that `C` does not support.
+    pub fn step_2(&mut self, message: Vec<u8>) -> Result<Vec<u8>, Error> {
+        let (_, n) = self.offered_ciphers();
+        let mut supported_ciphers = vec![0; 1 + 4 * n];
+        self.step_2_slice(&message, &mut supported_ciphers)?;
+        Ok(supported_ciphers)
+    }
+
+    /// Same as [`Initiator::step_2`] but read the message from a slice and write the cipher list
+    /// in `out` without allocating. Return the number of bytes written.
+    pub fn step_2_slice(&mut self, message: &[u8], out: &mut [u8]) -> Result<usize, Error> {
+        let mut phase = Phase::EphemeralKey;
+        self.read_responder_message(message, out, &mut phase)
+            .map_err(|e| e.at(Role::Initiator, phase))
+    }
+
+    /// Body of [`Initiator::step_2_slice`], `phase` is updated as the message is read
+    fn read_responder_message(
+        &mut self,
+        message: &[u8],
+        out: &mut [u8],
+        phase: &mut Phase,
+    ) -> Result<usize, Error> {
+        let pk_len = self.dh.pub_key_len();
+        let min_len = self.dh.second_message_len();
+        if message.len() < min_len || message.len() > min_len + MAX_EXTENSIONS_LEN {
+            return Err(ErrorKind::InvalidMessageLength(message.len()).into());
+        }
+        let (offered, n) = self.offered_ciphers();
+        if out.len() < 1 + 4 * n {
+            return Err(ErrorKind::OutputBufferTooSmall(1 + 4 * n).into());
+        }
         // 2. interprets first 32 bytes as `re.public_key`
         // 3. calls `MixHash(re.public_key)`
-        let remote_pub_key = &message[0..32];
+        let remote_pub_key = &message[0..pk_len];
         self.mix_hash(remote_pub_key);
 
         // 4. calls `MixKey(ECDH(e.private_key, re.public_key))`
-        let e_private_key = self.e.secret_bytes();
-        self.mix_key(&Self::ecdh(&e_private_key[..], remote_pub_key)[..]);
+        self.mix_key_with_dh(remote_pub_key)?;
 
+        *phase = Phase::StaticKey;
         // 5. decrypts next 48 bytes with `DecryptAndHash()` and stores the results as `rs.public_key` which is **server's static public key** (note that 32 bytes is the public key and 16 bytes is MAC)
-        let mut to_decrypt = message[32..80].to_vec();
-
-        self.decrypt_and_hash(&mut to_decrypt)?;
-        let rs_pub_key = to_decrypt;
+        let mut to_decrypt = [0; MAX_PUB_KEY_LEN + 16];
+        let to_decrypt = &mut to_decrypt[..pk_len + 16];
+        to_decrypt.copy_from_slice(&message[pk_len..(2 * pk_len + 16)]);
+        let len = self.decrypt_and_hash(to_decrypt)?;
+        let rs_pub_key = &to_decrypt[..len];
+        let rs_key = self.dh.canonical_pub_key(rs_pub_key)?;
+        if !self.pinned_static_keys.is_empty() && !self.pinned_static_keys.contains(&rs_key) {
+            return Err(ErrorKind::StaticKeyNotPinned(rs_key).into());
+        }
 
         // 6. calls `MixKey(ECDH(e.private_key, rs.public_key)`
-        self.mix_key(&Self::ecdh(&e_private_key[..], &rs_pub_key[..])[..]);
-
-        let mut to_decrypt = message[80..170].to_vec();
-        self.decrypt_and_hash(&mut to_decrypt)?;
-        let plaintext: [u8; 74] = to_decrypt.try_into().unwrap();
-        let signature_message: SignatureNoiseMessage = plaintext.into();
-        if signature_message.verify(&self.pk) {
-            let (temp_k1, temp_k2) = Self::hkdf_2(self.get_ck(), &[]);
-            let c1 = ChaCha20Poly1305::new(&temp_k1.into());
-            let c2 = ChaCha20Poly1305::new(&temp_k2.into());
-            let c1: Cipher<ChaCha20Poly1305> = Cipher::from_key_and_cipher(temp_k1, c1);
-            let c2: Cipher<ChaCha20Poly1305> = Cipher::from_key_and_cipher(temp_k2, c2);
-            self.c1 = Some(GenericCipher::ChaCha20Poly1305(c1));
-            self.c2 = Some(GenericCipher::ChaCha20Poly1305(c2));
-            // len = 1
-            // 47,53,45,41 = AESG
-            let supported_ciphers = [1, 0x47, 0x53, 0x45, 0x41];
-            Ok(supported_ciphers)
-        } else {
-            Err(Error::InvalidCertificate(plaintext))
+        self.mix_key_with_dh(rs_pub_key)?;
+
+        *phase = Phase::Certificate;
+        let certificate = &message[(2 * pk_len + 16)..];
+        let mut to_decrypt = [0; MAX_CERTIFICATE_LEN + 16];
+        let to_decrypt = &mut to_decrypt[..certificate.len()];
+        to_decrypt.copy_from_slice(certificate);
+        let len = self.decrypt_and_hash(to_decrypt)?;
+        let signature_message = SignatureNoiseMessage::from_bytes(&to_decrypt[..len])?;
+        // The certificate must be issued for the static key of this responder
+        let authority = self.verify_certificate(&signature_message, &rs_key)?;
+        self.revocations.check(&rs_key, &signature_message)?;
+        if let Some((known_servers, endpoint)) = &self.known_servers {
+            // The authority is pinned only when it is not taken from the trust store, so that
+            // the trust store can roll its keys
+            let tofu = self.is_tofu();
+            known_servers.check_or_record(&KnownServer {
+                endpoint: endpoint.clone(),
+                static_key: rs_key,
+                authority: authority.as_ref().filter(|_| tofu).map(|a| *a.key()),
+            })?;
         }
+        self.certificate = Some((signature_message, authority));
+
+        *phase = Phase::CipherNegotiation;
+        let (temp_k1, temp_k2) = self.split_keys();
+        *self.k1.expose_mut() = Some(temp_k1);
+        *self.k2.expose_mut() = Some(temp_k2);
+        self.step = 4;
+        encode_cipher_list(offered[..n].iter().copied(), out)
+    }
+
+    /// Verify the certificate with the trust store. When the responder is trusted on first use
+    /// and the trust store is empty, with the authority key named by the certificate, if any,
+    /// otherwise only its validity period is checked.
+    fn verify_certificate(
+        &self,
+        certificate: &SignatureNoiseMessage,
+        static_key: &[u8],
+    ) -> Result<Option<TrustedAuthority>, Error> {
+        if !self.is_tofu() {
+            return Ok(Some(
+                self.authorities.verify(certificate, static_key)?.clone(),
+            ));
+        }
+        match certificate.authority_key()? {
+            Some(key) => {
+                let authorities = AuthorityTrustStore::from(key);
+                Ok(Some(authorities.verify(certificate, static_key)?.clone()))
+            }
+            None => {
+                certificate.check_validity_at(SignatureNoiseMessage::now())?;
+                Ok(None)
+            }
+        }
+    }
+
+    fn is_tofu(&self) -> bool {
+        self.known_servers.is_some() && self.authorities.authorities().is_empty()
     }
 
     /// #### 4.5.5.1 Upgrade to a new AEAD-cipher
@@ -200,74 +458,70 @@ impl<C: AeadCipher> Initiator<C> {
     /// 1. Both initiator and responder create a new pair of CipherState objects with the negotiated cipher for encrypting transport messages from initiator to responder and in the other direction respectively
     /// 2. New keys `key_new` are derived from the original CipherState keys `key_orig` by taking the first 32 bytes from `ENCRYPT(key_orig, maxnonce, zero_len, zeros)` using the negotiated cipher function where `maxnonce` is 2<sup>64</sup> - 1, `zerolen` is a zero-length byte sequence, and `zeros` is a sequence of 32 bytes filled with zeros. (see `Rekey(k)` function<sup>[8](#reference-8)</sup>)
     /// 3. New CipherState objects are reinitialized: `InitializeKey(key_new)`.
-    pub fn step_4(mut self, cipher_chosed: Vec<u8>) -> Result<NoiseCodec<C>, Error> {
-        match cipher_chosed.len() {
-            0 => Err(Error::InvalidCipherList(cipher_chosed)),
-            1 => {
-                if cipher_chosed[0] == 0 {
-                    let mut encryptor = None;
-                    std::mem::swap(&mut encryptor, &mut self.c1);
-                    let mut decryptor = None;
-                    std::mem::swap(&mut decryptor, &mut self.c2);
-                    let mut encryptor = encryptor.unwrap();
-                    let mut decryptor = decryptor.unwrap();
-                    encryptor.erase_k();
-                    decryptor.erase_k();
-                    // Responder want to use ChaCha
-                    let codec = crate::NoiseCodec {
-                        encryptor,
-                        decryptor,
-                    };
-                    Ok(codec)
-                } else {
-                    Err(Error::InvalidCipherList(cipher_chosed))
-                }
-            }
-            5 => {
-                // Responder want to use AesGcm
-                if cipher_chosed == [1, 0x47, 0x53, 0x45, 0x41] {
-                    let mut encryptor = None;
-                    std::mem::swap(&mut encryptor, &mut self.c1);
-                    let mut decryptor = None;
-                    std::mem::swap(&mut decryptor, &mut self.c2);
-                    let encryptor = encryptor.unwrap().into_aesg();
-                    let decryptor = decryptor.unwrap().into_aesg();
-                    let codec = crate::NoiseCodec {
-                        encryptor,
-                        decryptor,
-                    };
-                    Ok(codec)
-                } else {
-                    Err(Error::InvalidCipherList(cipher_chosed))
-                }
-            }
-            _ => Err(Error::InvalidCipherList(cipher_chosed)),
-        }
+    pub fn step_4(self, cipher_chosed: Vec<u8>) -> Result<NoiseCodec<C>, Error> {
+        self.step_4_slice(&cipher_chosed)
     }
 
-    fn erase(&mut self) {
-        if let Some(k) = self.k.as_mut() {
-            for b in k {
-                unsafe { ptr::write_volatile(b, 0) };
-            }
-        }
-        for mut b in self.ck {
-            unsafe { ptr::write_volatile(&mut b, 0) };
-        }
-        for mut b in self.h {
-            unsafe { ptr::write_volatile(&mut b, 0) };
-        }
-        if let Some(c1) = self.c1.as_mut() {
-            c1.erase_k()
+    /// Same as [`Initiator::step_4`] but read `CIPHER_CHOICE` from a slice
+    pub fn step_4_slice(self, cipher_chosed: &[u8]) -> Result<NoiseCodec<C>, Error> {
+        self.read_cipher_choice(cipher_chosed)
+            .map_err(|e| e.at(Role::Initiator, Phase::CipherNegotiation))
+    }
+
+    fn read_cipher_choice(mut self, cipher_chosed: &[u8]) -> Result<NoiseCodec<C>, Error> {
+        let mut chosen = decode_cipher_list(cipher_chosed)?;
+        let code = match (chosen.next(), chosen.next()) {
+            (code, None) => code,
+            _ => return Err(ErrorKind::InvalidCipherList(ErrorBytes::new(cipher_chosed)).into()),
+        };
+        if self.k1.expose().is_none() || self.k2.expose().is_none() {
+            return Err(ErrorKind::HandshakeNotFinalized.into());
         }
-        if let Some(c2) = self.c2.as_mut() {
-            c2.erase_k()
+        // Responder must choose ChaCha or one of the ciphers that we offered
+        let offered = code.is_none_or(|code| self.config.ciphers().contains(code));
+        let invalid = || {
+            Error::from(ErrorKind::InvalidCipherChosed(ErrorBytes::new(
+                cipher_chosed,
+            )))
+        };
+        if !offered || !C::supports(code) {
+            return Err(invalid());
         }
-        self.e.non_secure_erase();
+        self.config.check_choice(code)?;
+        let ciphers = self.config.ciphers();
+        let encryptor =
+            Cipher::negotiated(ciphers, code, self.k1.expose_mut()).ok_or_else(invalid)?;
+        let decryptor =
+            Cipher::negotiated(ciphers, code, self.k2.expose_mut()).ok_or_else(invalid)?;
+        Ok(NoiseCodec {
+            encryptor,
+            decryptor,
+        })
     }
 }
-impl<C: AeadCipher> Drop for Initiator<C> {
-    fn drop(&mut self) {
-        self.erase();
+
+impl<C: TransportCipher, D: Dh, H: NoiseHash> fmt::Debug for Initiator<C, D, H> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (offered, n) = self.offered_ciphers();
+        let offered: Vec<_> = offered[..n].iter().map(|c| CipherName(Some(*c))).collect();
+        let pinned_static_keys: Vec<_> = self.pinned_static_keys.iter().map(|k| Hex(k)).collect();
+        f.debug_struct("Initiator")
+            .field("step", &self.step)
+            .field("dh", &self.dh.name())
+            .field("hash", &H::NAME)
+            .field("n", &self.n)
+            .field("k", &self.k)
+            .field("ck", &self.ck)
+            .field("h", &self.h)
+            .field("e", &Hex(self.dh.pub_key(&self.e)))
+            .field("authorities", &self.authorities)
+            .field("revocations", &self.revocations)
+            .field("pinned_static_keys", &pinned_static_keys)
+            .field("known_servers", &self.known_servers)
+            .field("certificate", &self.certificate())
+            .field("offered_ciphers", &offered)
+            .field("k1", &self.k1)
+            .field("k2", &self.k2)
+            .finish()
     }
 }
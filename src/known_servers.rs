@@ -0,0 +1,158 @@
+use crate::error::{Error, ErrorKind};
+use secp256k1::XOnlyPublicKey;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+/// Keys of a server recorded on the first connection
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KnownServer {
+    pub endpoint: String,
+    /// Static public key of the responder as returned by [`crate::Dh::canonical_pub_key`]
+    pub static_key: [u8; 32],
+    /// Authority that signed the certificate, if the certificate names it. It is asserted by
+    /// the certificate itself on the first connection, nothing vouches for it.
+    pub authority: Option<XOnlyPublicKey>,
+}
+
+/// File of the servers trusted on first use, in the style of SSH `known_hosts`.
+///
+/// Each line is `<endpoint> <static key hex> <authority key hex or ->`, empty lines and lines
+/// starting with `#` are ignored. The endpoint is the name used by the caller to connect, e.g.
+/// `pool.example.com:34254`, it can not contain spaces.
+#[derive(Debug, Clone)]
+pub struct KnownServers {
+    path: PathBuf,
+}
+
+impl KnownServers {
+    /// The file is created on the first record
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    /// Recorded keys of `endpoint`
+    pub fn get(&self, endpoint: &str) -> Result<Option<KnownServer>, Error> {
+        let content = match fs::read_to_string(&self.path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        for line in content.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let server =
+                Self::parse(line).ok_or_else(|| ErrorKind::InvalidKnownServers(line.into()))?;
+            if server.endpoint == endpoint {
+                return Ok(Some(server));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Record the keys of a server that is not known yet
+    pub fn record(&self, server: &KnownServer) -> Result<(), Error> {
+        if server.endpoint.is_empty() || server.endpoint.contains(char::is_whitespace) {
+            return Err(ErrorKind::InvalidKnownServers(server.endpoint.clone()).into());
+        }
+        let authority = match server.authority {
+            Some(authority) => authority.to_string(),
+            None => "-".to_string(),
+        };
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        let static_key = hex::encode(server.static_key);
+        writeln!(file, "{} {} {}", server.endpoint, static_key, authority)?;
+        Ok(())
+    }
+
+    /// Record `server` on the first connection, then fail with
+    /// [`ErrorKind::KnownServerChanged`] if its keys are not the recorded ones
+    pub fn check_or_record(&self, server: &KnownServer) -> Result<(), Error> {
+        match self.get(&server.endpoint)? {
+            Some(known) if &known == server => Ok(()),
+            Some(_) => Err(ErrorKind::KnownServerChanged(server.endpoint.clone()).into()),
+            None => self.record(server),
+        }
+    }
+
+    fn parse(line: &str) -> Option<KnownServer> {
+        let mut fields = line.split_whitespace();
+        let endpoint = fields.next()?.to_string();
+        let static_key = hex::decode(fields.next()?).ok()?.try_into().ok()?;
+        let authority = match fields.next()? {
+            "-" => None,
+            authority => Some(authority.parse().ok()?),
+        };
+        match fields.next() {
+            Some(_) => None,
+            None => Some(KnownServer {
+                endpoint,
+                static_key,
+                authority,
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Secp256k1;
+
+    #[test]
+    fn test_check_or_record() {
+        let path = std::env::temp_dir().join(format!("known-servers-{}", rand::random::<u64>()));
+        let known_servers = KnownServers::new(&path);
+        let authority = Secp256k1::generate_key().x_only_public_key().0;
+        let server = KnownServer {
+            endpoint: "pool.example.com:34254".into(),
+            static_key: [2; 32],
+            authority: Some(authority),
+        };
+        assert!(known_servers.get(&server.endpoint).unwrap().is_none());
+        known_servers.check_or_record(&server).unwrap();
+        known_servers.check_or_record(&server).unwrap();
+        let other = KnownServer {
+            endpoint: "other.example.com:34254".into(),
+            static_key: [3; 32],
+            authority: None,
+        };
+        known_servers.check_or_record(&other).unwrap();
+        assert!(known_servers.get(&server.endpoint).unwrap() == Some(server.clone()));
+        assert!(known_servers.get(&other.endpoint).unwrap() == Some(other));
+
+        let changed = KnownServer {
+            static_key: [4; 32],
+            ..server.clone()
+        };
+        assert!(matches!(
+            known_servers.check_or_record(&changed).map_err(Error::into_kind),
+            Err(ErrorKind::KnownServerChanged(endpoint)) if endpoint == server.endpoint
+        ));
+        let changed = KnownServer {
+            authority: None,
+            ..server.clone()
+        };
+        assert!(known_servers.check_or_record(&changed).is_err());
+        let invalid = KnownServer {
+            endpoint: "pool example".into(),
+            ..server
+        };
+        assert!(matches!(
+            known_servers.record(&invalid).map_err(Error::into_kind),
+            Err(ErrorKind::InvalidKnownServers(_))
+        ));
+
+        fs::write(&path, "# comment\n\npool.example.com 0202 -\n").unwrap();
+        assert!(matches!(
+            known_servers.get("pool.example.com").map_err(Error::into_kind),
+            Err(ErrorKind::InvalidKnownServers(line)) if line == "pool.example.com 0202 -"
+        ));
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -2,59 +2,206 @@
 
 #![feature(negative_impls)]
 
-use aed_cipher::AeadCipher;
 use aes_gcm::aead::Buffer;
-use cipher_state::GenericCipher;
+use cipher_state::{Cipher, CipherState};
+use std::fmt;
+use std::io::IoSlice;
 mod aed_cipher;
+mod cipher_registry;
 mod cipher_state;
+mod config;
+mod dh;
 mod error;
 mod handshake;
+mod hash;
 mod initiator;
+mod known_servers;
+mod protocol_name;
 mod responder;
+mod revocation;
+mod secret;
 mod signature_message;
+mod split;
 #[cfg(test)]
 mod test;
+mod trust_store;
 
 const PARITY: secp256k1::Parity = secp256k1::Parity::Even;
 
 /// protocolName is official noise protocol name such as Noise_NX_secp256k1_ChaChaPoly_SHA256 encoded as an ASCII string
 pub const PROTOCOL_NAME_CHACHA: &str = "Noise_NX_secp256k1_ChaChaPoly_SHA256";
-/// protocolName is official noise protocol name such as Noise_NX_secp256k1_AES-GCM_SHA256 encoded as an ASCII string
-pub const PROTOCOL_NAME_AES: &str = "Noise_NX_secp256k1_AES-GCM_SHA256";
+/// protocolName is official noise protocol name such as Noise_NX_secp256k1_AESGCM_SHA256 encoded as an ASCII string
+pub const PROTOCOL_NAME_AES: &str = "Noise_NX_secp256k1_AESGCM_SHA256";
+/// protocolName used when the ephemeral and static keys are ElligatorSwift encoded
+pub const PROTOCOL_NAME_ELLSWIFT_CHACHA: &str = "Noise_NX_Secp256k1+EllSwift_ChaChaPoly_SHA256";
 /// An encrypted message is tag_size bytes bigger than the clear message
 pub const CHACHA_POLY_TAG_SIZE: usize = 16;
 /// An encrypted message is tag_size bytes bigger than the clear message
 pub const AES_GMC_TAG_SIZE: usize = 16;
 
-/// If protocolName is less than or equal to 32 bytes in length, use protocolName with zero bytes
-/// appended to make 32 bytes. Otherwise, apply HASH to it. For name =
-/// "Noise_NX_secp256k1_ChaChaPoly_SHA256", we need the hash.
-pub const HASHED_PROTOCOL_NAME_CHACHA: [u8; 32] = [
-    168, 246, 65, 106, 218, 197, 235, 205, 62, 183, 118, 131, 234, 247, 6, 174, 180, 164, 162, 125,
-    30, 121, 156, 182, 95, 117, 218, 138, 122, 135, 4, 65,
-];
+/// Shared secret returned by the secp256k1 ECDH on x-only keys. It is part of the protocol
+/// definition: both peers must use the same one or the handshake fails at the first MAC check,
+/// the protocol name does not tell them apart.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EcdhOutput {
+    /// 32 bytes x coordinate of the shared point, as specified by Sv2
+    XCoordinate,
+    /// 64 bytes shared point (x || y), the default to stay compatible with previous versions of
+    /// this crate
+    #[default]
+    SharedPoint,
+}
+
+impl EcdhOutput {
+    /// Length of the shared secret fed into `MixKey`
+    pub const fn secret_len(&self) -> usize {
+        match self {
+            EcdhOutput::XCoordinate => 32,
+            EcdhOutput::SharedPoint => 64,
+        }
+    }
+
+    /// Select the shared secret from a serialized shared point (x || y)
+    pub(crate) fn shared_secret<'a>(&self, point: &'a [u8; 64]) -> &'a [u8] {
+        &point[..self.secret_len()]
+    }
+}
+
+/// Revision of the Sv2 security spec used to encode the handshake keys
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProtocolVersion {
+    /// 32 bytes x-only public keys with even parity and the given ECDH output
+    XOnly(EcdhOutput),
+    /// 64 bytes ElligatorSwift encoded public keys and x-only ECDH as defined in BIP324
+    EllSwift,
+}
 
-/// If protocolName is less than or equal to 32 bytes in length, use protocolName with zero bytes
-/// appended to make 32 bytes. Otherwise, apply HASH to it. For name =
-/// "Noise_NX_secp256k1_AES", we need the hash.
-pub const HASHED_PROTOCOL_NAME_AES: [u8; 32] = [
-    98, 20, 128, 113, 111, 137, 141, 202, 194, 70, 69, 231, 226, 122, 220, 145, 249, 218, 130, 51,
-    53, 203, 242, 0, 71, 117, 5, 73, 173, 157, 32, 55,
-];
+impl Default for ProtocolVersion {
+    fn default() -> Self {
+        ProtocolVersion::XOnly(EcdhOutput::default())
+    }
+}
 
-pub struct NoiseCodec<C: AeadCipher> {
-    encryptor: GenericCipher<C>,
-    decryptor: GenericCipher<C>,
+impl ProtocolVersion {
+    /// Length of the ECDH shared secret
+    pub const fn dh_len(&self) -> usize {
+        match self {
+            ProtocolVersion::XOnly(output) => output.secret_len(),
+            ProtocolVersion::EllSwift => 32,
+        }
+    }
+
+    /// Length of a public key as sent on the wire
+    pub const fn pub_key_len(&self) -> usize {
+        match self {
+            ProtocolVersion::XOnly(_) => 32,
+            ProtocolVersion::EllSwift => 64,
+        }
+    }
+}
+
+/// Encrypted transport session returned by the handshake. `C` is the type of the negotiated
+/// cipher, with [`DynCipher`] it can be any cipher of the [`CipherRegistry`].
+pub struct NoiseCodec<C: AeadCipher = DynCipher> {
+    encryptor: Cipher<C>,
+    decryptor: Cipher<C>,
 }
 
 impl<C: AeadCipher> NoiseCodec<C> {
     pub fn encrypt<T: Buffer>(&mut self, msg: &mut T) -> Result<(), aes_gcm::Error> {
-        self.encryptor.encrypt(msg)
+        self.encryptor.encrypt_with_ad(&[], msg)
     }
     pub fn decrypt<T: Buffer>(&mut self, msg: &mut T) -> Result<(), aes_gcm::Error> {
-        self.decryptor.decrypt(msg)
+        self.decryptor.decrypt_with_ad(&[], msg)
+    }
+    /// Encrypt the first `len` bytes of `buf` in place and write the MAC right after them, `buf`
+    /// must be at least `len + 16` bytes. Return the length of the encrypted message.
+    pub fn encrypt_slice(&mut self, buf: &mut [u8], len: usize) -> Result<usize, aes_gcm::Error> {
+        self.encryptor.encrypt_with_ad_slice(&[], buf, len)
+    }
+    /// Decrypt `buf` (ciphertext followed by the MAC) in place. Return the length of the
+    /// plaintext left at the start of `buf`.
+    pub fn decrypt_slice(&mut self, buf: &mut [u8]) -> Result<usize, aes_gcm::Error> {
+        self.decryptor.decrypt_with_ad_slice(&[], buf)
+    }
+    /// Encrypt `buf` in place and return the MAC
+    pub fn encrypt_detached(&mut self, buf: &mut [u8]) -> Result<[u8; 16], aes_gcm::Error> {
+        self.encryptor.encrypt_with_ad_detached(&[], buf)
+    }
+    /// Decrypt `buf` in place checking it against `mac`
+    pub fn decrypt_detached(
+        &mut self,
+        buf: &mut [u8],
+        mac: &[u8; 16],
+    ) -> Result<(), aes_gcm::Error> {
+        self.decryptor.decrypt_with_ad_detached(&[], buf, mac)
+    }
+    /// Write the encryption of `msg` followed by the MAC in `out`, that must be at least
+    /// `msg.len() + 16` bytes. Return the length of the encrypted message.
+    pub fn encrypt_to(&mut self, msg: &[u8], out: &mut [u8]) -> Result<usize, aes_gcm::Error> {
+        self.encryptor.encrypt_with_ad_to(&[], msg, out)
+    }
+    /// Write the decryption of `msg` (ciphertext followed by the MAC) in `out`. Return the length
+    /// of the plaintext.
+    pub fn decrypt_to(&mut self, msg: &[u8], out: &mut [u8]) -> Result<usize, aes_gcm::Error> {
+        self.decryptor.decrypt_with_ad_to(&[], msg, out)
+    }
+    /// Encrypt `msgs` with sequential nonces one after the other in `out`, each one followed by
+    /// its MAC. Return the number of bytes written.
+    pub fn encrypt_batch(
+        &mut self,
+        msgs: &[IoSlice<'_>],
+        out: &mut [u8],
+    ) -> Result<usize, aes_gcm::Error> {
+        self.encryptor.encrypt_with_ad_batch(&[], msgs, out)
+    }
+    /// Encrypt `parts` as a single message in `out`. Return the length of the encrypted message.
+    pub fn encrypt_vectored(
+        &mut self,
+        parts: &[IoSlice<'_>],
+        out: &mut [u8],
+    ) -> Result<usize, aes_gcm::Error> {
+        self.encryptor.encrypt_with_ad_vectored(&[], parts, out)
+    }
+    /// Decrypt in place the messages in `buf` whose lengths are `lens`, see
+    /// [`NoiseCodec::encrypt_batch`]. The plaintexts are moved at the start of `buf` and `lens`
+    /// is updated with their lengths. Return the total length of the plaintexts.
+    pub fn decrypt_batch(
+        &mut self,
+        buf: &mut [u8],
+        lens: &mut [usize],
+    ) -> Result<usize, aes_gcm::Error> {
+        self.decryptor.decrypt_with_ad_batch(&[], buf, lens)
+    }
+}
+
+impl<C: AeadCipher> fmt::Debug for NoiseCodec<C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NoiseCodec")
+            .field("encryptor", &self.encryptor)
+            .field("decryptor", &self.decryptor)
+            .finish()
     }
 }
 
+pub use aed_cipher::{AeadCipher, DynCipher, TransportCipher, MAX_NONCE_LEN};
+pub use cipher_registry::{
+    decode_cipher_list, encode_cipher_list, CipherBuilder, CipherCode, CipherRegistry, AESG, ASIV,
+    MAX_CIPHERS, XCHA,
+};
+pub use config::{has_aes_hardware, InitiatorConfig, ResponderConfig};
+pub use dh::{Dh, Secp256k1, Secp256k1KeyPair, X25519KeyPair, MAX_DH_LEN, MAX_PUB_KEY_LEN, X25519};
+pub use error::{Error, ErrorBytes, ErrorKind, Phase, Role};
+pub use hash::{Blake2b, Blake2s, NoiseHash, Sha256, Sha512, MAX_BLOCK_LEN, MAX_HASH_LEN};
 pub use initiator::Initiator;
+pub use known_servers::{KnownServer, KnownServers};
+pub use protocol_name::{
+    ProtocolName, SUPPORTED_CIPHERS, SUPPORTED_DH, SUPPORTED_HASHES, SUPPORTED_PATTERNS,
+};
 pub use responder::Responder;
+pub use revocation::{Revocation, RevocationList, RevocationSet};
+pub use signature_message::{
+    CertificateError, CertificateExtension, SignatureNoiseMessage, MAX_EXTENSIONS_LEN,
+};
+pub use split::{NoiseReceiver, NoiseSender, ReuniteError};
+pub use trust_store::{AuthorityTrustStore, TrustedAuthority};
@@ -0,0 +1,208 @@
+use std::{fmt, str::FromStr};
+
+use crate::error::{Error, ErrorKind};
+use crate::hash::{NoiseHash, MAX_HASH_LEN};
+
+/// Handshake patterns implemented by this crate
+pub const SUPPORTED_PATTERNS: [&str; 1] = ["NX"];
+/// Names of the [`crate::Dh`] implementations of this crate
+pub const SUPPORTED_DH: [&str; 3] = ["secp256k1", "Secp256k1+EllSwift", "25519"];
+/// Cipher functions that can be named in a protocol name
+pub const SUPPORTED_CIPHERS: [&str; 2] = ["ChaChaPoly", "AESGCM"];
+/// Names of the [`NoiseHash`] implementations of this crate
+pub const SUPPORTED_HASHES: [&str; 4] = ["SHA256", "SHA512", "BLAKE2s", "BLAKE2b"];
+
+/// Noise protocol name as `Noise_<pattern>_<dh>_<cipher>_<hash>`, for example
+/// `Noise_NX_secp256k1_ChaChaPoly_SHA256`. It is used to derive the initial `ck` and `h` of the
+/// handshake.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProtocolName {
+    pattern: String,
+    dh: String,
+    cipher: String,
+    hash: String,
+}
+
+impl ProtocolName {
+    /// Protocol name of the NX handshake used by Sv2. `dh` and `hash` come from the [`crate::Dh`]
+    /// and [`NoiseHash`] implementations so they are not checked against the supported lists.
+    pub(crate) fn nx(dh: &str, hash: &str) -> Self {
+        Self {
+            pattern: "NX".to_string(),
+            dh: dh.to_string(),
+            cipher: "ChaChaPoly".to_string(),
+            hash: hash.to_string(),
+        }
+    }
+
+    /// Error if this is not the name of the NX handshake with the given DH and hash functions
+    pub(crate) fn ensure_nx(&self, dh: &str, hash: &str) -> Result<(), Error> {
+        if *self == Self::nx(dh, hash) {
+            Ok(())
+        } else {
+            Err(ErrorKind::UnsupportedProtocolName(self.to_string()).into())
+        }
+    }
+
+    pub fn pattern(&self) -> &str {
+        &self.pattern
+    }
+
+    pub fn dh(&self) -> &str {
+        &self.dh
+    }
+
+    pub fn cipher(&self) -> &str {
+        &self.cipher
+    }
+
+    pub fn hash(&self) -> &str {
+        &self.hash
+    }
+
+    fn parts(&self) -> [&str; 9] {
+        [
+            "Noise",
+            "_",
+            &self.pattern,
+            "_",
+            &self.dh,
+            "_",
+            &self.cipher,
+            "_",
+            &self.hash,
+        ]
+    }
+
+    /// If protocolName is less than or equal to HASHLEN bytes in length, use protocolName with
+    /// zero bytes appended to make HASHLEN bytes. Otherwise, apply HASH to it. `H` must be the hash
+    /// function named by the protocol name, only the first `H::HASH_LEN` bytes are meaningful.
+    pub fn pad_or_hash<H: NoiseHash>(&self) -> [u8; MAX_HASH_LEN] {
+        debug_assert!(self.hash == H::NAME);
+        let parts = self.parts();
+        let len: usize = parts.iter().map(|part| part.len()).sum();
+        if len <= H::HASH_LEN {
+            let mut padded = [0; MAX_HASH_LEN];
+            let mut index = 0;
+            for part in parts {
+                padded[index..index + part.len()].copy_from_slice(part.as_bytes());
+                index += part.len();
+            }
+            padded
+        } else {
+            let mut engine = H::Engine::default();
+            for part in parts {
+                H::input(&mut engine, part.as_bytes());
+            }
+            H::finalize(engine)
+        }
+    }
+}
+
+impl FromStr for ProtocolName {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || Error::from(ErrorKind::InvalidProtocolName(s.to_string()));
+        let mut parts = s.split('_');
+        if parts.next() != Some("Noise") {
+            return Err(invalid());
+        }
+        let mut next = |supported: &[&str]| match parts.next() {
+            Some(part) if supported.contains(&part) => Ok(part.to_string()),
+            _ => Err(invalid()),
+        };
+        let name = Self {
+            pattern: next(&SUPPORTED_PATTERNS)?,
+            dh: next(&SUPPORTED_DH)?,
+            cipher: next(&SUPPORTED_CIPHERS)?,
+            hash: next(&SUPPORTED_HASHES)?,
+        };
+        match parts.next() {
+            Some(_) => Err(invalid()),
+            None => Ok(name),
+        }
+    }
+}
+
+impl fmt::Display for ProtocolName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for part in self.parts() {
+            f.write_str(part)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::hash::{Sha256, Sha512};
+
+    #[test]
+    fn test_parse() {
+        let name: ProtocolName = crate::PROTOCOL_NAME_CHACHA.parse().unwrap();
+        assert!(name.pattern() == "NX");
+        assert!(name.dh() == "secp256k1");
+        assert!(name.cipher() == "ChaChaPoly");
+        assert!(name.hash() == "SHA256");
+        assert!(name.to_string() == crate::PROTOCOL_NAME_CHACHA);
+        assert!(name == ProtocolName::nx("secp256k1", "SHA256"));
+
+        for name in [
+            crate::PROTOCOL_NAME_AES,
+            crate::PROTOCOL_NAME_ELLSWIFT_CHACHA,
+            "Noise_NX_25519_ChaChaPoly_BLAKE2b",
+        ] {
+            assert!(name.parse::<ProtocolName>().unwrap().to_string() == name);
+        }
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        for name in [
+            "",
+            "Noise",
+            "Noise_NX_secp256k1_ChaChaPoly",
+            "Noise_NX_secp256k1_ChaChaPoly_SHA256_",
+            "Noise_XX_secp256k1_ChaChaPoly_SHA256",
+            "Noise_NX_448_ChaChaPoly_SHA256",
+            "Noise_NX_secp256k1_AES-GCM_SHA256",
+            "Noise_NX_secp256k1_ChaChaPoly_SHA1",
+            "NoisePSK_NX_secp256k1_ChaChaPoly_SHA256",
+        ] {
+            match name.parse::<ProtocolName>().map_err(Error::into_kind) {
+                Err(ErrorKind::InvalidProtocolName(n)) => assert!(n == name),
+                _ => panic!("{} must be invalid", name),
+            }
+        }
+    }
+
+    #[test]
+    fn test_pad_or_hash() {
+        let name: ProtocolName = crate::PROTOCOL_NAME_CHACHA.parse().unwrap();
+        // 36 bytes so it is hashed with SHA256
+        let expected = [
+            168, 246, 65, 106, 218, 197, 235, 205, 62, 183, 118, 131, 234, 247, 6, 174, 180, 164,
+            162, 125, 30, 121, 156, 182, 95, 117, 218, 138, 122, 135, 4, 65,
+        ];
+        assert!(name.pad_or_hash::<Sha256>()[..32] == expected);
+
+        let name: ProtocolName = crate::PROTOCOL_NAME_ELLSWIFT_CHACHA.parse().unwrap();
+        let expected = [
+            46, 180, 120, 129, 32, 142, 158, 238, 31, 102, 159, 103, 198, 110, 231, 14, 169, 234,
+            136, 9, 13, 80, 63, 232, 48, 220, 75, 200, 62, 41, 191, 16,
+        ];
+        assert!(name.pad_or_hash::<Sha256>()[..32] == expected);
+
+        // exactly 32 bytes so it is padded and not hashed
+        let name: ProtocolName = "Noise_NX_25519_ChaChaPoly_SHA256".parse().unwrap();
+        assert!(&name.pad_or_hash::<Sha256>()[..32] == b"Noise_NX_25519_ChaChaPoly_SHA256");
+
+        // padded to HASHLEN
+        let name = ProtocolName::nx("secp256k1", "SHA512");
+        let padded = name.pad_or_hash::<Sha512>();
+        assert!(&padded[..36] == b"Noise_NX_secp256k1_ChaChaPoly_SHA512");
+        assert!(padded[36..] == [0; 28]);
+    }
+}
@@ -1,40 +1,58 @@
-use std::ptr;
+use std::fmt;
+use std::marker::PhantomData;
 
-use crate::aed_cipher::AeadCipher;
-use crate::cipher_state::{Cipher, CipherState, GenericCipher};
-use crate::error::Error;
+use crate::aed_cipher::{DynCipher, TransportCipher};
+use crate::cipher_state::{Cipher, CipherState};
+use crate::config::ResponderConfig;
+use crate::dh::{Dh, Secp256k1, MAX_DH_LEN};
+use crate::error::{Error, ErrorBytes, ErrorKind, Phase, Role};
 use crate::handshake::HandshakeOp;
-use crate::{signature_message::SignatureNoiseMessage, NoiseCodec};
-use aes_gcm::KeyInit;
+use crate::hash::{NoiseHash, Sha256, MAX_HASH_LEN};
+use crate::protocol_name::ProtocolName;
+use crate::secret::{erase_keypair, CipherName, Hex, Secret};
+use crate::signature_message::{CertificateExtension, SignatureNoiseMessage};
+use crate::{NoiseCodec, ProtocolVersion};
 use chacha20poly1305::ChaCha20Poly1305;
-use secp256k1::KeyPair;
+use secp256k1::Keypair;
+use zeroize::Zeroizing;
 
-const VERSION: u16 = 0;
-
-pub struct Responder<C: AeadCipher> {
+pub struct Responder<C: TransportCipher = DynCipher, D: Dh = Secp256k1, H: NoiseHash = Sha256> {
     handshake_cipher: Option<ChaCha20Poly1305>,
-    k: Option<[u8; 32]>,
+    k: Secret<Option<[u8; 32]>>,
     n: u64,
     // Chaining key
-    ck: [u8; 32],
+    ck: Secret<[u8; MAX_HASH_LEN]>,
     // Handshake hash
-    h: [u8; 32],
+    h: Secret<[u8; MAX_HASH_LEN]>,
+    dh: D,
     // ephemeral keypair
-    e: KeyPair,
+    e: D::KeyPair,
     // Static pub keypair
-    s: KeyPair,
-    c1: Option<GenericCipher<C>>,
-    c2: Option<GenericCipher<C>>,
+    s: D::KeyPair,
+    // Authority keypair, signs the SIGNATURE_NOISE_MESSAGE
+    a: Keypair,
+    // Extensions of the SIGNATURE_NOISE_MESSAGE
+    extensions: Vec<CertificateExtension>,
+    // Next step of the handshake: 1 or 3
+    step: u8,
+    // Keys of the transport ciphers, they are built when the cipher is negotiated
+    k1: Secret<Option<[u8; 32]>>,
+    k2: Secret<Option<[u8; 32]>>,
+    // Ciphers that can be negotiated after the handshake
+    config: ResponderConfig,
+    // Type of the negotiated transport cipher
+    cipher: PhantomData<C>,
+    hash: PhantomData<H>,
 }
 /// Make sure that Respoder is not sync so we do not need to worry about what other memory accessor see
 /// after that we zeroize k is send cause if we send it the original thread can not access
 /// anymore it
-impl<C: AeadCipher> !Sync for Responder<C> {}
-impl<C: AeadCipher> !Copy for Responder<C> {}
+impl<C: TransportCipher, D: Dh, H: NoiseHash> !Sync for Responder<C, D, H> {}
+impl<C: TransportCipher, D: Dh, H: NoiseHash> !Copy for Responder<C, D, H> {}
 
-impl<C: AeadCipher> CipherState<ChaCha20Poly1305> for Responder<C> {
+impl<C: TransportCipher, D: Dh, H: NoiseHash> CipherState<ChaCha20Poly1305> for Responder<C, D, H> {
     fn get_k(&mut self) -> &mut Option<[u8; 32]> {
-        &mut self.k
+        self.k.expose_mut()
     }
     fn get_n(&self) -> u64 {
         self.n
@@ -44,31 +62,33 @@ impl<C: AeadCipher> CipherState<ChaCha20Poly1305> for Responder<C> {
     }
 
     fn set_k(&mut self, k: Option<[u8; 32]>) {
-        self.k = k;
+        *self.k.expose_mut() = k;
     }
     fn get_cipher(&mut self) -> &mut Option<ChaCha20Poly1305> {
         &mut self.handshake_cipher
     }
 }
 
-impl<C: AeadCipher> HandshakeOp<ChaCha20Poly1305> for Responder<C> {
+impl<C: TransportCipher, D: Dh, H: NoiseHash> HandshakeOp<ChaCha20Poly1305> for Responder<C, D, H> {
+    type Hash = H;
+
     fn name(&self) -> String {
         "Responder".to_string()
     }
-    fn get_h(&mut self) -> &mut [u8; 32] {
-        &mut self.h
+    fn get_h(&mut self) -> &mut [u8; MAX_HASH_LEN] {
+        self.h.expose_mut()
     }
 
-    fn get_ck(&mut self) -> &mut [u8; 32] {
-        &mut self.ck
+    fn get_ck(&mut self) -> &mut [u8; MAX_HASH_LEN] {
+        self.ck.expose_mut()
     }
 
-    fn set_h(&mut self, data: [u8; 32]) {
-        self.h = data;
+    fn set_h(&mut self, data: [u8; MAX_HASH_LEN]) {
+        *self.h.expose_mut() = data;
     }
 
-    fn set_ck(&mut self, data: [u8; 32]) {
-        self.ck = data;
+    fn set_ck(&mut self, data: [u8; MAX_HASH_LEN]) {
+        *self.ck.expose_mut() = data;
     }
 
     fn set_handshake_cipher(&mut self, cipher: ChaCha20Poly1305) {
@@ -76,23 +96,104 @@ impl<C: AeadCipher> HandshakeOp<ChaCha20Poly1305> for Responder<C> {
     }
 }
 
-impl<C: AeadCipher> Responder<C> {
-    pub fn new(s: KeyPair) -> Box<Self> {
+impl<C: TransportCipher> Responder<C> {
+    /// `s` is used both as static key and as authority key
+    pub fn new(s: Keypair) -> Box<Self> {
+        Self::new_with_version(s, ProtocolVersion::default())
+    }
+
+    /// Create a responder that speaks the given revision of the handshake
+    pub fn new_with_version(s: Keypair, version: ProtocolVersion) -> Box<Self> {
+        let dh = Secp256k1::new(version);
+        Self::with_dh(dh.keypair(s), s, dh)
+    }
+}
+
+impl<C: TransportCipher, D: Dh, H: NoiseHash> Responder<C, D, H> {
+    /// Create a responder that uses `dh` for the handshake with the static key `s`. The
+    /// SIGNATURE_NOISE_MESSAGE is signed with the authority key `a`.
+    pub fn with_dh(s: D::KeyPair, a: Keypair, dh: D) -> Box<Self> {
+        let protocol_name = ProtocolName::nx(dh.name(), H::NAME);
+        Self::init(s, a, dh, &protocol_name)
+    }
+
+    /// Same as [`Responder::with_dh`] but check that `protocol_name` names the NX handshake with `dh`,
+    /// ChaChaPoly and `H`, then initialize the handshake from it.
+    pub fn with_protocol_name(
+        s: D::KeyPair,
+        a: Keypair,
+        dh: D,
+        protocol_name: &ProtocolName,
+    ) -> Result<Box<Self>, Error> {
+        protocol_name.ensure_nx(dh.name(), H::NAME)?;
+        Ok(Self::init(s, a, dh, protocol_name))
+    }
+
+    fn init(s: D::KeyPair, a: Keypair, dh: D, protocol_name: &ProtocolName) -> Box<Self> {
         let mut self_ = Self {
             handshake_cipher: None,
-            k: None,
+            k: Secret::new(None),
             n: 0,
-            ck: [0; 32],
-            h: [0; 32],
-            e: Self::generate_key(),
+            ck: Secret::new([0; MAX_HASH_LEN]),
+            h: Secret::new([0; MAX_HASH_LEN]),
+            e: dh.generate_keypair(),
             s,
-            c1: None,
-            c2: None,
+            a,
+            dh,
+            extensions: vec![],
+            step: 1,
+            k1: Secret::new(None),
+            k2: Secret::new(None),
+            config: ResponderConfig::default(),
+            cipher: PhantomData,
+            hash: PhantomData,
         };
-        Self::initialize_self(&mut self_);
+        self_.initialize_self(protocol_name);
         Box::new(self_)
     }
 
+    /// Cipher policy used in [`Responder::step_3`]. The default config allows AES-256-GCM and
+    /// falls back to ChaChaPoly.
+    pub fn set_config(&mut self, config: ResponderConfig) {
+        self.config = config;
+    }
+
+    /// Extensions of the certificate sent in [`Responder::step_1`]. Without extensions the
+    /// certificate is a version 0 SIGNATURE_NOISE_MESSAGE, with extensions it is version 1 and
+    /// the message is longer, see [`Responder::second_message_len`].
+    pub fn set_certificate_extensions(
+        &mut self,
+        extensions: Vec<CertificateExtension>,
+    ) -> Result<(), Error> {
+        CertificateExtension::encoded_len(&extensions)?;
+        self.extensions = extensions;
+        Ok(())
+    }
+
+    /// Length of the message written by [`Responder::step_1`]
+    pub fn second_message_len(&self) -> usize {
+        let extensions = CertificateExtension::encoded_len(&self.extensions).unwrap_or(0);
+        self.dh.second_message_len() + extensions
+    }
+
+    /// `MixKey(DH(e.private_key, re.public_key))`
+    fn mix_key_with_e(&mut self, re_pub: &[u8]) -> Result<(), Error> {
+        let mut dh = Zeroizing::new([0; MAX_DH_LEN]);
+        self.dh.dh(&self.e, re_pub, false, &mut dh)?;
+        let len = self.dh.dh_len();
+        self.mix_key(&dh[..len]);
+        Ok(())
+    }
+
+    /// `MixKey(DH(s.private_key, re.public_key))`
+    fn mix_key_with_s(&mut self, re_pub: &[u8]) -> Result<(), Error> {
+        let mut dh = Zeroizing::new([0; MAX_DH_LEN]);
+        self.dh.dh(&self.s, re_pub, false, &mut dh)?;
+        let len = self.dh.dh_len();
+        self.mix_key(&dh[..len]);
+        Ok(())
+    }
+
     /// #### 4.5.1.2 Responder
     ///
     /// 1. receives ephemeral public key message (32 bytes plaintext public key)
@@ -126,67 +227,90 @@ impl<C: AeadCipher> Responder<C> {
     /// | SIGNATURE_NOISE_MESSAGE | Signed message containing Responder's static key. Signature is issued by authority that is generally known to operate the server acting as the noise responder |
     /// | MAC                     | Message authentication code for SIGNATURE_NOISE_MESSAGE                                                                                                        |
     ///
-    /// Message length: 170 bytes
-    pub fn step_1(
+    /// Message length: 170 bytes, 234 bytes with [`ProtocolVersion::EllSwift`], plus the
+    /// certificate extensions
+    pub fn step_1(&mut self, re_pub: Vec<u8>, cert_validity: u32) -> Result<Vec<u8>, Error> {
+        let mut out = vec![0; self.second_message_len()];
+        self.step_1_slice(&re_pub, cert_validity, &mut out)?;
+        Ok(out)
+    }
+
+    /// Same as [`Responder::step_1`] but read the initiator message from a slice and write the
+    /// response in `out` without allocating. Return the number of bytes written.
+    pub fn step_1_slice(
         &mut self,
-        re_pub: [u8; 32],
+        re_pub: &[u8],
         cert_validity: u32,
-    ) -> Result<[u8; 170], aes_gcm::Error> {
+        out: &mut [u8],
+    ) -> Result<usize, Error> {
+        let mut phase = Phase::EphemeralKey;
+        self.write_responder_message(re_pub, cert_validity, out, &mut phase)
+            .map_err(|e| e.at(Role::Responder, phase))
+    }
+
+    /// Body of [`Responder::step_1_slice`], `phase` is updated as the message is written
+    fn write_responder_message(
+        &mut self,
+        re_pub: &[u8],
+        cert_validity: u32,
+        out: &mut [u8],
+        phase: &mut Phase,
+    ) -> Result<usize, Error> {
+        let pk_len = self.dh.pub_key_len();
+        let message_len = self.second_message_len();
+        if re_pub.len() != self.dh.first_message_len() {
+            return Err(ErrorKind::InvalidMessageLength(re_pub.len()).into());
+        }
+        if out.len() < message_len {
+            return Err(ErrorKind::OutputBufferTooSmall(message_len).into());
+        }
         // 4.5.1.2 Responder
-        Self::mix_hash(self, &re_pub[..]);
-        Self::decrypt_and_hash(self, &mut vec![])?;
+        Self::mix_hash(self, re_pub);
+        Self::decrypt_and_hash(self, &mut [])?;
 
         // 4.5.2.1 Responder
-        let mut out = [0; 170];
-        let serialized = self.e.x_only_public_key().0.serialize();
-        out[..32].copy_from_slice(&serialized[..32]);
+        out[..pk_len].copy_from_slice(self.dh.pub_key(&self.e));
 
         // 3. calls `MixHash(e.public_key)`
-        Self::mix_hash(self, &serialized);
+        Self::mix_hash(self, &out[..pk_len]);
 
         // 4. calls `MixKey(ECDH(e.private_key, re.public_key))`
-        let e_private_key = self.e.secret_bytes();
-        let ecdh = Self::ecdh(&e_private_key[..], &re_pub[..]);
-        Self::mix_key(self, &ecdh);
+        self.mix_key_with_e(re_pub)?;
 
+        *phase = Phase::StaticKey;
         // 5. appends `EncryptAndHash(s.public_key)` (32 bytes encrypted public key, 16 bytes MAC)
-        let mut encrypted_static_pub_k = vec![0; 32];
-        let static_pub_k = self.s.x_only_public_key().0.serialize();
-        encrypted_static_pub_k[..32].copy_from_slice(&static_pub_k[..32]);
-        self.encrypt_and_hash(&mut encrypted_static_pub_k)?;
-        out[32..(32 + 16 + 32)].copy_from_slice(&encrypted_static_pub_k[..(32 + 16)]);
+        let encrypted_static_pub_k = &mut out[pk_len..(2 * pk_len + 16)];
+        encrypted_static_pub_k[..pk_len].copy_from_slice(self.dh.pub_key(&self.s));
+        self.encrypt_and_hash(encrypted_static_pub_k, pk_len)?;
 
         // 6. calls `MixKey(ECDH(s.private_key, re.public_key))`
-        let s_private_key = self.s.secret_bytes();
-        let ecdh = Self::ecdh(&s_private_key[..], &re_pub[..]);
-        Self::mix_key(self, &ecdh[..]);
+        self.mix_key_with_s(re_pub)?;
 
+        *phase = Phase::Certificate;
         // 7. appends `EncryptAndHash(SIGNATURE_NOISE_MESSAGE)` to the buffer
         let valid_from = std::time::SystemTime::now()
             .duration_since(std::time::UNIX_EPOCH)
             .unwrap()
             .as_secs();
         let not_valid_after = valid_from as u32 + cert_validity;
-        let signature_noise_message =
-            self.get_signature(VERSION, valid_from as u32, not_valid_after);
-        let mut signature_part = Vec::with_capacity(74 + 16);
-        signature_part.extend_from_slice(&signature_noise_message[..]);
-        Self::encrypt_and_hash(self, &mut signature_part)?;
-        for i in (32 + 48)..(32 + 48 + 74 + 16) {
-            out[i] = signature_part[i - (32 + 48)];
-        }
+        let static_key = self.dh.canonical_pub_key(self.dh.pub_key(&self.s))?;
+        let signature_part = &mut out[(2 * pk_len + 16)..message_len];
+        let len = SignatureNoiseMessage::sign_into(
+            valid_from as u32,
+            not_valid_after,
+            &self.extensions,
+            &static_key,
+            &self.a,
+            signature_part,
+        )?;
+        Self::encrypt_and_hash(self, signature_part, len)?;
 
         // 9. return pair of CipherState objects, the first for encrypting transport messages from initiator to responder, and the second for messages in the other direction:
-        let ck = Self::get_ck(self);
-        let (temp_k1, temp_k2) = Self::hkdf_2(ck, &[]);
-        let c1 = ChaCha20Poly1305::new(&temp_k1.into());
-        let c2 = ChaCha20Poly1305::new(&temp_k2.into());
-        let c1: Cipher<ChaCha20Poly1305> = Cipher::from_key_and_cipher(temp_k1, c1);
-        let c2: Cipher<ChaCha20Poly1305> = Cipher::from_key_and_cipher(temp_k2, c2);
-        self.c1 = Some(GenericCipher::ChaCha20Poly1305(c1));
-        self.c2 = Some(GenericCipher::ChaCha20Poly1305(c2));
-        let to_send = out;
-        Ok(to_send)
+        let (temp_k1, temp_k2) = self.split_keys();
+        *self.k1.expose_mut() = Some(temp_k1);
+        *self.k2.expose_mut() = Some(temp_k2);
+        self.step = 3;
+        Ok(message_len)
     }
 
     /// ### 4.5.5 Cipher upgrade part 2: `<- CIPHER_CHOICE`
@@ -203,105 +327,103 @@ impl<C: AeadCipher> Responder<C> {
     /// | OPTION[u32] | Request to upgrade to a given AEAD-cipher |
     ///
     /// Message length: 1 or 5 bytes
-    pub fn step_3(
-        mut self,
-        cipher_list: Vec<u8>,
-    ) -> Result<(Vec<u8>, NoiseCodec<C>), crate::error::Error> {
-        match cipher_list.len() {
-            0 => Err(Error::InvalidCipherList(cipher_list)),
-            1 => {
-                if cipher_list[0] == 0 {
-                    let mut encryptor = None;
-                    std::mem::swap(&mut encryptor, &mut self.c2);
-                    let mut decryptor = None;
-                    std::mem::swap(&mut decryptor, &mut self.c1);
-                    let mut encryptor = encryptor.unwrap();
-                    let mut decryptor = decryptor.unwrap();
-                    encryptor.erase_k();
-                    decryptor.erase_k();
-                    // Initiator is ok to use ChaCha
-                    let codec = crate::NoiseCodec {
-                        encryptor,
-                        decryptor,
-                    };
-                    Ok((vec![0], codec))
-                } else {
-                    Err(Error::InvalidCipherList(cipher_list))
-                }
-            }
-            _ => {
-                if cipher_list[0] as usize * 4 + 1 != cipher_list.len() {
-                    Err(Error::InvalidCipherList(cipher_list))
-                } else {
-                    let mut index = 1;
-                    while index < cipher_list.len() {
-                        let cipher = &cipher_list[index..index + 4];
-                        // Initiator ask to use AesGcm
-                        if cipher == [0x47, 0x53, 0x45, 0x41] {
-                            let mut encryptor = None;
-                            std::mem::swap(&mut encryptor, &mut self.c2);
-                            let mut decryptor = None;
-                            std::mem::swap(&mut decryptor, &mut self.c1);
-                            let encryptor = encryptor.unwrap().into_aesg();
-                            let decryptor = decryptor.unwrap().into_aesg();
-                            let codec = crate::NoiseCodec {
-                                encryptor,
-                                decryptor,
-                            };
-                            return Ok((vec![1, 0x47, 0x53, 0x45, 0x41], codec));
-                        }
-                        index += 4;
-                    }
-                    Err(Error::UnsupportedCiphers(cipher_list))
-                }
-            }
-        }
+    pub fn step_3(self, cipher_list: Vec<u8>) -> Result<(Vec<u8>, NoiseCodec<C>), Error> {
+        let mut cipher_choice = [0; 5];
+        let (len, codec) = self.step_3_slice(&cipher_list, &mut cipher_choice)?;
+        Ok((cipher_choice[..len].to_vec(), codec))
     }
 
-    fn get_signature(&self, version: u16, valid_from: u32, not_valid_after: u32) -> [u8; 74] {
-        let mut ret = [0; 74];
-        let version = version.to_le_bytes();
-        let valid_from = valid_from.to_le_bytes();
-        let not_valid_after = not_valid_after.to_le_bytes();
-        ret[0] = version[0];
-        ret[1] = version[1];
-        ret[2] = valid_from[0];
-        ret[3] = valid_from[1];
-        ret[4] = valid_from[2];
-        ret[5] = valid_from[3];
-        ret[6] = not_valid_after[0];
-        ret[7] = not_valid_after[1];
-        ret[8] = not_valid_after[2];
-        ret[9] = not_valid_after[3];
-        SignatureNoiseMessage::sign(&mut ret, &self.s);
-        ret
+    /// Same as [`Responder::step_3`] but read `AEAD_CIPHERS` from a slice and write
+    /// `CIPHER_CHOICE` in `out` without allocating. Return the number of bytes written.
+    ///
+    /// The chosen cipher is the first cipher of the [`ResponderConfig`] that the initiator
+    /// offered. If there is none the session stays on ChaChaPoly, unless the config requires a
+    /// cipher.
+    pub fn step_3_slice(
+        self,
+        cipher_list: &[u8],
+        out: &mut [u8],
+    ) -> Result<(usize, NoiseCodec<C>), Error> {
+        self.write_cipher_choice(cipher_list, out)
+            .map_err(|e| e.at(Role::Responder, Phase::CipherNegotiation))
     }
 
-    fn erase(&mut self) {
-        if let Some(k) = self.k.as_mut() {
-            for b in k {
-                unsafe { ptr::write_volatile(b, 0) };
-            }
-        }
-        for mut b in self.ck {
-            unsafe { ptr::write_volatile(&mut b, 0) };
-        }
-        for mut b in self.h {
-            unsafe { ptr::write_volatile(&mut b, 0) };
+    fn write_cipher_choice(
+        mut self,
+        cipher_list: &[u8],
+        out: &mut [u8],
+    ) -> Result<(usize, NoiseCodec<C>), Error> {
+        if out.len() < 5 {
+            return Err(ErrorKind::OutputBufferTooSmall(5).into());
         }
-        if let Some(c1) = self.c1.as_mut() {
-            c1.erase_k()
+        let chosen = self
+            .config
+            .ciphers()
+            .choose_where(cipher_list, |code| C::supports(Some(code)))?;
+        if self.k1.expose().is_none() || self.k2.expose().is_none() {
+            return Err(ErrorKind::HandshakeNotFinalized.into());
         }
-        if let Some(c2) = self.c2.as_mut() {
-            c2.erase_k()
+        let len = match chosen {
+            Some(code) => {
+                out[0] = 1;
+                out[1..5].copy_from_slice(&code);
+                5
+            }
+            None if self.config.is_cipher_required() || !C::supports(None) => {
+                return Err(ErrorKind::UnsupportedCiphers(ErrorBytes::new(cipher_list)).into())
+            }
+            // Nothing better than ChaCha
+            None => {
+                out[0] = 0;
+                1
+            }
+        };
+        let ciphers = self.config.ciphers();
+        let encryptor = Cipher::negotiated(ciphers, chosen, self.k2.expose_mut());
+        let decryptor = Cipher::negotiated(ciphers, chosen, self.k1.expose_mut());
+        match (encryptor, decryptor) {
+            (Some(encryptor), Some(decryptor)) => Ok((
+                len,
+                NoiseCodec {
+                    encryptor,
+                    decryptor,
+                },
+            )),
+            _ => Err(ErrorKind::InvalidCipherState.into()),
         }
-        self.e.non_secure_erase();
-        self.s.non_secure_erase();
     }
 }
 
-impl<C: AeadCipher> Drop for Responder<C> {
+impl<C: TransportCipher, D: Dh, H: NoiseHash> Drop for Responder<C, D, H> {
+    /// The other secrets are erased by the drop of their fields
     fn drop(&mut self) {
-        self.erase();
+        erase_keypair(&mut self.a);
+    }
+}
+
+impl<C: TransportCipher, D: Dh, H: NoiseHash> fmt::Debug for Responder<C, D, H> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ciphers: Vec<_> = self
+            .config
+            .ciphers()
+            .codes()
+            .filter(|c| C::supports(Some(*c)))
+            .map(|c| CipherName(Some(c)))
+            .collect();
+        f.debug_struct("Responder")
+            .field("step", &self.step)
+            .field("dh", &self.dh.name())
+            .field("hash", &H::NAME)
+            .field("n", &self.n)
+            .field("k", &self.k)
+            .field("ck", &self.ck)
+            .field("h", &self.h)
+            .field("e", &Hex(self.dh.pub_key(&self.e)))
+            .field("s", &Hex(self.dh.pub_key(&self.s)))
+            .field("a", &self.a.x_only_public_key().0)
+            .field("ciphers", &ciphers)
+            .field("k1", &self.k1)
+            .field("k2", &self.k2)
+            .finish()
     }
 }
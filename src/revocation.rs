@@ -0,0 +1,364 @@
+use crate::error::{Error, ErrorKind};
+use crate::secret::Hex;
+use crate::signature_message::{CertificateError, SignatureNoiseMessage};
+use secp256k1::constants::CURVE_ORDER;
+use secp256k1::ellswift::ElligatorSwift;
+use secp256k1::{
+    hashes::sha256, schnorr::Signature, Keypair, Message, PublicKey, XOnlyPublicKey, SECP256K1,
+};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+/// Version of the encoded revocation list
+const VERSION: u16 = 0;
+/// Prepended to the signed data so that a revocation list signature can not be mistaken for a
+/// certificate signature
+const SIGNATURE_TAG: &[u8] = b"Sv2 revocation list";
+
+const STATIC_KEY: u8 = 1;
+const CERTIFICATE: u8 = 2;
+
+/// Server static key or certificate that must not be accepted anymore
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub enum Revocation {
+    /// Static public key of a responder as returned by [`crate::Dh::canonical_pub_key`]
+    StaticKey([u8; 32]),
+    /// [`SignatureNoiseMessage::hash`] of a certificate. The [`crate::Responder`] signs a new
+    /// certificate in every handshake, so this only revokes certificates issued externally and
+    /// sent unchanged by the responder.
+    Certificate([u8; 32]),
+}
+
+impl Revocation {
+    /// Revoke the static key `key`, either in canonical form or as a 64 bytes ElligatorSwift
+    /// encoding
+    pub fn static_key(key: &[u8]) -> Result<Self, Error> {
+        match key.len() {
+            32 => Ok(Self::StaticKey(key.try_into().unwrap())),
+            64 => {
+                let encoded = ElligatorSwift::from_array(key.try_into().unwrap());
+                let key = PublicKey::from_ellswift(encoded).x_only_public_key().0;
+                Ok(Self::StaticKey(key.serialize()))
+            }
+            _ => Err(ErrorKind::InvalidPublicKey.into()),
+        }
+    }
+}
+
+impl fmt::Debug for Revocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::StaticKey(key) => f.debug_tuple("StaticKey").field(&Hex(key)).finish(),
+            Self::Certificate(hash) => f.debug_tuple("Certificate").field(&Hex(hash)).finish(),
+        }
+    }
+}
+
+/// List of revocations issued by the authority.
+///
+/// Encoded as version (u16), issued_at (u32), number of revocations (u16), each revocation as
+/// type (u8, 1 static key, 2 certificate hash), length (u8, 32 for both) and value, then the
+/// Schnorr signature of the authority over all the previous bytes.
+pub struct RevocationList {
+    /// Seconds since the unix epoch
+    pub issued_at: u32,
+    pub revocations: Vec<Revocation>,
+    pub signature: [u8; 64],
+}
+
+impl fmt::Debug for RevocationList {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RevocationList")
+            .field("issued_at", &self.issued_at)
+            .field("revocations", &self.revocations)
+            .field("signature", &Hex(&self.signature))
+            .finish()
+    }
+}
+
+impl RevocationList {
+    /// Unsigned list, fail if it can not be encoded
+    pub fn new(issued_at: u32, revocations: Vec<Revocation>) -> Result<Self, Error> {
+        if revocations.len() > u16::MAX as usize {
+            return Err(ErrorKind::InvalidRevocationList(CertificateError::Malformed).into());
+        }
+        Ok(Self {
+            issued_at,
+            revocations,
+            signature: [0; 64],
+        })
+    }
+
+    /// Sign the list with the authority keypair
+    pub fn sign(&mut self, kp: &Keypair) {
+        let m = Message::from_hashed_data::<sha256::Hash>(&self.signed_data());
+        self.signature = SECP256K1.sign_schnorr(&m, kp).serialize();
+    }
+
+    /// Check that the list is signed by the authority `pk`
+    pub fn verify(&self, pk: &XOnlyPublicKey) -> Result<(), Error> {
+        let invalid = |e| Error::from(ErrorKind::InvalidRevocationList(e));
+        let s = self.signature;
+        if XOnlyPublicKey::from_slice(&s[..32]).is_err() || s[32..] >= CURVE_ORDER[..] {
+            return Err(invalid(CertificateError::MalformedSignature));
+        }
+        let m = Message::from_hashed_data::<sha256::Hash>(&self.signed_data());
+        let s =
+            Signature::from_slice(&s).map_err(|_| invalid(CertificateError::MalformedSignature))?;
+        SECP256K1
+            .verify_schnorr(&s, &m, pk)
+            .map_err(|_| invalid(CertificateError::SignatureMismatch))
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut encoded = self.unsigned();
+        encoded.extend_from_slice(&self.signature);
+        encoded
+    }
+
+    /// Parse an encoded list, the signature is not checked
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        let malformed = || {
+            Error::from(ErrorKind::InvalidRevocationList(
+                CertificateError::Malformed,
+            ))
+        };
+        if bytes.len() < 8 + 64 {
+            return Err(malformed());
+        }
+        let (mut data, signature) = bytes.split_at(bytes.len() - 64);
+        let version = u16::from_le_bytes([data[0], data[1]]);
+        if version != VERSION {
+            let e = CertificateError::UnsupportedVersion(version);
+            return Err(ErrorKind::InvalidRevocationList(e).into());
+        }
+        let issued_at = u32::from_le_bytes(data[2..6].try_into().unwrap());
+        let count = u16::from_le_bytes([data[6], data[7]]);
+        data = &data[8..];
+        let mut revocations = vec![];
+        for _ in 0..count {
+            let (kind, len) = match data {
+                [kind, len, ..] => (*kind, *len as usize),
+                _ => return Err(malformed()),
+            };
+            let value = data.get(2..2 + len).ok_or_else(malformed)?;
+            let revocation = match kind {
+                STATIC_KEY if len == 32 => Revocation::StaticKey(value.try_into().unwrap()),
+                CERTIFICATE if len == 32 => Revocation::Certificate(value.try_into().unwrap()),
+                _ => return Err(malformed()),
+            };
+            revocations.push(revocation);
+            data = &data[2 + len..];
+        }
+        if !data.is_empty() {
+            return Err(malformed());
+        }
+        Ok(Self {
+            issued_at,
+            revocations,
+            signature: signature.try_into().unwrap(),
+        })
+    }
+
+    fn unsigned(&self) -> Vec<u8> {
+        let mut encoded = VERSION.to_le_bytes().to_vec();
+        encoded.extend_from_slice(&self.issued_at.to_le_bytes());
+        encoded.extend_from_slice(&(self.revocations.len() as u16).to_le_bytes());
+        for revocation in &self.revocations {
+            let (kind, value) = match revocation {
+                Revocation::StaticKey(key) => (STATIC_KEY, &key[..]),
+                Revocation::Certificate(hash) => (CERTIFICATE, &hash[..]),
+            };
+            encoded.push(kind);
+            encoded.push(value.len() as u8);
+            encoded.extend_from_slice(value);
+        }
+        encoded
+    }
+
+    fn signed_data(&self) -> Vec<u8> {
+        let mut data = SIGNATURE_TAG.to_vec();
+        data.extend_from_slice(&self.unsigned());
+        data
+    }
+}
+
+/// Revocations checked by the [`crate::Initiator`] after the certificate is verified
+#[derive(Debug, Clone, Default)]
+pub struct RevocationSet {
+    /// Latest list of each authority with its `issued_at`
+    lists: HashMap<XOnlyPublicKey, (u32, HashSet<Revocation>)>,
+    revocations: HashSet<Revocation>,
+}
+
+impl RevocationSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Replace the list of the authority `pk` with `list` if it is signed by `pk`. Fail if
+    /// `list` is older than the one already held, so that an old list can not be replayed to
+    /// lift newer revocations, or if it has the same `issued_at` but other revocations.
+    pub fn add_list(&mut self, list: &RevocationList, pk: &XOnlyPublicKey) -> Result<(), Error> {
+        list.verify(pk)?;
+        let revocations: HashSet<Revocation> = list.revocations.iter().cloned().collect();
+        if let Some((latest, held)) = self.lists.get(pk) {
+            if list.issued_at < *latest || (list.issued_at == *latest && revocations != *held) {
+                return Err(ErrorKind::OutdatedRevocationList {
+                    issued_at: list.issued_at,
+                    latest: *latest,
+                }
+                .into());
+            }
+        }
+        self.lists.insert(*pk, (list.issued_at, revocations));
+        Ok(())
+    }
+
+    /// Add a revocation that does not come from a signed list
+    pub fn add(&mut self, revocation: Revocation) {
+        self.revocations.insert(revocation);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.revocations.is_empty() && self.lists.values().all(|(_, list)| list.is_empty())
+    }
+
+    /// Fail if the static key of the responder, in the form of
+    /// [`crate::Dh::canonical_pub_key`], or its certificate are revoked
+    pub fn check(
+        &self,
+        static_key: &[u8; 32],
+        certificate: &SignatureNoiseMessage,
+    ) -> Result<(), CertificateError> {
+        // Nothing to compare to, skip hashing the certificate on every handshake
+        if self.is_empty() {
+            return Ok(());
+        }
+        let key = Revocation::StaticKey(*static_key);
+        let hash = Revocation::Certificate(certificate.hash());
+        let revoked = |r: &HashSet<Revocation>| r.contains(&key) || r.contains(&hash);
+        match revoked(&self.revocations) || self.lists.values().any(|(_, r)| revoked(r)) {
+            true => Err(CertificateError::Revoked),
+            false => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{Dh, Secp256k1};
+
+    #[test]
+    fn test_encode_and_verify() {
+        let authority = Secp256k1::generate_key();
+        let pk = authority.x_only_public_key().0;
+        let revocations = vec![
+            Revocation::StaticKey([2; 32]),
+            Revocation::Certificate([3; 32]),
+        ];
+        let mut list = RevocationList::new(100, revocations.clone()).unwrap();
+        list.sign(&authority);
+        let encoded = list.encode();
+        assert!(encoded.len() == 8 + 2 * (2 + 32) + 64);
+        let parsed = RevocationList::from_bytes(&encoded).unwrap();
+        assert!(parsed.issued_at == 100 && parsed.revocations == revocations);
+        assert!(parsed.verify(&pk).is_ok());
+
+        let mut set = RevocationSet::new();
+        let other = Secp256k1::generate_key().x_only_public_key().0;
+        assert!(matches!(
+            set.add_list(&parsed, &other).map_err(Error::into_kind),
+            Err(ErrorKind::InvalidRevocationList(
+                CertificateError::SignatureMismatch
+            ))
+        ));
+        assert!(set.is_empty());
+        set.add_list(&parsed, &pk).unwrap();
+        let certificate = SignatureNoiseMessage::from([0; 74]);
+        assert!(set.check(&[2; 32], &certificate) == Err(CertificateError::Revoked));
+        assert!(set.check(&[4; 32], &certificate).is_ok());
+        set.add(Revocation::Certificate(certificate.hash()));
+        assert!(set.check(&[4; 32], &certificate) == Err(CertificateError::Revoked));
+
+        // The revocations are covered by the signature
+        let mut tampered = encoded.clone();
+        tampered[10] = 5;
+        let tampered = RevocationList::from_bytes(&tampered).unwrap();
+        assert!(matches!(
+            tampered.verify(&pk).map_err(Error::into_kind),
+            Err(ErrorKind::InvalidRevocationList(
+                CertificateError::SignatureMismatch
+            ))
+        ));
+        let parse = |bytes: &[u8]| match RevocationList::from_bytes(bytes).map_err(Error::into_kind)
+        {
+            Err(ErrorKind::InvalidRevocationList(e)) => Some(e),
+            _ => None,
+        };
+        let truncated = [&encoded[..20], &encoded[encoded.len() - 64..]].concat();
+        assert!(parse(&truncated) == Some(CertificateError::Malformed));
+        let mut unknown_version = encoded.clone();
+        unknown_version[0] = 1;
+        assert!(parse(&unknown_version) == Some(CertificateError::UnsupportedVersion(1)));
+        assert!(Revocation::static_key(&[0; 65]).is_err());
+    }
+
+    #[test]
+    fn test_outdated_list() {
+        let authority = Secp256k1::generate_key();
+        let pk = authority.x_only_public_key().0;
+        let list = |issued_at, key| {
+            let revocations = vec![Revocation::StaticKey([key; 32])];
+            let mut list = RevocationList::new(issued_at, revocations).unwrap();
+            list.sign(&authority);
+            list
+        };
+        let certificate = SignatureNoiseMessage::from([0; 74]);
+        let mut set = RevocationSet::new();
+        set.add_list(&list(100, 2), &pk).unwrap();
+        // A newer list replaces the held one
+        set.add_list(&list(200, 3), &pk).unwrap();
+        assert!(set.check(&[2; 32], &certificate).is_ok());
+        assert!(set.check(&[3; 32], &certificate) == Err(CertificateError::Revoked));
+        assert!(matches!(
+            set.add_list(&list(100, 2), &pk).map_err(Error::into_kind),
+            Err(ErrorKind::OutdatedRevocationList {
+                issued_at: 100,
+                latest: 200
+            })
+        ));
+        assert!(set.check(&[3; 32], &certificate) == Err(CertificateError::Revoked));
+        // A list with the same issued_at is only accepted again if it is the same list
+        set.add_list(&list(200, 3), &pk).unwrap();
+        assert!(matches!(
+            set.add_list(&list(200, 4), &pk).map_err(Error::into_kind),
+            Err(ErrorKind::OutdatedRevocationList {
+                issued_at: 200,
+                latest: 200
+            })
+        ));
+        assert!(set.check(&[3; 32], &certificate) == Err(CertificateError::Revoked));
+        assert!(set.check(&[4; 32], &certificate).is_ok());
+
+        // Lists of other authorities are kept apart
+        let other = Secp256k1::generate_key();
+        let mut other_list = RevocationList::new(50, vec![]).unwrap();
+        other_list.sign(&other);
+        set.add_list(&other_list, &other.x_only_public_key().0)
+            .unwrap();
+        assert!(set.check(&[3; 32], &certificate) == Err(CertificateError::Revoked));
+    }
+
+    #[test]
+    fn test_ellswift_static_key() {
+        let kp = Secp256k1::generate_key();
+        let x_only = kp.x_only_public_key().0.serialize();
+        let dh = Secp256k1::new(crate::ProtocolVersion::EllSwift);
+        let encoded = dh.keypair(kp);
+        let revocation = Revocation::static_key(dh.pub_key(&encoded)).unwrap();
+        assert!(revocation == Revocation::StaticKey(x_only));
+        assert!(Revocation::static_key(&x_only).unwrap() == revocation);
+    }
+}
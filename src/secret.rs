@@ -0,0 +1,80 @@
+use crate::cipher_registry::{CipherCode, AESG, ASIV, XCHA};
+use secp256k1::Keypair;
+use std::mem::size_of;
+use std::{fmt, slice};
+use zeroize::{Zeroize, Zeroizing};
+
+/// Secret value zeroized on drop. It can only be read with [`Secret::expose`] and it is printed
+/// as `[REDACTED]` by `Debug`, so deriving `Debug` for a struct that holds it does not leak it.
+pub(crate) struct Secret<T: Zeroize>(Zeroizing<T>);
+
+impl<T: Zeroize> Secret<T> {
+    pub fn new(value: T) -> Self {
+        Self(Zeroizing::new(value))
+    }
+
+    pub fn expose(&self) -> &T {
+        &self.0
+    }
+
+    pub fn expose_mut(&mut self) -> &mut T {
+        &mut self.0
+    }
+}
+
+impl<T: Zeroize> fmt::Debug for Secret<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("[REDACTED]")
+    }
+}
+
+/// Overwrite `kp` with zeros. Unlike [`Keypair::non_secure_erase`] the writes are not optimized
+/// away, but the copies of the keypair made before, e.g. by the caller, are not erased.
+pub(crate) fn erase_keypair(kp: &mut Keypair) {
+    // Safety: a `Keypair` is 96 plain bytes and any byte pattern is a valid value
+    let bytes =
+        unsafe { slice::from_raw_parts_mut(kp as *mut Keypair as *mut u8, size_of::<Keypair>()) };
+    bytes.zeroize();
+}
+
+/// Print public bytes, such as public keys and signatures, as hex
+pub(crate) struct Hex<'a>(pub &'a [u8]);
+
+impl fmt::Debug for Hex<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for b in self.0 {
+            write!(f, "{:02x}", b)?;
+        }
+        Ok(())
+    }
+}
+
+/// Print the name of a negotiated cipher, `None` is ChaChaPoly
+pub(crate) struct CipherName(pub Option<CipherCode>);
+
+impl fmt::Debug for CipherName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            None => f.write_str("ChaChaPoly"),
+            Some(AESG) => f.write_str("AES-256-GCM"),
+            Some(ASIV) => f.write_str("AES-256-GCM-SIV"),
+            Some(XCHA) => f.write_str("XChaCha20-Poly1305"),
+            Some(code) => write!(f, "{:#010x}", u32::from_be_bytes(code)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_debug() {
+        let secret = Secret::new(Some([0xab; 32]));
+        assert!(format!("{:?}", secret) == "[REDACTED]");
+        assert!(format!("{:?}", Hex(&[0xab, 0x01])) == "ab01");
+        assert!(format!("{:?}", CipherName(None)) == "ChaChaPoly");
+        assert!(format!("{:?}", CipherName(Some(AESG))) == "AES-256-GCM");
+        assert!(format!("{:?}", CipherName(Some([1, 2, 3, 4]))) == "0x01020304");
+    }
+}
@@ -1,11 +1,171 @@
-use secp256k1::{hashes::sha256, schnorr::Signature, KeyPair, Message, Secp256k1, XOnlyPublicKey};
+use secp256k1::constants::CURVE_ORDER;
+use secp256k1::hashes::{sha256, Hash, HashEngine};
+use secp256k1::{schnorr::Signature, Keypair, Message, XOnlyPublicKey, SECP256K1};
+use std::fmt;
 use std::time::SystemTime;
 
+use crate::error::{Error, ErrorKind};
+use crate::secret::Hex;
+
+/// SIGNATURE_NOISE_MESSAGE without extensions
+pub(crate) const VERSION_0: u16 = 0;
+/// SIGNATURE_NOISE_MESSAGE followed by TLV extensions covered by the signature
+pub(crate) const VERSION_1: u16 = 1;
+/// Length of the version, the validity and the signature
+pub(crate) const CERTIFICATE_HEADER_LEN: usize = 74;
+/// Maximum length of the encoded extensions of a version 1 certificate
+pub const MAX_EXTENSIONS_LEN: usize = 1024;
+/// Maximum length of an encoded SIGNATURE_NOISE_MESSAGE
+pub(crate) const MAX_CERTIFICATE_LEN: usize = CERTIFICATE_HEADER_LEN + MAX_EXTENSIONS_LEN;
+
+/// Reason why a SIGNATURE_NOISE_MESSAGE is rejected, timestamps are seconds since the unix
+/// epoch
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CertificateError {
+    /// The certificate is not valid yet, the clock of one of the peers may be wrong
+    NotYetValid {
+        valid_from: u32,
+        now: u32,
+    },
+    /// The certificate is expired, the clock of one of the peers may be wrong
+    Expired {
+        not_valid_after: u32,
+        now: u32,
+    },
+    UnsupportedVersion(u16),
+    /// The certificate has an invalid length or invalid extensions
+    Malformed,
+    /// The signature is not a valid Schnorr signature encoding
+    MalformedSignature,
+    /// The signature is not made by the authority key
+    SignatureMismatch,
+    /// The certificate is signed by an authority key that is not trusted now
+    AuthorityNotValid,
+    /// The certificate or the static key of the responder is in a revocation list
+    Revoked,
+}
+
+impl fmt::Display for CertificateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotYetValid { valid_from, now } => {
+                write!(f, "certificate valid from {}, now is {}", valid_from, now)
+            }
+            Self::Expired {
+                not_valid_after,
+                now,
+            } => write!(
+                f,
+                "certificate expired at {}, now is {}",
+                not_valid_after, now
+            ),
+            Self::UnsupportedVersion(v) => write!(f, "unsupported certificate version {}", v),
+            Self::Malformed => f.write_str("malformed certificate"),
+            Self::MalformedSignature => f.write_str("malformed certificate signature"),
+            Self::SignatureMismatch => f.write_str("certificate not signed by the authority"),
+            Self::AuthorityNotValid => {
+                f.write_str("certificate signed by an authority key that is not valid now")
+            }
+            Self::Revoked => f.write_str("certificate or server key revoked"),
+        }
+    }
+}
+
+impl std::error::Error for CertificateError {}
+
+/// Extension of a version 1 certificate, encoded as type (u16), length (u16) and value. Each
+/// type can appear at most once.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CertificateExtension {
+    pub kind: u16,
+    pub value: Vec<u8>,
+}
+
+impl CertificateExtension {
+    /// Identifier of the pool that operates the server
+    pub const POOL_ID: u16 = 1;
+    /// Hostname that the certificate is bound to
+    pub const HOSTNAME: u16 = 2;
+    /// Subprotocols that the server is allowed to speak
+    pub const SUBPROTOCOLS: u16 = 3;
+    /// X-only public key of the authority, used by initiators that trust the server on first
+    /// use
+    pub const AUTHORITY_KEY: u16 = 4;
+
+    pub fn new(kind: u16, value: impl Into<Vec<u8>>) -> Self {
+        Self {
+            kind,
+            value: value.into(),
+        }
+    }
+
+    pub fn authority_key(key: &XOnlyPublicKey) -> Self {
+        Self::new(Self::AUTHORITY_KEY, key.serialize())
+    }
+
+    /// Length of the encoded extensions, fail if they can not be sent in a certificate
+    pub(crate) fn encoded_len(extensions: &[Self]) -> Result<usize, CertificateError> {
+        let mut len = 0;
+        for (i, extension) in extensions.iter().enumerate() {
+            if extensions[..i].iter().any(|e| e.kind == extension.kind) {
+                return Err(CertificateError::Malformed);
+            }
+            len += 4 + extension.value.len();
+        }
+        if len > MAX_EXTENSIONS_LEN {
+            return Err(CertificateError::Malformed);
+        }
+        Ok(len)
+    }
+
+    /// Type and length of the encoded extension, followed by the value
+    fn type_and_len(&self) -> [u8; 4] {
+        let [t0, t1] = self.kind.to_le_bytes();
+        let [l0, l1] = (self.value.len() as u16).to_le_bytes();
+        [t0, t1, l0, l1]
+    }
+
+    fn decode(mut bytes: &[u8]) -> Result<Vec<Self>, CertificateError> {
+        let mut extensions = vec![];
+        while !bytes.is_empty() {
+            if bytes.len() < 4 {
+                return Err(CertificateError::Malformed);
+            }
+            let kind = u16::from_le_bytes([bytes[0], bytes[1]]);
+            let len = u16::from_le_bytes([bytes[2], bytes[3]]) as usize;
+            let value = bytes.get(4..4 + len).ok_or(CertificateError::Malformed)?;
+            extensions.push(Self::new(kind, value));
+            bytes = &bytes[4 + len..];
+        }
+        Self::encoded_len(&extensions)?;
+        Ok(extensions)
+    }
+}
+
+/// SIGNATURE_NOISE_MESSAGE sent by the responder, certificate signed by the authority.
+///
+/// The signature covers the version, the validity and the static public key of the responder
+/// as returned by [`crate::Dh::canonical_pub_key`], for secp256k1 the x-only key even when it is
+/// sent ElligatorSwift encoded. Version 0 is the 74 bytes message of the spec. Version 1 is
+/// followed by [`CertificateExtension`]s, the signature covers them too.
 pub struct SignatureNoiseMessage {
     pub version: u16,
     pub valid_from: u32,
     pub not_valid_after: u32,
     pub signature: [u8; 64],
+    pub extensions: Vec<CertificateExtension>,
+}
+
+impl fmt::Debug for SignatureNoiseMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SignatureNoiseMessage")
+            .field("version", &self.version)
+            .field("valid_from", &self.valid_from)
+            .field("not_valid_after", &self.not_valid_after)
+            .field("signature", &Hex(&self.signature))
+            .field("extensions", &self.extensions)
+            .finish()
+    }
 }
 
 impl From<[u8; 74]> for SignatureNoiseMessage {
@@ -19,50 +179,238 @@ impl From<[u8; 74]> for SignatureNoiseMessage {
             valid_from,
             not_valid_after,
             signature,
+            extensions: vec![],
         }
     }
 }
 
 impl SignatureNoiseMessage {
-    pub fn verify(self, pk: &XOnlyPublicKey) -> bool {
-        let now = SystemTime::now()
+    /// Unsigned certificate, version 1 if there are extensions, version 0 otherwise
+    pub fn new(
+        valid_from: u32,
+        not_valid_after: u32,
+        extensions: Vec<CertificateExtension>,
+    ) -> Result<Self, CertificateError> {
+        CertificateExtension::encoded_len(&extensions)?;
+        let version = match extensions.is_empty() {
+            true => VERSION_0,
+            false => VERSION_1,
+        };
+        Ok(Self {
+            version,
+            valid_from,
+            not_valid_after,
+            signature: [0; 64],
+            extensions,
+        })
+    }
+
+    /// Parse a certificate of a supported version
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, CertificateError> {
+        if bytes.len() < CERTIFICATE_HEADER_LEN {
+            return Err(CertificateError::Malformed);
+        }
+        let (header, extensions) = bytes.split_at(CERTIFICATE_HEADER_LEN);
+        let mut message = Self::from(<[u8; 74]>::try_from(header).unwrap());
+        match message.version {
+            VERSION_0 if extensions.is_empty() => (),
+            VERSION_0 => return Err(CertificateError::Malformed),
+            VERSION_1 => message.extensions = CertificateExtension::decode(extensions)?,
+            version => return Err(CertificateError::UnsupportedVersion(version)),
+        }
+        Ok(message)
+    }
+
+    /// Length of [`SignatureNoiseMessage::encode`]
+    pub fn encoded_len(&self) -> usize {
+        Self::len_with(&self.extensions)
+    }
+
+    fn len_with(extensions: &[CertificateExtension]) -> usize {
+        let extensions: usize = extensions.iter().map(|e| 4 + e.value.len()).sum();
+        CERTIFICATE_HEADER_LEN + extensions
+    }
+
+    /// Write the certificate in `out`, that must be at least
+    /// [`SignatureNoiseMessage::encoded_len`] bytes. Return the number of bytes written.
+    pub fn encode(&self, out: &mut [u8]) -> Result<usize, Error> {
+        Self::write(&self.header(), &self.signature, &self.extensions, out)
+    }
+
+    /// Sign a certificate with `extensions` for the responder with static public key
+    /// `static_key` and write it in `out`. Same as [`SignatureNoiseMessage::new`],
+    /// [`SignatureNoiseMessage::sign_with`] and [`SignatureNoiseMessage::encode`] without
+    /// allocating. Return the number of bytes written.
+    pub fn sign_into(
+        valid_from: u32,
+        not_valid_after: u32,
+        extensions: &[CertificateExtension],
+        static_key: &[u8],
+        kp: &Keypair,
+        out: &mut [u8],
+    ) -> Result<usize, Error> {
+        CertificateExtension::encoded_len(extensions)?;
+        let version = match extensions.is_empty() {
+            true => VERSION_0,
+            false => VERSION_1,
+        };
+        let header = Self::encode_header(version, valid_from, not_valid_after);
+        let m = Self::digest(&header, static_key, extensions);
+        let signature = SECP256K1.sign_schnorr(&m, kp).serialize();
+        Self::write(&header, &signature, extensions, out)
+    }
+
+    /// Authority key named by the [`CertificateExtension::AUTHORITY_KEY`] extension
+    pub fn authority_key(&self) -> Result<Option<XOnlyPublicKey>, CertificateError> {
+        self.extension(CertificateExtension::AUTHORITY_KEY)
+            .map(|key| XOnlyPublicKey::from_slice(key).map_err(|_| CertificateError::Malformed))
+            .transpose()
+    }
+
+    /// SHA-256 of the encoded certificate, used to revoke it
+    pub fn hash(&self) -> [u8; 32] {
+        let mut engine = sha256::Hash::engine();
+        engine.input(&self.header());
+        engine.input(&self.signature);
+        for extension in &self.extensions {
+            engine.input(&extension.type_and_len());
+            engine.input(&extension.value);
+        }
+        sha256::Hash::from_engine(engine).to_byte_array()
+    }
+
+    /// Sign the certificate of the responder with static public key `static_key` with the
+    /// authority keypair, `static_key` is in the form of [`crate::Dh::canonical_pub_key`]
+    pub fn sign_with(&mut self, static_key: &[u8], kp: &Keypair) {
+        let m = Self::digest(&self.header(), static_key, &self.extensions);
+        self.signature = SECP256K1.sign_schnorr(&m, kp).serialize();
+    }
+
+    /// Value of the extension of type `kind`
+    pub fn extension(&self, kind: u16) -> Option<&[u8]> {
+        self.extensions
+            .iter()
+            .find(|e| e.kind == kind)
+            .map(|e| &e.value[..])
+    }
+
+    /// Check that the certificate is valid now and that it is signed by `pk` for the responder
+    /// with static public key `static_key`, in the form of [`crate::Dh::canonical_pub_key`]
+    pub fn verify(&self, pk: &XOnlyPublicKey, static_key: &[u8]) -> Result<(), CertificateError> {
+        self.verify_at(pk, static_key, Self::now())
+    }
+
+    /// Same as [`SignatureNoiseMessage::verify`] with `now` seconds since the unix epoch
+    pub fn verify_at(
+        &self,
+        pk: &XOnlyPublicKey,
+        static_key: &[u8],
+        now: u32,
+    ) -> Result<(), CertificateError> {
+        self.check_validity_at(now)?;
+        // r must be the x coordinate of a point and s lower than the curve order
+        let s = self.signature;
+        if XOnlyPublicKey::from_slice(&s[..32]).is_err() || s[32..] >= CURVE_ORDER[..] {
+            return Err(CertificateError::MalformedSignature);
+        }
+        let m = Self::digest(&self.header(), static_key, &self.extensions);
+        let s = Signature::from_slice(&s).map_err(|_| CertificateError::MalformedSignature)?;
+        SECP256K1
+            .verify_schnorr(&s, &m, pk)
+            .map_err(|_| CertificateError::SignatureMismatch)
+    }
+
+    /// Check the version, the extensions and that `now` is in the validity period, without
+    /// checking the signature
+    pub fn check_validity_at(&self, now: u32) -> Result<(), CertificateError> {
+        match self.version {
+            VERSION_0 if !self.extensions.is_empty() => return Err(CertificateError::Malformed),
+            VERSION_0 => (),
+            VERSION_1 => {
+                CertificateExtension::encoded_len(&self.extensions)?;
+            }
+            version => return Err(CertificateError::UnsupportedVersion(version)),
+        }
+        if now < self.valid_from {
+            return Err(CertificateError::NotYetValid {
+                valid_from: self.valid_from,
+                now,
+            });
+        }
+        if now > self.not_valid_after {
+            return Err(CertificateError::Expired {
+                not_valid_after: self.not_valid_after,
+                now,
+            });
+        }
+        Ok(())
+    }
+
+    /// Seconds since the unix epoch
+    pub(crate) fn now() -> u32 {
+        SystemTime::now()
             .duration_since(SystemTime::UNIX_EPOCH)
             .unwrap()
-            .as_secs() as u32;
-        if self.valid_from <= now && self.not_valid_after >= now {
-            let secp = Secp256k1::verification_only();
-            let (m, s) = self.split();
-            let m = Message::from_hashed_data::<sha256::Hash>(&m[0..10]);
-            let s = match Signature::from_slice(&s) {
-                Ok(s) => s,
-                _ => return false,
-            };
-            secp.verify_schnorr(&s, &m, pk).is_ok()
-        } else {
-            false
-        }
-    }
-    pub fn sign(msg: &mut [u8; 74], kp: &KeyPair) {
-        let secp = Secp256k1::signing_only();
-        let m = Message::from_hashed_data::<sha256::Hash>(&msg[0..10]);
-        let signature = secp.sign_schnorr(&m, kp);
-        for (i, b) in signature.as_ref().iter().enumerate() {
-            msg[10 + i] = *b;
-        }
-    }
-
-    fn split(self) -> ([u8; 10], [u8; 64]) {
-        let mut m = [0; 10];
-        m[0] = self.version.to_le_bytes()[0];
-        m[1] = self.version.to_le_bytes()[1];
-        m[2] = self.valid_from.to_le_bytes()[0];
-        m[3] = self.valid_from.to_le_bytes()[1];
-        m[4] = self.valid_from.to_le_bytes()[2];
-        m[5] = self.valid_from.to_le_bytes()[3];
-        m[6] = self.not_valid_after.to_le_bytes()[0];
-        m[7] = self.not_valid_after.to_le_bytes()[1];
-        m[8] = self.not_valid_after.to_le_bytes()[2];
-        m[9] = self.not_valid_after.to_le_bytes()[3];
-        (m, self.signature)
+            .as_secs() as u32
+    }
+
+    /// Sign an encoded version 0 certificate for the responder with static public key
+    /// `static_key`
+    pub fn sign(msg: &mut [u8; 74], static_key: &[u8], kp: &Keypair) {
+        let header = msg[0..10].try_into().unwrap();
+        let m = Self::digest(&header, static_key, &[]);
+        let signature = SECP256K1.sign_schnorr(&m, kp);
+        msg[10..].copy_from_slice(signature.as_ref());
+    }
+
+    /// Hash of the signed data: version, validity, static public key and encoded extensions
+    fn digest(
+        header: &[u8; 10],
+        static_key: &[u8],
+        extensions: &[CertificateExtension],
+    ) -> Message {
+        let mut engine = sha256::Hash::engine();
+        engine.input(header);
+        engine.input(static_key);
+        for extension in extensions {
+            engine.input(&extension.type_and_len());
+            engine.input(&extension.value);
+        }
+        Message::from_digest(sha256::Hash::from_engine(engine).to_byte_array())
+    }
+
+    /// Write the header, the signature and the extensions in `out`
+    fn write(
+        header: &[u8; 10],
+        signature: &[u8; 64],
+        extensions: &[CertificateExtension],
+        out: &mut [u8],
+    ) -> Result<usize, Error> {
+        let len = Self::len_with(extensions);
+        if out.len() < len {
+            return Err(ErrorKind::OutputBufferTooSmall(len).into());
+        }
+        out[..10].copy_from_slice(header);
+        out[10..CERTIFICATE_HEADER_LEN].copy_from_slice(signature);
+        let mut offset = CERTIFICATE_HEADER_LEN;
+        for extension in extensions {
+            out[offset..offset + 4].copy_from_slice(&extension.type_and_len());
+            offset += 4;
+            out[offset..offset + extension.value.len()].copy_from_slice(&extension.value);
+            offset += extension.value.len();
+        }
+        Ok(len)
+    }
+
+    fn header(&self) -> [u8; 10] {
+        Self::encode_header(self.version, self.valid_from, self.not_valid_after)
+    }
+
+    fn encode_header(version: u16, valid_from: u32, not_valid_after: u32) -> [u8; 10] {
+        let mut header = [0; 10];
+        header[0..2].copy_from_slice(&version.to_le_bytes());
+        header[2..6].copy_from_slice(&valid_from.to_le_bytes());
+        header[6..10].copy_from_slice(&not_valid_after.to_le_bytes());
+        header
     }
 }
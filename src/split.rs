@@ -0,0 +1,159 @@
+use std::fmt;
+use std::io::IoSlice;
+use std::sync::Arc;
+
+use aes_gcm::aead::Buffer;
+
+use crate::aed_cipher::{AeadCipher, DynCipher};
+use crate::cipher_state::{Cipher, CipherState};
+use crate::NoiseCodec;
+
+/// Encrypting half of a [`NoiseCodec`], see [`NoiseCodec::split`]
+pub struct NoiseSender<C: AeadCipher = DynCipher> {
+    encryptor: Cipher<C>,
+    // Shared with the receiver, used to check that the halves come from the same codec
+    pair: Arc<()>,
+}
+
+/// Decrypting half of a [`NoiseCodec`], see [`NoiseCodec::split`]
+pub struct NoiseReceiver<C: AeadCipher = DynCipher> {
+    decryptor: Cipher<C>,
+    pair: Arc<()>,
+}
+
+/// Returned by [`NoiseSender::reunite`] when the halves do not come from the same codec
+pub struct ReuniteError<C: AeadCipher = DynCipher>(pub NoiseSender<C>, pub NoiseReceiver<C>);
+
+impl<C: AeadCipher> fmt::Debug for NoiseSender<C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NoiseSender")
+            .field("encryptor", &self.encryptor)
+            .finish()
+    }
+}
+
+impl<C: AeadCipher> fmt::Debug for NoiseReceiver<C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NoiseReceiver")
+            .field("decryptor", &self.decryptor)
+            .finish()
+    }
+}
+
+impl<C: AeadCipher> fmt::Debug for ReuniteError<C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("ReuniteError")
+            .field(&self.0)
+            .field(&self.1)
+            .finish()
+    }
+}
+
+impl<C: AeadCipher> NoiseCodec<C> {
+    /// Split the codec so that messages can be encrypted and decrypted from different threads
+    pub fn split(self) -> (NoiseSender<C>, NoiseReceiver<C>) {
+        let pair = Arc::new(());
+        let sender = NoiseSender {
+            encryptor: self.encryptor,
+            pair: pair.clone(),
+        };
+        let receiver = NoiseReceiver {
+            decryptor: self.decryptor,
+            pair,
+        };
+        (sender, receiver)
+    }
+}
+
+impl<C: AeadCipher> NoiseSender<C> {
+    /// Same as [`NoiseCodec::encrypt`]
+    pub fn encrypt<T: Buffer>(&mut self, msg: &mut T) -> Result<(), aes_gcm::Error> {
+        self.encryptor.encrypt_with_ad(&[], msg)
+    }
+
+    /// Same as [`NoiseCodec::encrypt_slice`]
+    pub fn encrypt_slice(&mut self, buf: &mut [u8], len: usize) -> Result<usize, aes_gcm::Error> {
+        self.encryptor.encrypt_with_ad_slice(&[], buf, len)
+    }
+
+    /// Same as [`NoiseCodec::encrypt_detached`]
+    pub fn encrypt_detached(&mut self, buf: &mut [u8]) -> Result<[u8; 16], aes_gcm::Error> {
+        self.encryptor.encrypt_with_ad_detached(&[], buf)
+    }
+
+    /// Same as [`NoiseCodec::encrypt_to`]
+    pub fn encrypt_to(&mut self, msg: &[u8], out: &mut [u8]) -> Result<usize, aes_gcm::Error> {
+        self.encryptor.encrypt_with_ad_to(&[], msg, out)
+    }
+
+    /// Same as [`NoiseCodec::encrypt_batch`]
+    pub fn encrypt_batch(
+        &mut self,
+        msgs: &[IoSlice<'_>],
+        out: &mut [u8],
+    ) -> Result<usize, aes_gcm::Error> {
+        self.encryptor.encrypt_with_ad_batch(&[], msgs, out)
+    }
+
+    /// Same as [`NoiseCodec::encrypt_vectored`]
+    pub fn encrypt_vectored(
+        &mut self,
+        parts: &[IoSlice<'_>],
+        out: &mut [u8],
+    ) -> Result<usize, aes_gcm::Error> {
+        self.encryptor.encrypt_with_ad_vectored(&[], parts, out)
+    }
+
+    /// Join the halves returned by [`NoiseCodec::split`], fail if they come from different
+    /// codecs
+    pub fn reunite(self, receiver: NoiseReceiver<C>) -> Result<NoiseCodec<C>, ReuniteError<C>> {
+        if Arc::ptr_eq(&self.pair, &receiver.pair) {
+            Ok(NoiseCodec {
+                encryptor: self.encryptor,
+                decryptor: receiver.decryptor,
+            })
+        } else {
+            Err(ReuniteError(self, receiver))
+        }
+    }
+}
+
+impl<C: AeadCipher> NoiseReceiver<C> {
+    /// Same as [`NoiseCodec::decrypt`]
+    pub fn decrypt<T: Buffer>(&mut self, msg: &mut T) -> Result<(), aes_gcm::Error> {
+        self.decryptor.decrypt_with_ad(&[], msg)
+    }
+
+    /// Same as [`NoiseCodec::decrypt_slice`]
+    pub fn decrypt_slice(&mut self, buf: &mut [u8]) -> Result<usize, aes_gcm::Error> {
+        self.decryptor.decrypt_with_ad_slice(&[], buf)
+    }
+
+    /// Same as [`NoiseCodec::decrypt_detached`]
+    pub fn decrypt_detached(
+        &mut self,
+        buf: &mut [u8],
+        mac: &[u8; 16],
+    ) -> Result<(), aes_gcm::Error> {
+        self.decryptor.decrypt_with_ad_detached(&[], buf, mac)
+    }
+
+    /// Same as [`NoiseCodec::decrypt_to`]
+    pub fn decrypt_to(&mut self, msg: &[u8], out: &mut [u8]) -> Result<usize, aes_gcm::Error> {
+        self.decryptor.decrypt_with_ad_to(&[], msg, out)
+    }
+
+    /// Same as [`NoiseCodec::decrypt_batch`]
+    pub fn decrypt_batch(
+        &mut self,
+        buf: &mut [u8],
+        lens: &mut [usize],
+    ) -> Result<usize, aes_gcm::Error> {
+        self.decryptor.decrypt_with_ad_batch(&[], buf, lens)
+    }
+
+    /// Same as [`NoiseSender::reunite`]
+    pub fn reunite(self, sender: NoiseSender<C>) -> Result<NoiseCodec<C>, ReuniteError<C>> {
+        sender.reunite(self)
+    }
+}
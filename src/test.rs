@@ -1,21 +1,1502 @@
-use crate::{handshake::HandshakeOp, initiator::Initiator, responder::Responder};
-use chacha20poly1305::ChaCha20Poly1305;
+use crate::{aed_cipher::AeadCipher, cipher_state::Cipher, handshake::HandshakeOp};
+use crate::{
+    error::{Error, ErrorKind, Phase, Role},
+    initiator::Initiator,
+    responder::Responder,
+    signature_message::{CertificateError, CertificateExtension, SignatureNoiseMessage},
+    Dh, EcdhOutput, ProtocolVersion, Secp256k1, Secp256k1KeyPair, X25519KeyPair, MAX_DH_LEN,
+    X25519,
+};
+use crate::{
+    has_aes_hardware, AuthorityTrustStore, Blake2b, Blake2s, CipherCode, CipherRegistry, DynCipher,
+    InitiatorConfig, KnownServers, NoiseCodec, NoiseHash, NoiseReceiver, NoiseSender, ProtocolName,
+    ResponderConfig, ReuniteError, Revocation, RevocationList, RevocationSet, Sha256, Sha512,
+    TransportCipher, TrustedAuthority, AESG, ASIV, MAX_EXTENSIONS_LEN, XCHA,
+};
+use aes_gcm::{Aes128Gcm, Aes256Gcm, KeyInit};
+use aes_gcm_siv::Aes256GcmSiv;
+use chacha20poly1305::{ChaCha20Poly1305, XChaCha20Poly1305};
+use secp256k1::{Keypair, SECP256K1};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::fmt;
+use std::io::IoSlice;
+
+/// `CIPHER_CHOICE` with the initiator and responder codecs
+type Negotiated<I = DynCipher, R = DynCipher> = (Vec<u8>, NoiseCodec<I>, NoiseCodec<R>);
+
+/// Steps 0 to 2, the initiator authenticates the responder. Return the `AEAD_CIPHERS` list of
+/// the initiator.
+fn authenticate<I, R, D, H>(
+    initiator: &mut Initiator<I, D, H>,
+    responder: &mut Responder<R, D, H>,
+) -> Result<Vec<u8>, Error>
+where
+    I: TransportCipher,
+    R: TransportCipher,
+    D: Dh,
+    H: NoiseHash,
+{
+    let first_message = initiator.step_0()?;
+    let second_message = responder.step_1(first_message, 60)?;
+    initiator.step_2(second_message)
+}
+
+/// Whole handshake between `initiator` and `responder`
+fn handshake<I, R, D, H>(
+    mut initiator: Box<Initiator<I, D, H>>,
+    mut responder: Box<Responder<R, D, H>>,
+) -> Result<Negotiated<I, R>, Error>
+where
+    I: TransportCipher,
+    R: TransportCipher,
+    D: Dh,
+    H: NoiseHash,
+{
+    let cipher_list = authenticate(&mut initiator, &mut responder)?;
+    let (cipher_choice, codec_responder) = responder.step_3(cipher_list)?;
+    let codec_initiator = initiator.step_4(cipher_choice.clone())?;
+    Ok((cipher_choice, codec_initiator, codec_responder))
+}
+
+/// Initiator and responder that share a fresh key pair as static and authority key
+fn peers<I: TransportCipher, R: TransportCipher>(
+    initiator_config: InitiatorConfig,
+    responder_config: ResponderConfig,
+) -> (Box<Initiator<I>>, Box<Responder<R>>) {
+    let key_pair = Secp256k1::generate_key();
+    let mut initiator = Initiator::<I>::new(key_pair.public_key().into());
+    let mut responder = Responder::<R>::new(key_pair);
+    initiator.set_config(initiator_config);
+    responder.set_config(responder_config);
+    (initiator, responder)
+}
+
+fn negotiate(
+    initiator_config: InitiatorConfig,
+    responder_config: ResponderConfig,
+) -> Result<Negotiated, Error> {
+    let (initiator, responder) = peers(initiator_config, responder_config);
+    handshake(initiator, responder)
+}
+
+/// Send a message each way between the codecs of the two sides
+fn check_codecs<I: TransportCipher, R: TransportCipher>(
+    codec_initiator: &mut NoiseCodec<I>,
+    codec_responder: &mut NoiseCodec<R>,
+) {
+    let mut message = b"ciao".to_vec();
+    codec_initiator.encrypt(&mut message).unwrap();
+    assert!(message.len() == 4 + 16 && message[..4] != *b"ciao");
+    codec_responder.decrypt(&mut message).unwrap();
+    assert!(message == b"ciao");
+    codec_responder.encrypt(&mut message).unwrap();
+    codec_initiator.decrypt(&mut message).unwrap();
+    assert!(message == b"ciao");
+}
 
 #[test]
 fn test_1() {
-    let key_pair = Responder::<ChaCha20Poly1305>::generate_key();
+    let key_pair = Secp256k1::generate_key();
+    let initiator = Initiator::<ChaCha20Poly1305>::new(key_pair.public_key().into());
+    let responder = Responder::<ChaCha20Poly1305>::new(key_pair);
+    let (_, mut codec_initiator, mut codec_responder) = handshake(initiator, responder).unwrap();
+    check_codecs(&mut codec_initiator, &mut codec_responder);
+}
+
+/// Allocator that counts the allocations of each thread, the tests run in parallel
+struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+impl CountingAllocator {
+    fn count() {
+        let _ = ALLOCATIONS.try_with(|n| n.set(n.get() + 1));
+    }
+
+    /// Number of allocations made by the current thread while running `f`
+    fn allocations<T>(f: impl FnOnce() -> T) -> (usize, T) {
+        let before = ALLOCATIONS.with(Cell::get);
+        let result = f();
+        (ALLOCATIONS.with(Cell::get) - before, result)
+    }
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        Self::count();
+        System.alloc(layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        Self::count();
+        System.alloc_zeroed(layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        Self::count();
+        System.realloc(ptr, layout, new_size)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+/// Handshake through the slice API, return the initiator and responder codecs
+fn slice_handshake<C: TransportCipher>(
+    mut initiator: Box<Initiator<C>>,
+    mut responder: Box<Responder<C>>,
+) -> (NoiseCodec<C>, NoiseCodec<C>) {
+    let mut buf = [0; 170];
+    let len = initiator.step_0_slice(&mut buf).unwrap();
+    let mut second_message = [0; 170];
+    let len = responder
+        .step_1_slice(&buf[..len], 31449600, &mut second_message)
+        .unwrap();
+    let len = initiator
+        .step_2_slice(&second_message[..len], &mut buf)
+        .unwrap();
+    let mut cipher_choice = [0; 5];
+    let (len, codec_responder) = responder
+        .step_3_slice(&buf[..len], &mut cipher_choice)
+        .unwrap();
+    let codec_initiator = initiator.step_4_slice(&cipher_choice[..len]).unwrap();
+    (codec_initiator, codec_responder)
+}
+
+#[test]
+fn test_handshake_with_slices() {
+    let (initiator, responder) = peers::<ChaCha20Poly1305, ChaCha20Poly1305>(
+        InitiatorConfig::default(),
+        ResponderConfig::default(),
+    );
+    let (mut codec_initiator, mut codec_responder) = slice_handshake(initiator, responder);
+
+    let mut message = [0; 4 + 16];
+    message[..4].copy_from_slice(b"ciao");
+    let len = codec_initiator.encrypt_slice(&mut message, 4).unwrap();
+    assert!(len == message.len());
+    assert!(&message[..4] != b"ciao");
+    let len = codec_responder.decrypt_slice(&mut message).unwrap();
+    assert!(&message[..len] == b"ciao");
+
+    let mut too_small = [0; 4 + 15];
+    assert!(codec_responder.encrypt_slice(&mut too_small, 4).is_err());
+    assert!(codec_responder
+        .encrypt_slice(&mut too_small, usize::MAX)
+        .is_err());
+}
+
+#[test]
+fn test_slice_api_does_not_allocate() {
+    let (initiator, responder) = peers::<ChaCha20Poly1305, ChaCha20Poly1305>(
+        InitiatorConfig::default(),
+        ResponderConfig::default(),
+    );
+    // the first use of the random number generator of the thread allocates
+    slice_handshake(initiator, responder);
+
+    let (initiator, responder) = peers::<ChaCha20Poly1305, ChaCha20Poly1305>(
+        InitiatorConfig::default(),
+        ResponderConfig::default(),
+    );
+    let mut message = [0; 4 + 16];
+    message[..4].copy_from_slice(b"ciao");
+    let (allocations, len) = CountingAllocator::allocations(|| {
+        let (mut codec_initiator, mut codec_responder) = slice_handshake(initiator, responder);
+        codec_initiator.encrypt_slice(&mut message, 4).unwrap();
+        codec_responder.decrypt_slice(&mut message).unwrap()
+    });
+    assert!(message[..len] == *b"ciao");
+    assert!(allocations == 0);
+}
+
+#[test]
+fn test_ellswift_handshake() {
+    let key_pair = Secp256k1::generate_key();
+    let version = ProtocolVersion::EllSwift;
+    let dh = Secp256k1::new(version);
+    assert!(dh.first_message_len() == 64 && dh.second_message_len() == 234);
+
+    let initiator =
+        Initiator::<ChaCha20Poly1305>::new_with_version(key_pair.public_key().into(), version);
+    let responder = Responder::<ChaCha20Poly1305>::new_with_version(key_pair, version);
+    let (_, mut codec_initiator, mut codec_responder) = handshake(initiator, responder).unwrap();
+    check_codecs(&mut codec_initiator, &mut codec_responder);
+}
+
+#[test]
+fn test_protocol_version_mismatch() {
+    let key_pair = Secp256k1::generate_key();
+
+    let mut initiator = Initiator::<ChaCha20Poly1305>::new(key_pair.public_key().into());
+    let mut responder =
+        Responder::<ChaCha20Poly1305>::new_with_version(key_pair, ProtocolVersion::EllSwift);
+    let error = authenticate(&mut initiator, &mut responder).unwrap_err();
+    assert!(error.role() == Some(Role::Responder));
+    assert!(matches!(error.kind(), ErrorKind::InvalidMessageLength(32)));
+}
+
+#[test]
+fn test_x_coordinate_handshake() {
+    let version = ProtocolVersion::XOnly(EcdhOutput::XCoordinate);
+    let key_pair = Secp256k1::generate_key();
+
+    let initiator =
+        Initiator::<ChaCha20Poly1305>::new_with_version(key_pair.public_key().into(), version);
+    let responder = Responder::<ChaCha20Poly1305>::new_with_version(key_pair, version);
+    assert!(handshake(initiator, responder).is_ok());
+}
+
+#[test]
+fn test_ecdh_output_mismatch() {
+    let key_pair = Secp256k1::generate_key();
 
     let mut initiator = Initiator::<ChaCha20Poly1305>::new(key_pair.public_key().into());
-    let mut responder = Responder::<ChaCha20Poly1305>::new(key_pair);
+    let mut responder = Responder::<ChaCha20Poly1305>::new_with_version(
+        key_pair,
+        ProtocolVersion::XOnly(EcdhOutput::XCoordinate),
+    );
+    let error = authenticate(&mut initiator, &mut responder).unwrap_err();
+    assert!(error.role() == Some(Role::Initiator));
+    assert!(error.phase() == Some(Phase::StaticKey));
+    assert!(matches!(error.kind(), ErrorKind::DecryptionFailed));
+    assert!(error.to_string() == "initiator failed at static key: decryption failed");
+}
+
+/// secp256k1 DH with a fixed ephemeral key, to check a handshake against a transcript computed
+/// by an independent implementation
+struct FixedEphemeral {
+    dh: Secp256k1,
+    e: &'static str,
+}
+
+impl FixedEphemeral {
+    fn keypair(&self, secret: &str) -> Secp256k1KeyPair {
+        let secret = hex::decode(secret).unwrap();
+        self.dh
+            .keypair(Keypair::from_seckey_slice(SECP256K1, &secret).unwrap())
+    }
+}
+
+impl Dh for FixedEphemeral {
+    type KeyPair = Secp256k1KeyPair;
+
+    fn name(&self) -> &'static str {
+        self.dh.name()
+    }
+
+    fn pub_key_len(&self) -> usize {
+        self.dh.pub_key_len()
+    }
+
+    fn dh_len(&self) -> usize {
+        self.dh.dh_len()
+    }
+
+    fn generate_keypair(&self) -> Self::KeyPair {
+        self.keypair(self.e)
+    }
+
+    fn pub_key<'a>(&self, keypair: &'a Self::KeyPair) -> &'a [u8] {
+        self.dh.pub_key(keypair)
+    }
+
+    fn canonical_pub_key(&self, pub_key: &[u8]) -> Result<[u8; 32], Error> {
+        self.dh.canonical_pub_key(pub_key)
+    }
+
+    fn dh(
+        &self,
+        keypair: &Self::KeyPair,
+        remote: &[u8],
+        initiator: bool,
+        out: &mut [u8; MAX_DH_LEN],
+    ) -> Result<(), Error> {
+        self.dh.dh(keypair, remote, initiator, out)
+    }
+}
+
+#[test]
+fn test_handshake_transcript() {
+    // Secret keys with even parity: initiator ephemeral, responder ephemeral and static
+    let ei = "9df41ce7330164e97a45d6ab5db3e4a6c7e01c0130ea7230d80a2ca3b32210a2";
+    let er = "b0a64df5d18f107bc3767eb5a4ea37ad327f1036dc48e25ac7aca87fc0a54b93";
+    let s = "c4763aaa3c819126c5a5859dd94fd5c4933f3371d89f3c8af2fc09dc8cd068a7";
+    // Responder ephemeral key and encrypted static key, first transport message of each side
+    let vectors = [
+        (
+            EcdhOutput::SharedPoint,
+            "2cf9fb23b066ebf5ad891fbee82f7243e9cc788ef0cbf00ead69a1234bde8df5\
+             a5bc0e63aa38d28c250b4bd50dd3e2aa4f3c34251cf275d6371a8510a5f32cf5\
+             1fe141202976030252f3056ef26c4253",
+            "77bbaa15b8613577ab368adc543ce626b9aac51b",
+            "8811c2b4bc210236934e79cc2cfa3c142c725664",
+        ),
+        (
+            EcdhOutput::XCoordinate,
+            "2cf9fb23b066ebf5ad891fbee82f7243e9cc788ef0cbf00ead69a1234bde8df5\
+             2bbc58e8fafa762d8381bc7ccec38d45d2cd13c0831189aede275bd9050f3591\
+             5bd336d3e80d2d920d54e7c6dee2766b",
+            "44426c81253b4161d02553bc0f15ee5322433f21",
+            "7ea423f105be4ffd7506467059ec3b20c6ebbaad",
+        ),
+    ];
+    for (output, second_message, ciao, hola) in vectors {
+        let dh = Secp256k1::new(ProtocolVersion::XOnly(output));
+        let authority = Secp256k1::generate_key();
+        let initiator_dh = FixedEphemeral { dh, e: ei };
+        let responder_dh = FixedEphemeral { dh, e: er };
+        let s = responder_dh.keypair(s);
+        let mut initiator = Initiator::<ChaCha20Poly1305, FixedEphemeral>::with_dh(
+            authority.x_only_public_key().0,
+            initiator_dh,
+        );
+        let mut responder =
+            Responder::<ChaCha20Poly1305, FixedEphemeral>::with_dh(s, authority, responder_dh);
+        let first_message = initiator.step_0().unwrap();
+        let message = responder.step_1(first_message, 60).unwrap();
+        assert!(hex::encode(&message[..80]) == second_message);
+        initiator.step_2(message).unwrap();
+        let (cipher_choice, mut codec_responder) = responder.step_3(vec![0]).unwrap();
+        let mut codec_initiator = initiator.step_4(cipher_choice).unwrap();
+
+        let mut message = b"ciao".to_vec();
+        codec_initiator.encrypt(&mut message).unwrap();
+        assert!(hex::encode(&message) == ciao);
+        let mut message = b"hola".to_vec();
+        codec_responder.encrypt(&mut message).unwrap();
+        assert!(hex::encode(&message) == hola);
+    }
+}
+
+fn x25519_keypair() -> X25519KeyPair {
+    X25519KeyPair::from_secret(rand::random())
+}
+
+#[test]
+fn test_x25519_handshake() {
+    let authority = Secp256k1::generate_key();
+    assert!(X25519.first_message_len() == 32 && X25519.second_message_len() == 170);
+
+    let initiator =
+        Initiator::<ChaCha20Poly1305, X25519>::with_dh(authority.public_key().into(), X25519);
+    let responder =
+        Responder::<ChaCha20Poly1305, X25519>::with_dh(x25519_keypair(), authority, X25519);
+    let (_, mut codec_initiator, mut codec_responder) = handshake(initiator, responder).unwrap();
+    check_codecs(&mut codec_initiator, &mut codec_responder);
+}
+
+#[test]
+fn test_x25519_responder_with_snow_initiator() {
+    let authority = Secp256k1::generate_key();
+    let mut initiator = snow::Builder::new("Noise_NX_25519_ChaChaPoly_SHA256".parse().unwrap())
+        .build_initiator()
+        .unwrap();
+    let mut responder =
+        Responder::<ChaCha20Poly1305, X25519>::with_dh(x25519_keypair(), authority, X25519);
+
+    let mut first_message = [0; 64];
+    let len = initiator.write_message(&[], &mut first_message).unwrap();
+    let second_message = responder
+        .step_1(first_message[..len].to_vec(), 31449600)
+        .unwrap();
+    let mut payload = [0; 170];
+    let len = initiator
+        .read_message(&second_message, &mut payload)
+        .unwrap();
+    assert!(len == 74);
+    let signature_message: SignatureNoiseMessage =
+        <[u8; 74]>::try_from(&payload[..74]).unwrap().into();
+    let authority = authority.public_key().into();
+    let static_key = initiator.get_remote_static().unwrap();
+    assert!(signature_message.verify(&authority, static_key).is_ok());
+
+    // Keep ChaCha so that both sides use the keys returned by Split()
+    let (_, mut codec) = responder.step_3(vec![0]).unwrap();
+    let mut initiator = initiator.into_transport_mode().unwrap();
+
+    for _ in 0..3 {
+        let mut message = [0; 4 + 16];
+        let len = initiator.write_message(b"ciao", &mut message).unwrap();
+        let len = codec.decrypt_slice(&mut message[..len]).unwrap();
+        assert!(&message[..len] == b"ciao");
+
+        let mut message = b"hola".to_vec();
+        codec.encrypt(&mut message).unwrap();
+        let mut plaintext = [0; 4];
+        let len = initiator.read_message(&message, &mut plaintext).unwrap();
+        assert!(&plaintext[..len] == b"hola");
+    }
+}
+
+fn x25519_initiator_with_snow_responder<H: NoiseHash>() {
+    let authority = Secp256k1::generate_key();
+    let static_key: [u8; 32] = rand::random();
+    let static_pub_key = X25519
+        .pub_key(&X25519KeyPair::from_secret(static_key))
+        .to_vec();
+    let name = format!("Noise_NX_25519_ChaChaPoly_{}", H::NAME);
+    let mut responder = snow::Builder::new(name.parse().unwrap())
+        .local_private_key(&static_key)
+        .build_responder()
+        .unwrap();
+    let mut initiator =
+        Initiator::<ChaCha20Poly1305, X25519, H>::with_dh(authority.public_key().into(), X25519);
+
     let first_message = initiator.step_0().unwrap();
-    let second_message = responder.step_1(first_message, 31449600).unwrap();
-    let thirth_message = initiator.step_2(second_message).unwrap();
-    let (fourth_message, mut codec_responder) = responder.step_3(thirth_message.to_vec()).unwrap();
-    let mut codec_initiator = initiator.step_4(fourth_message).unwrap();
-    let mut message = "ciao".as_bytes().to_vec();
+    let mut buf = [0; 170];
+    responder.read_message(&first_message, &mut buf).unwrap();
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as u32;
+    let mut signature_message = [0; 74];
+    signature_message[2..6].copy_from_slice(&now.to_le_bytes());
+    signature_message[6..10].copy_from_slice(&(now + 3600).to_le_bytes());
+    SignatureNoiseMessage::sign(&mut signature_message, &static_pub_key, &authority);
+    let len = responder
+        .write_message(&signature_message, &mut buf)
+        .unwrap();
+    assert!(len == 170);
+    initiator.step_2(buf.to_vec()).unwrap();
+
+    let mut codec = initiator.step_4(vec![0]).unwrap();
+    let mut responder = responder.into_transport_mode().unwrap();
+
+    for _ in 0..3 {
+        let mut message = b"ciao".to_vec();
+        codec.encrypt(&mut message).unwrap();
+        let mut plaintext = [0; 4];
+        let len = responder.read_message(&message, &mut plaintext).unwrap();
+        assert!(&plaintext[..len] == b"ciao");
+
+        let mut message = [0; 4 + 16];
+        let len = responder.write_message(b"hola", &mut message).unwrap();
+        let len = codec.decrypt_slice(&mut message[..len]).unwrap();
+        assert!(&message[..len] == b"hola");
+    }
+}
+
+#[test]
+fn test_x25519_initiator_with_snow_responder() {
+    x25519_initiator_with_snow_responder::<Sha256>();
+    x25519_initiator_with_snow_responder::<Sha512>();
+    x25519_initiator_with_snow_responder::<Blake2s>();
+    x25519_initiator_with_snow_responder::<Blake2b>();
+}
+
+#[test]
+fn test_blake2s_handshake() {
+    let key_pair = Secp256k1::generate_key();
+    let dh = Secp256k1::default();
+
+    let initiator = Initiator::<ChaCha20Poly1305, Secp256k1, Blake2s>::with_dh(
+        key_pair.public_key().into(),
+        dh,
+    );
+    let responder = Responder::<ChaCha20Poly1305, Secp256k1, Blake2s>::with_dh(
+        dh.keypair(key_pair),
+        key_pair,
+        dh,
+    );
+    let (_, mut codec_initiator, mut codec_responder) = handshake(initiator, responder).unwrap();
+    check_codecs(&mut codec_initiator, &mut codec_responder);
+}
+
+#[test]
+fn test_handshake_from_protocol_name() {
+    let key_pair = Secp256k1::generate_key();
+    let dh = Secp256k1::default();
+    let name: ProtocolName = crate::PROTOCOL_NAME_CHACHA.parse().unwrap();
+
+    let initiator =
+        Initiator::<ChaCha20Poly1305>::with_protocol_name(key_pair.public_key().into(), dh, &name)
+            .unwrap();
+    let responder = Responder::<ChaCha20Poly1305>::with_protocol_name(
+        dh.keypair(key_pair),
+        key_pair,
+        dh,
+        &name,
+    )
+    .unwrap();
+    handshake(initiator, responder).unwrap();
+
+    for name in [
+        crate::PROTOCOL_NAME_AES,
+        crate::PROTOCOL_NAME_ELLSWIFT_CHACHA,
+        "Noise_NX_secp256k1_ChaChaPoly_BLAKE2s",
+    ] {
+        let name: ProtocolName = name.parse().unwrap();
+        match Initiator::<ChaCha20Poly1305>::with_protocol_name(
+            key_pair.public_key().into(),
+            dh,
+            &name,
+        )
+        .map_err(Error::into_kind)
+        {
+            Err(ErrorKind::UnsupportedProtocolName(n)) => assert!(n == name.to_string()),
+            _ => panic!("{} must not be accepted", name),
+        }
+    }
+}
+
+const A128: CipherCode = *b"A128";
+
+fn aes_128_gcm(k: [u8; 32]) -> DynCipher {
+    DynCipher(Box::new(Aes128Gcm::new_from_slice(&k[..16]).unwrap()))
+}
+
+#[test]
+fn test_registered_cipher_negotiation() {
+    let mut initiator_ciphers = CipherRegistry::new();
+    initiator_ciphers.register_with(A128, aes_128_gcm).unwrap();
+    initiator_ciphers.register::<Aes256Gcm>(AESG).unwrap();
+
+    // responder preference wins
+    let mut responder_ciphers = CipherRegistry::default();
+    responder_ciphers.register_with(A128, aes_128_gcm).unwrap();
+    let (cipher_choice, _, _) = negotiate(
+        InitiatorConfig::new(initiator_ciphers.clone()),
+        ResponderConfig::new(responder_ciphers),
+    )
+    .unwrap();
+    assert!(cipher_choice == [1, 0x47, 0x53, 0x45, 0x41]);
+
+    let mut responder_ciphers = CipherRegistry::new();
+    responder_ciphers.register_with(A128, aes_128_gcm).unwrap();
+    let (cipher_choice, mut codec_initiator, mut codec_responder) = negotiate(
+        InitiatorConfig::new(initiator_ciphers),
+        ResponderConfig::new(responder_ciphers),
+    )
+    .unwrap();
+    assert!(cipher_choice == [1, b'A', b'1', b'2', b'8']);
+    for _ in 0..3 {
+        check_codecs(&mut codec_initiator, &mut codec_responder);
+    }
+}
+
+#[test]
+fn test_chacha_only() {
+    let (cipher_choice, mut codec_initiator, mut codec_responder) =
+        negotiate(InitiatorConfig::chacha_only(), ResponderConfig::default()).unwrap();
+    assert!(cipher_choice == [0]);
+    check_codecs(&mut codec_initiator, &mut codec_responder);
+}
+
+#[test]
+fn test_fallback_to_chacha() {
+    // AES-GCM offered but not allowed
+    let mut responder_ciphers = CipherRegistry::new();
+    responder_ciphers.register_with(A128, aes_128_gcm).unwrap();
+    let (cipher_choice, mut codec_initiator, mut codec_responder) = negotiate(
+        InitiatorConfig::default(),
+        ResponderConfig::new(responder_ciphers),
+    )
+    .unwrap();
+    assert!(cipher_choice == [0]);
+    check_codecs(&mut codec_initiator, &mut codec_responder);
+
+    // ChaCha pinned
+    let (cipher_choice, _, _) =
+        negotiate(InitiatorConfig::default(), ResponderConfig::chacha_only()).unwrap();
+    assert!(cipher_choice == [0]);
+}
+
+#[test]
+fn test_required_cipher() {
+    let config = ResponderConfig::default().with_required_cipher(true);
+    let (cipher_choice, _, _) = negotiate(InitiatorConfig::default(), config.clone()).unwrap();
+    assert!(cipher_choice == [1, 0x47, 0x53, 0x45, 0x41]);
+
+    let mut initiator_ciphers = CipherRegistry::new();
+    initiator_ciphers.register_with(A128, aes_128_gcm).unwrap();
+    assert!(matches!(
+        negotiate(InitiatorConfig::new(initiator_ciphers), config.clone()).map_err(Error::into_kind),
+        Err(ErrorKind::UnsupportedCiphers(list)) if list == [1, b'A', b'1', b'2', b'8']
+    ));
+    assert!(matches!(
+        negotiate(InitiatorConfig::chacha_only(), config).map_err(Error::into_kind),
+        Err(ErrorKind::UnsupportedCiphers(list)) if list == [0]
+    ));
+}
+
+#[test]
+fn test_cipher_not_offered() {
+    let cipher_choice = vec![1, b'A', b'1', b'2', b'8'];
+    let (mut initiator, mut responder) = peers::<ChaCha20Poly1305, ChaCha20Poly1305>(
+        InitiatorConfig::default(),
+        ResponderConfig::default(),
+    );
+    authenticate(&mut initiator, &mut responder).unwrap();
+    assert!(matches!(
+        initiator.step_4(cipher_choice.clone()).map_err(Error::into_kind),
+        Err(ErrorKind::InvalidCipherChosed(c)) if c == cipher_choice
+    ));
+
+    // A cipher that was not offered is invalid even if it is ranked below the minimum
+    let config = InitiatorConfig::default().with_min_cipher(AESG).unwrap();
+    let (mut initiator, mut responder) =
+        peers::<DynCipher, DynCipher>(config, ResponderConfig::default());
+    authenticate(&mut initiator, &mut responder).unwrap();
+    assert!(matches!(
+        initiator.step_4(cipher_choice.clone()).map_err(Error::into_kind),
+        Err(ErrorKind::InvalidCipherChosed(c)) if c == cipher_choice
+    ));
+}
+
+#[test]
+fn test_min_cipher() {
+    let mut initiator_ciphers = CipherRegistry::default();
+    initiator_ciphers.register_with(A128, aes_128_gcm).unwrap();
+    let config = InitiatorConfig::new(initiator_ciphers)
+        .with_min_cipher(AESG)
+        .unwrap();
+    let (cipher_choice, _, _) = negotiate(config.clone(), ResponderConfig::default()).unwrap();
+    assert!(cipher_choice == [1, 0x47, 0x53, 0x45, 0x41]);
+
+    // A128 is offered but ranked below AES-256-GCM
+    let mut responder_ciphers = CipherRegistry::new();
+    responder_ciphers.register_with(A128, aes_128_gcm).unwrap();
+    assert!(matches!(
+        negotiate(config.clone(), ResponderConfig::new(responder_ciphers)).map_err(Error::into_kind),
+        Err(ErrorKind::CipherBelowMinimum(c)) if c == *b"A128"
+    ));
+    assert!(matches!(
+        negotiate(config, ResponderConfig::chacha_only()).map_err(Error::into_kind),
+        Err(ErrorKind::CipherBelowMinimum(c)) if c.is_empty()
+    ));
+
+    assert!(matches!(
+        InitiatorConfig::chacha_only().with_min_cipher(AESG).map_err(Error::into_kind),
+        Err(ErrorKind::UnsupportedCiphers(c)) if c == AESG
+    ));
+}
+
+#[test]
+fn test_auto_config() {
+    let config = InitiatorConfig::auto();
+    assert!(config.ciphers().contains(AESG) == has_aes_hardware());
+    let (cipher_choice, mut codec_initiator, mut codec_responder) =
+        negotiate(config, ResponderConfig::default()).unwrap();
+    if has_aes_hardware() {
+        assert!(cipher_choice == [1, 0x47, 0x53, 0x45, 0x41]);
+    } else {
+        assert!(cipher_choice == [0]);
+    }
+    check_codecs(&mut codec_initiator, &mut codec_responder);
+}
+
+#[test]
+fn test_siv_and_xchacha_negotiation() {
+    for code in [ASIV, XCHA] {
+        let mut responder_ciphers = CipherRegistry::all();
+        responder_ciphers.unregister(AESG);
+        responder_ciphers.unregister(if code == ASIV { XCHA } else { ASIV });
+        let (cipher_choice, mut codec_initiator, mut codec_responder) = negotiate(
+            InitiatorConfig::new(CipherRegistry::all()),
+            ResponderConfig::new(responder_ciphers),
+        )
+        .unwrap();
+        assert!(cipher_choice[1..] == code);
+        for _ in 0..3 {
+            check_codecs(&mut codec_initiator, &mut codec_responder);
+        }
+    }
+}
+
+#[test]
+fn test_typed_ciphers() {
+    // only the ciphers that the type supports are offered and chosen
+    let all = || InitiatorConfig::new(CipherRegistry::all());
+    let responder_config = || ResponderConfig::new(CipherRegistry::all());
+    let (initiator, responder) = peers::<Aes256GcmSiv, Aes256GcmSiv>(all(), responder_config());
+    let (cipher_choice, mut codec_initiator, mut codec_responder) =
+        handshake(initiator, responder).unwrap();
+    assert!(cipher_choice[1..] == ASIV);
+    check_codecs(&mut codec_initiator, &mut codec_responder);
+
+    let (initiator, responder) =
+        peers::<ChaCha20Poly1305, ChaCha20Poly1305>(all(), responder_config());
+    let (cipher_choice, _, _) = handshake(initiator, responder).unwrap();
+    assert!(cipher_choice == [0]);
+
+    // a typed session interoperate with a DynCipher one
+    let (initiator, responder) = peers::<XChaCha20Poly1305, DynCipher>(all(), responder_config());
+    let (cipher_choice, mut codec_initiator, mut codec_responder) =
+        handshake(initiator, responder).unwrap();
+    assert!(cipher_choice[1..] == XCHA);
+    check_codecs(&mut codec_initiator, &mut codec_responder);
+
+    // AES-GCM can not fall back to ChaCha
+    let (initiator, responder) =
+        peers::<DynCipher, Aes256Gcm>(InitiatorConfig::chacha_only(), responder_config());
+    assert!(matches!(
+        handshake(initiator, responder).map_err(Error::into_kind),
+        Err(ErrorKind::UnsupportedCiphers(list)) if list == [0]
+    ));
+    // the responder chose a cipher that the initiator can not build
+    let (initiator, responder) =
+        peers::<Aes256Gcm, DynCipher>(InitiatorConfig::chacha_only(), responder_config());
+    assert!(matches!(
+        handshake(initiator, responder).map_err(Error::into_kind),
+        Err(ErrorKind::InvalidCipherChosed(choice)) if choice == [0]
+    ));
+}
+
+#[test]
+fn test_split_codec() {
+    fn assert_send<T: Send>() {}
+    assert_send::<NoiseSender>();
+    assert_send::<NoiseReceiver>();
+
+    let (_, codec_initiator, mut codec_responder) =
+        negotiate(InitiatorConfig::default(), ResponderConfig::default()).unwrap();
+    let (mut sender, mut receiver) = codec_initiator.split();
+    let writer = std::thread::spawn(move || {
+        let mut messages = vec![];
+        for i in 0..3u8 {
+            let mut message = vec![i; 10];
+            sender.encrypt(&mut message).unwrap();
+            messages.push(message);
+        }
+        (sender, messages)
+    });
+    let mut message = b"ciao".to_vec();
+    codec_responder.encrypt(&mut message).unwrap();
+    let reader = std::thread::spawn(move || {
+        receiver.decrypt(&mut message).unwrap();
+        assert!(message == b"ciao");
+        receiver
+    });
+    let (sender, messages) = writer.join().unwrap();
+    let receiver = reader.join().unwrap();
+    for (i, mut message) in messages.into_iter().enumerate() {
+        codec_responder.decrypt(&mut message).unwrap();
+        assert!(message == vec![i as u8; 10]);
+    }
+
+    // halves of different codecs
+    let (_, other, _) = negotiate(InitiatorConfig::default(), ResponderConfig::default()).unwrap();
+    let (other_sender, other_receiver) = other.split();
+    let ReuniteError(sender, other_receiver) = match sender.reunite(other_receiver) {
+        Err(e) => e,
+        Ok(_) => panic!("halves of different codecs must not be reunited"),
+    };
+    assert!(other_sender.reunite(other_receiver).is_ok());
+
+    // nonces keep going after reunite
+    let mut codec_initiator = receiver.reunite(sender).ok().unwrap();
+    let mut message = b"ciao".to_vec();
     codec_initiator.encrypt(&mut message).unwrap();
-    assert!(message != "ciao".as_bytes().to_vec());
     codec_responder.decrypt(&mut message).unwrap();
+    assert!(message == b"ciao");
+}
+
+#[test]
+fn test_detached_and_slice_to_slice() {
+    let (_, mut codec_initiator, mut codec_responder) =
+        negotiate(InitiatorConfig::default(), ResponderConfig::default()).unwrap();
+
+    // same wire format as encrypt
+    let mut buf = *b"ciao";
+    let mac = codec_initiator.encrypt_detached(&mut buf).unwrap();
+    let mut message = buf.to_vec();
+    message.extend_from_slice(&mac);
+    codec_responder.decrypt(&mut message).unwrap();
+    assert!(message == b"ciao");
+
+    let mut message = b"ciao".to_vec();
+    codec_responder.encrypt(&mut message).unwrap();
+    let (buf, mac) = message.split_at_mut(4);
+    codec_initiator
+        .decrypt_detached(buf, mac[..].try_into().unwrap())
+        .unwrap();
+    assert!(buf == b"ciao");
+
+    // into a bigger pooled buffer
+    let mut out = [0; 64];
+    let len = codec_initiator.encrypt_to(b"ciao", &mut out).unwrap();
+    assert!(len == 4 + 16);
+    let mut plain = [0; 64];
+    let len = codec_responder.decrypt_to(&out[..len], &mut plain).unwrap();
+    assert!(plain[..len] == *b"ciao");
+
+    // too small output does not consume a nonce
+    assert!(codec_initiator.encrypt_to(b"ciao", &mut out[..19]).is_err());
+    let len = codec_initiator.encrypt_to(b"ciao", &mut out).unwrap();
+    assert!(codec_responder
+        .decrypt_to(&out[..len], &mut plain[..3])
+        .is_err());
+
+    // tampered message
+    out[0] ^= 1;
+    assert!(codec_responder.decrypt_to(&out[..len], &mut plain).is_err());
+    assert!(plain[..4] == [0; 4]);
+    let mut mac = [0; 16];
+    assert!(codec_responder
+        .decrypt_detached(&mut out[..4], &mac)
+        .is_err());
+    mac.copy_from_slice(&out[4..20]);
+    assert!(codec_responder.decrypt_detached(&mut [], &mac).is_err());
+}
+
+#[test]
+fn test_tampered_message_zeroed() {
+    // the failing message is zeroed, not left as ciphertext
+    let config = InitiatorConfig::default().with_min_cipher(AESG).unwrap();
+    let (cipher_choice, mut codec_initiator, mut codec_responder) =
+        negotiate(config, ResponderConfig::default()).unwrap();
+    assert!(cipher_choice == [1, 0x47, 0x53, 0x45, 0x41]);
+
+    let mut buf = [0; 20];
+    buf[..4].copy_from_slice(b"ciao");
+    codec_initiator.encrypt_slice(&mut buf, 4).unwrap();
+    buf[19] ^= 1;
+    assert!(codec_responder.decrypt_slice(&mut buf).is_err());
+    assert!(buf[..4] == [0; 4]);
+
+    let mut buf = *b"ciao";
+    let mut mac = codec_responder.encrypt_detached(&mut buf).unwrap();
+    mac[0] ^= 1;
+    assert!(codec_initiator.decrypt_detached(&mut buf, &mac).is_err());
+    assert!(buf == [0; 4]);
+
+    // fresh codecs, the failed decryptions left the nonces out of sync
+    let config = InitiatorConfig::default().with_min_cipher(AESG).unwrap();
+    let (_, mut codec_initiator, mut codec_responder) =
+        negotiate(config, ResponderConfig::default()).unwrap();
+    let msgs = [IoSlice::new(b"ciao"), IoSlice::new(b"hola")];
+    let mut out = [0; 40];
+    codec_initiator.encrypt_batch(&msgs, &mut out).unwrap();
+    out[39] ^= 1;
+    let mut lens = [20, 20];
+    assert!(codec_responder.decrypt_batch(&mut out, &mut lens).is_err());
+    assert!(lens == [4, 20] && out[..4] == *b"ciao" && out[20..24] == [0; 4]);
+}
+
+#[test]
+fn test_batch() {
+    let (_, mut codec_initiator, mut codec_responder) =
+        negotiate(InitiatorConfig::default(), ResponderConfig::default()).unwrap();
+    let msgs: Vec<Vec<u8>> = (0..10u8).map(|i| vec![i; i as usize * 3]).collect();
+    let slices: Vec<IoSlice> = msgs.iter().map(|msg| IoSlice::new(msg)).collect();
+    let total: usize = msgs.iter().map(|msg| msg.len() + 16).sum();
+
+    let mut out = vec![0; total];
+    assert!(codec_initiator
+        .encrypt_batch(&slices, &mut out[..total - 1])
+        .is_err());
+    assert!(codec_initiator.encrypt_batch(&slices, &mut out).unwrap() == total);
+
+    // same as encrypting one message at a time
+    let mut offset = 0;
+    let mut lens = vec![];
+    for msg in &msgs {
+        let mut message = out[offset..offset + msg.len() + 16].to_vec();
+        lens.push(message.len());
+        codec_responder.decrypt(&mut message).unwrap();
+        assert!(message == *msg);
+        offset += msg.len() + 16;
+    }
+
+    let mut out = vec![0; total];
+    codec_responder.encrypt_batch(&slices, &mut out).unwrap();
+    let plain_len = codec_initiator.decrypt_batch(&mut out, &mut lens).unwrap();
+    assert!(plain_len == msgs.iter().map(|msg| msg.len()).sum());
+    assert!(out[..plain_len] == msgs.concat());
+    assert!(lens == msgs.iter().map(|msg| msg.len()).collect::<Vec<_>>());
+
+    // gather the parts in a single message
+    let mut out = [0; 64];
+    let len = codec_initiator
+        .encrypt_vectored(
+            &[IoSlice::new(b"ci"), IoSlice::new(b""), IoSlice::new(b"ao")],
+            &mut out,
+        )
+        .unwrap();
+    let mut message = out[..len].to_vec();
+    codec_responder.decrypt(&mut message).unwrap();
+    assert!(message == b"ciao");
+
+    // a tampered message stops the batch
+    let mut out = vec![0; total];
+    codec_responder.encrypt_batch(&slices, &mut out).unwrap();
+    let mut lens: Vec<usize> = msgs.iter().map(|msg| msg.len() + 16).collect();
+    out[lens[0] + lens[1]] ^= 1;
+    assert!(codec_initiator.decrypt_batch(&mut out, &mut lens).is_err());
+    assert!(lens[..2] == [0, 3]);
+
+    // lengths that overflow when summed
+    assert!(codec_initiator
+        .decrypt_batch(&mut out, &mut [usize::MAX, 16])
+        .is_err());
+}
+
+/// Move `value` in a zeroed buffer owned by the test, drop it there and return the content of
+/// the buffer. The bytes are read with volatile reads so that the compiler can not assume that
+/// they are unused after the drop.
+fn memory_after_drop<T>(value: T) -> Vec<u8> {
+    let layout = std::alloc::Layout::new::<T>();
+    unsafe {
+        let buf = std::alloc::alloc_zeroed(layout);
+        assert!(!buf.is_null());
+        std::ptr::write(buf as *mut T, value);
+        std::ptr::drop_in_place(buf as *mut T);
+        let memory = (0..layout.size())
+            .map(|i| std::ptr::read_volatile(buf.add(i)))
+            .collect();
+        std::alloc::dealloc(buf, layout);
+        memory
+    }
+}
+
+fn contains(memory: &[u8], secret: &[u8]) -> bool {
+    memory.windows(secret.len()).any(|w| w == secret)
+}
+
+fn check_cipher_erased<C: AeadCipher + KeyInit>() {
+    let key = [0x42; 32];
+    let cipher = Box::new(Cipher::from_key_and_cipher(
+        key,
+        C::new_from_slice(&key).unwrap(),
+    ));
+    let memory = unsafe {
+        let ptr = &*cipher as *const Cipher<C> as *const u8;
+        std::slice::from_raw_parts(ptr, std::mem::size_of::<Cipher<C>>())
+    };
+    // both k and the key schedule hold the key before the drop
+    assert!(memory.windows(32).filter(|w| *w == key).count() >= 2);
+    assert!(!contains(&memory_after_drop(*cipher), &key));
+}
+
+#[test]
+fn test_cipher_erased_on_drop() {
+    check_cipher_erased::<ChaCha20Poly1305>();
+    check_cipher_erased::<XChaCha20Poly1305>();
+    check_cipher_erased::<Aes256Gcm>();
+    check_cipher_erased::<Aes256GcmSiv>();
+}
+
+/// Check that `Debug` redacts the handshake secrets of `role` and its `other_secrets`, and that
+/// the drop erases them
+fn check_handshake_secrets<T>(mut role: Box<T>, mut other_secrets: Vec<Vec<u8>>)
+where
+    T: HandshakeOp<ChaCha20Poly1305> + fmt::Debug,
+{
+    let (k1, k2) = role.split_keys();
+    let mut secrets = vec![
+        role.get_ck()[..32].to_vec(),
+        role.get_h()[..32].to_vec(),
+        role.get_k().unwrap().to_vec(),
+        k1.to_vec(),
+        k2.to_vec(),
+    ];
+    secrets.append(&mut other_secrets);
+    let debug = format!("{:?}", role);
+    assert!(debug.contains("k: [REDACTED], ck: [REDACTED], h: [REDACTED]"));
+    assert!(debug.contains("k1: [REDACTED], k2: [REDACTED]"));
+    let memory = memory_after_drop(*role);
+    for secret in &secrets {
+        assert!(!debug.contains(&hex::encode(secret)));
+        assert!(!debug.contains(&format!("{:?}", secret)));
+        assert!(!contains(&memory, secret));
+    }
+}
+
+#[test]
+fn test_handshake_secrets() {
+    let authority = Secp256k1::generate_key();
+    let authority_secret = authority.secret_bytes().to_vec();
+    let authority_key = authority.x_only_public_key().0;
+    let mut initiator = Initiator::<DynCipher>::new(authority_key);
+    let mut responder = Responder::<DynCipher>::new(authority);
+    assert!(format!("{:?}", initiator).starts_with("Initiator { step: 0,"));
+    authenticate(&mut initiator, &mut responder).unwrap();
+
+    let debug = format!("{:?}", initiator);
+    assert!(debug.starts_with("Initiator { step: 4, dh: \"secp256k1\", hash: \"SHA256\","));
+    let debug = format!("{:?}", responder);
+    assert!(debug.starts_with("Responder { step: 3,"));
+    assert!(debug.contains(&hex::encode(authority_key.serialize())));
+    check_handshake_secrets(initiator, vec![]);
+    check_handshake_secrets(responder, vec![authority_secret]);
+}
+
+#[test]
+fn test_debug() {
+    let (_, mut codec_initiator, codec_responder) =
+        negotiate(InitiatorConfig::default(), ResponderConfig::default()).unwrap();
+    let mut message = b"ciao".to_vec();
+    codec_initiator.encrypt(&mut message).unwrap();
+    assert!(
+        format!("{:?}", codec_initiator)
+            == "NoiseCodec { encryptor: Cipher { cipher: AES-256-GCM, n: 1, k: [REDACTED] }, \
+                decryptor: Cipher { cipher: AES-256-GCM, n: 0, k: [REDACTED] } }"
+    );
+    let (sender, _) = codec_responder.split();
+    assert!(format!("{:?}", sender).starts_with("NoiseSender { encryptor: Cipher {"));
+
+    let mut message = [0; 74];
+    message[..2].copy_from_slice(&1_u16.to_le_bytes());
+    message[10] = 0xab;
+    let message = SignatureNoiseMessage::from(message);
+    let debug = format!("{:?}", message);
+    assert!(debug.starts_with(
+        "SignatureNoiseMessage { version: 1, valid_from: 0, not_valid_after: 0, signature: ab00"
+    ));
+}
+
+#[test]
+fn test_certificate_errors() {
+    let authority = Secp256k1::generate_key();
+    let mut message = [0; 74];
+    message[2..6].copy_from_slice(&100_u32.to_le_bytes());
+    message[6..10].copy_from_slice(&200_u32.to_le_bytes());
+    let static_key = [2; 32];
+    SignatureNoiseMessage::sign(&mut message, &static_key, &authority);
+    let pk = authority.x_only_public_key().0;
+    let verify_at = |message: [u8; 74], pk, now| {
+        SignatureNoiseMessage::from(message).verify_at(pk, &static_key, now)
+    };
+    assert!(verify_at(message, &pk, 150).is_ok());
+    assert!(
+        verify_at(message, &pk, 99)
+            == Err(CertificateError::NotYetValid {
+                valid_from: 100,
+                now: 99
+            })
+    );
+    assert!(
+        verify_at(message, &pk, 201)
+            == Err(CertificateError::Expired {
+                not_valid_after: 200,
+                now: 201
+            })
+    );
+    let other = Secp256k1::generate_key().x_only_public_key().0;
+    assert!(verify_at(message, &other, 150) == Err(CertificateError::SignatureMismatch));
+    let certificate = SignatureNoiseMessage::from(message);
+    assert!(certificate.check_validity_at(150).is_ok());
+    assert!(matches!(
+        certificate.check_validity_at(201),
+        Err(CertificateError::Expired { .. })
+    ));
+
+    let mut unknown_version = message;
+    unknown_version[0] = 7;
+    assert!(verify_at(unknown_version, &pk, 150) == Err(CertificateError::UnsupportedVersion(7)));
+    // s is not lower than the curve order
+    let mut malformed = message;
+    malformed[42..].fill(0xff);
+    assert!(verify_at(malformed, &pk, 150) == Err(CertificateError::MalformedSignature));
+
+    // The responder certificate is signed by another authority
+    let mut initiator = Initiator::<DynCipher>::new(other);
+    let mut responder = Responder::<DynCipher>::new(authority);
+    let error = authenticate(&mut initiator, &mut responder).unwrap_err();
+    assert!(error.phase() == Some(Phase::Certificate));
+    assert!(matches!(
+        error.kind(),
+        ErrorKind::InvalidCertificate(CertificateError::SignatureMismatch)
+    ));
+    assert!(
+        error.to_string()
+            == "initiator failed at certificate: invalid certificate: certificate not signed by the authority"
+    );
+}
+
+#[test]
+fn test_certificate_extensions() {
+    let authority = Secp256k1::generate_key();
+    let pk = authority.x_only_public_key().0;
+    let extensions = vec![
+        CertificateExtension::new(CertificateExtension::POOL_ID, "pool"),
+        CertificateExtension::new(CertificateExtension::HOSTNAME, "pool.example.com"),
+    ];
+    let mut initiator = Initiator::<DynCipher>::new(pk);
+    let mut responder = Responder::<DynCipher>::new(authority);
+    responder
+        .set_certificate_extensions(extensions.clone())
+        .unwrap();
+    let first_message = initiator.step_0().unwrap();
+    let second_message = responder.step_1(first_message, 60).unwrap();
+    assert!(second_message.len() == 170 + 4 + 4 + 4 + 16);
+    assert!(second_message.len() == responder.second_message_len());
+    let cipher_list = initiator.step_2(second_message).unwrap();
+    let certificate = initiator.certificate().unwrap();
+    assert!(certificate.version == 1);
+    assert!(certificate.extensions == extensions);
+    assert!(
+        certificate.extension(CertificateExtension::HOSTNAME) == Some(&b"pool.example.com"[..])
+    );
+    assert!(certificate
+        .extension(CertificateExtension::SUBPROTOCOLS)
+        .is_none());
+    let (cipher_choice, _) = responder.step_3(cipher_list).unwrap();
+    initiator.step_4(cipher_choice).unwrap();
+
+    // The extensions are covered by the signature
+    let mut certificate = SignatureNoiseMessage::new(100, 200, extensions.clone()).unwrap();
+    certificate.sign_with(&[2; 32], &authority);
+    let mut encoded = vec![0; certificate.encoded_len()];
+    assert!(certificate.encode(&mut encoded).unwrap() == encoded.len());
+    assert!(matches!(
+        certificate.encode(&mut encoded[..80]).map_err(Error::into_kind),
+        Err(ErrorKind::OutputBufferTooSmall(len)) if len == encoded.len()
+    ));
+    let parsed = SignatureNoiseMessage::from_bytes(&encoded).unwrap();
+    assert!(parsed.extensions == extensions);
+    assert!(parsed.verify_at(&pk, &[2; 32], 150).is_ok());
+    let mut signed = vec![0; encoded.len()];
+    let len =
+        SignatureNoiseMessage::sign_into(100, 200, &extensions, &[2; 32], &authority, &mut signed)
+            .unwrap();
+    let parsed = SignatureNoiseMessage::from_bytes(&signed[..len]).unwrap();
+    assert!(len == encoded.len() && parsed.extensions == extensions);
+    assert!(parsed.verify_at(&pk, &[2; 32], 150).is_ok());
+    *encoded.last_mut().unwrap() ^= 1;
+    let tampered = SignatureNoiseMessage::from_bytes(&encoded).unwrap();
+    assert!(tampered.verify_at(&pk, &[2; 32], 150) == Err(CertificateError::SignatureMismatch));
+
+    // Truncated extension, extensions in a version 0 certificate and unknown versions
+    let parse = |bytes: &[u8]| SignatureNoiseMessage::from_bytes(bytes).map(|_| ());
+    assert!(parse(&encoded[..encoded.len() - 1]) == Err(CertificateError::Malformed));
+    let mut v0 = encoded.clone();
+    v0[0] = 0;
+    assert!(parse(&v0) == Err(CertificateError::Malformed));
+    assert!(parse(&v0[..74]).is_ok());
+    assert!(parse(&v0[..73]) == Err(CertificateError::Malformed));
+    let mut v2 = encoded.clone();
+    v2[0] = 2;
+    assert!(parse(&v2) == Err(CertificateError::UnsupportedVersion(2)));
+    let duplicated = vec![extensions[0].clone(), extensions[0].clone()];
+    assert!(matches!(
+        responder_with_extensions(duplicated).map_err(Error::into_kind),
+        Err(ErrorKind::InvalidCertificate(CertificateError::Malformed))
+    ));
+    let too_long = vec![CertificateExtension::new(1, vec![0; MAX_EXTENSIONS_LEN])];
+    assert!(matches!(
+        responder_with_extensions(too_long).map_err(Error::into_kind),
+        Err(ErrorKind::InvalidCertificate(CertificateError::Malformed))
+    ));
+}
+
+fn responder_with_extensions(extensions: Vec<CertificateExtension>) -> Result<(), Error> {
+    let mut responder = Responder::<DynCipher>::new(Secp256k1::generate_key());
+    responder.set_certificate_extensions(extensions)
+}
+
+#[test]
+fn test_initiator_rejects_unknown_certificate_version() {
+    let authority = Secp256k1::generate_key();
+    let mut responder = snow::Builder::new("Noise_NX_25519_ChaChaPoly_SHA256".parse().unwrap())
+        .local_private_key(&rand::random::<[u8; 32]>())
+        .build_responder()
+        .unwrap();
+    let mut initiator =
+        Initiator::<ChaCha20Poly1305, X25519>::with_dh(authority.x_only_public_key().0, X25519);
+
+    let first_message = initiator.step_0().unwrap();
+    let mut buf = [0; 170];
+    responder.read_message(&first_message, &mut buf).unwrap();
+    let mut signature_message = [0; 74];
+    signature_message[0] = 2;
+    signature_message[6..10].copy_from_slice(&u32::MAX.to_le_bytes());
+    SignatureNoiseMessage::sign(&mut signature_message, &[0; 32], &authority);
+    let len = responder
+        .write_message(&signature_message, &mut buf)
+        .unwrap();
+    let error = initiator.step_2(buf[..len].to_vec()).unwrap_err();
+    assert!(error.phase() == Some(Phase::Certificate));
+    assert!(matches!(
+        error.kind(),
+        ErrorKind::InvalidCertificate(CertificateError::UnsupportedVersion(2))
+    ));
+}
+
+#[test]
+fn test_certificate_bound_to_static_key() {
+    let authority = Secp256k1::generate_key();
+    let pk = authority.x_only_public_key().0;
+    let mut message = [0; 74];
+    message[6..10].copy_from_slice(&u32::MAX.to_le_bytes());
+    SignatureNoiseMessage::sign(&mut message, &[2; 32], &authority);
+    let certificate = SignatureNoiseMessage::from(message);
+    assert!(certificate.verify(&pk, &[2; 32]).is_ok());
+    assert!(certificate.verify(&pk, &[3; 32]) == Err(CertificateError::SignatureMismatch));
+
+    // A server replays a valid certificate issued for another static key
+    let static_key: [u8; 32] = rand::random();
+    let mut responder = snow::Builder::new("Noise_NX_25519_ChaChaPoly_SHA256".parse().unwrap())
+        .local_private_key(&static_key)
+        .build_responder()
+        .unwrap();
+    let mut initiator = Initiator::<ChaCha20Poly1305, X25519>::with_dh(pk, X25519);
+    let first_message = initiator.step_0().unwrap();
+    let mut buf = [0; 170];
+    responder.read_message(&first_message, &mut buf).unwrap();
+    let len = responder.write_message(&message, &mut buf).unwrap();
+    let error = initiator.step_2(buf[..len].to_vec()).unwrap_err();
+    assert!(error.phase() == Some(Phase::Certificate));
+    assert!(matches!(
+        error.kind(),
+        ErrorKind::InvalidCertificate(CertificateError::SignatureMismatch)
+    ));
+}
+
+#[test]
+fn test_authority_key_rollover() {
+    let old = Secp256k1::generate_key();
+    let new = Secp256k1::generate_key();
+    let now = SignatureNoiseMessage::now();
+    let store = AuthorityTrustStore::new()
+        .with_authority(
+            TrustedAuthority::new(old.x_only_public_key().0)
+                .with_validity(0, now + 3600)
+                .with_label("old"),
+        )
+        .with_authority(
+            TrustedAuthority::new(new.x_only_public_key().0)
+                .with_validity(now - 3600, u32::MAX)
+                .with_label("new"),
+        );
+    for (authority, label) in [(old, "old"), (new, "new")] {
+        let mut initiator = Initiator::<DynCipher>::new(old.x_only_public_key().0);
+        initiator.set_trust_store(store.clone());
+        let mut responder = Responder::<DynCipher>::new(authority);
+        authenticate(&mut initiator, &mut responder).unwrap();
+        assert!(initiator.authority().unwrap().label() == Some(label));
+    }
+
+    // The new key is not trusted yet
+    let store = AuthorityTrustStore::new().with_authority(
+        TrustedAuthority::new(new.x_only_public_key().0).with_validity(now + 3600, u32::MAX),
+    );
+    let mut initiator = Initiator::<DynCipher>::new(new.x_only_public_key().0);
+    initiator.set_trust_store(store);
+    let mut responder = Responder::<DynCipher>::new(new);
+    let error = authenticate(&mut initiator, &mut responder).unwrap_err();
+    assert!(matches!(
+        error.kind(),
+        ErrorKind::InvalidCertificate(CertificateError::AuthorityNotValid)
+    ));
+}
+
+#[test]
+fn test_revoked_static_key() {
+    let authority = Secp256k1::generate_key();
+    let pk = authority.x_only_public_key().0;
+    let secret = rand::random();
+    let static_key = X25519KeyPair::from_secret(secret);
+    let revocation = Revocation::static_key(X25519.pub_key(&static_key)).unwrap();
+    let mut list = RevocationList::new(0, vec![revocation]).unwrap();
+    list.sign(&authority);
+    let mut revocations = RevocationSet::new();
+    revocations
+        .add_list(&RevocationList::from_bytes(&list.encode()).unwrap(), &pk)
+        .unwrap();
+
+    let mut initiator = Initiator::<ChaCha20Poly1305, X25519>::with_dh(pk, X25519);
+    initiator.set_revocations(revocations);
+    let mut responder = Responder::<ChaCha20Poly1305, X25519>::with_dh(
+        X25519KeyPair::from_secret(secret),
+        authority,
+        X25519,
+    );
+    let error = authenticate(&mut initiator, &mut responder).unwrap_err();
+    assert!(error.phase() == Some(Phase::Certificate));
+    assert!(matches!(
+        error.kind(),
+        ErrorKind::InvalidCertificate(CertificateError::Revoked)
+    ));
+    assert!(
+        error.to_string()
+            == "initiator failed at certificate: invalid certificate: certificate or server key revoked"
+    );
+
+    // ElligatorSwift encodings of the same key are randomized, the x-only key is revoked
+    let key_pair = Secp256k1::generate_key();
+    let pk = key_pair.x_only_public_key().0;
+    let dh = Secp256k1::new(ProtocolVersion::EllSwift);
+    let revocation = Revocation::static_key(dh.pub_key(&dh.keypair(key_pair))).unwrap();
+    let mut list = RevocationList::new(0, vec![revocation]).unwrap();
+    list.sign(&key_pair);
+    let mut revocations = RevocationSet::new();
+    revocations.add_list(&list, &pk).unwrap();
+    let mut initiator =
+        Initiator::<ChaCha20Poly1305>::new_with_version(pk, ProtocolVersion::EllSwift);
+    initiator.set_revocations(revocations);
+    let mut responder =
+        Responder::<ChaCha20Poly1305>::new_with_version(key_pair, ProtocolVersion::EllSwift);
+    let first_message = initiator.step_0().unwrap();
+    let second_message = responder.step_1(first_message, 60).unwrap();
+    assert!(matches!(
+        initiator.step_2(second_message).map_err(Error::into_kind),
+        Err(ErrorKind::InvalidCertificate(CertificateError::Revoked))
+    ));
+}
+
+#[test]
+fn test_trust_on_first_use() {
+    let path = std::env::temp_dir().join(format!("known-servers-{}", rand::random::<u64>()));
+    let authority = Secp256k1::generate_key();
+    let static_key = Secp256k1::generate_key();
+    let handshake_with = |initiator: &mut Initiator, dh: Secp256k1, static_key, authority| {
+        let mut responder = Responder::<DynCipher>::with_dh(dh.keypair(static_key), authority, dh);
+        let extension = CertificateExtension::authority_key(&authority.x_only_public_key().0);
+        responder
+            .set_certificate_extensions(vec![extension])
+            .unwrap();
+        authenticate(initiator, &mut responder).map(|_| ())
+    };
+    let handshake = |initiator: &mut Initiator, static_key, authority| {
+        handshake_with(initiator, Secp256k1::default(), static_key, authority)
+    };
+    let tofu = || Initiator::<DynCipher>::new_tofu(KnownServers::new(&path), "pool:34254");
+
+    // The keys are recorded on the first connection
+    let mut initiator = tofu();
+    handshake(&mut initiator, static_key, authority).unwrap();
+    let recorded = KnownServers::new(&path).get("pool:34254").unwrap().unwrap();
+    assert!(recorded.authority == Some(authority.x_only_public_key().0));
+    assert!(initiator.authority().unwrap().key() == &authority.x_only_public_key().0);
+    handshake(&mut tofu(), static_key, authority).unwrap();
+
+    // Another static key or another authority
+    let other = Secp256k1::generate_key();
+    for (static_key, authority) in [(other, authority), (static_key, other)] {
+        let error = handshake(&mut tofu(), static_key, authority).unwrap_err();
+        assert!(error.phase() == Some(Phase::Certificate));
+        assert!(matches!(error.kind(), ErrorKind::KnownServerChanged(e) if e == "pool:34254"));
+        assert!(
+            error.to_string()
+                == "initiator failed at certificate: keys of known server pool:34254 changed, \
+                    it may be impersonated"
+        );
+    }
+
+    // On top of the trust store only the static key is pinned
+    let mut initiator = Initiator::<DynCipher>::new(authority.x_only_public_key().0);
+    initiator.set_known_servers(KnownServers::new(&path), "other:34254");
+    handshake(&mut initiator, static_key, authority).unwrap();
+    let recorded = KnownServers::new(&path)
+        .get("other:34254")
+        .unwrap()
+        .unwrap();
+    assert!(recorded.authority.is_none());
+
+    // ElligatorSwift encodings are randomized, the x-only static key is recorded
+    let dh = Secp256k1::new(ProtocolVersion::EllSwift);
+    let tofu = |endpoint| {
+        let mut initiator = Initiator::<DynCipher>::with_dh(authority.x_only_public_key().0, dh);
+        initiator.set_trust_store(AuthorityTrustStore::new());
+        initiator.set_known_servers(KnownServers::new(&path), endpoint);
+        initiator
+    };
+    handshake_with(&mut tofu("ellswift:34254"), dh, static_key, authority).unwrap();
+    handshake_with(&mut tofu("ellswift:34254"), dh, static_key, authority).unwrap();
+    let recorded = KnownServers::new(&path)
+        .get("ellswift:34254")
+        .unwrap()
+        .unwrap();
+    assert!(recorded.static_key == static_key.x_only_public_key().0.serialize());
+
+    // Without the authority extension the validity period is still checked
+    let mut responder = snow::Builder::new("Noise_NX_25519_ChaChaPoly_SHA256".parse().unwrap())
+        .local_private_key(&rand::random::<[u8; 32]>())
+        .build_responder()
+        .unwrap();
+    let mut initiator =
+        Initiator::<ChaCha20Poly1305, X25519>::with_dh(authority.x_only_public_key().0, X25519);
+    initiator.set_trust_store(AuthorityTrustStore::new());
+    initiator.set_known_servers(KnownServers::new(&path), "expired:34254");
+    let first_message = initiator.step_0().unwrap();
+    let mut buf = [0; 170];
+    responder.read_message(&first_message, &mut buf).unwrap();
+    let mut signature_message = [0; 74];
+    let expired = SignatureNoiseMessage::now() - 1;
+    signature_message[6..10].copy_from_slice(&expired.to_le_bytes());
+    let len = responder
+        .write_message(&signature_message, &mut buf)
+        .unwrap();
+    assert!(matches!(
+        initiator
+            .step_2(buf[..len].to_vec())
+            .map_err(Error::into_kind),
+        Err(ErrorKind::InvalidCertificate(
+            CertificateError::Expired { .. }
+        ))
+    ));
+    assert!(KnownServers::new(&path)
+        .get("expired:34254")
+        .unwrap()
+        .is_none());
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_pinned_static_keys() {
+    let authority = Secp256k1::generate_key();
+    let pk = authority.x_only_public_key().0;
+    let pinned = Secp256k1::generate_key();
+    let other = Secp256k1::generate_key();
+    let other_key = other.x_only_public_key().0.serialize();
+    let unrelated_key = Secp256k1::generate_key().x_only_public_key().0.serialize();
+    let handshake = |version, pinned_key: &[u8], static_key| {
+        let dh = Secp256k1::new(version);
+        let mut initiator = Initiator::<DynCipher>::new_with_version(pk, version);
+        initiator
+            .set_pinned_static_keys(&[&unrelated_key[..], pinned_key])
+            .unwrap();
+        let mut responder = Responder::<DynCipher>::with_dh(dh.keypair(static_key), authority, dh);
+        authenticate(&mut initiator, &mut responder).map(|_| ())
+    };
+    let dh = Secp256k1::new(ProtocolVersion::default());
+    let pinned_key = dh.pub_key(&dh.keypair(pinned)).to_vec();
+    handshake(ProtocolVersion::default(), &pinned_key, pinned).unwrap();
+
+    // The certificate is valid but the static key is not pinned
+    let error = handshake(ProtocolVersion::default(), &pinned_key, other).unwrap_err();
+    assert!(error.phase() == Some(Phase::StaticKey));
+    assert!(matches!(error.kind(), ErrorKind::StaticKeyNotPinned(key) if key == &other_key));
+    assert!(
+        error.to_string()
+            == format!(
+                "initiator failed at static key: static key {} is not pinned",
+                hex::encode(other_key)
+            )
+    );
+
+    // ElligatorSwift encodings are randomized, the pinned encoding is not the one sent
+    let version = ProtocolVersion::EllSwift;
+    let dh = Secp256k1::new(version);
+    let pinned_key = dh.pub_key(&dh.keypair(pinned)).to_vec();
+    handshake(version, &pinned_key, pinned).unwrap();
+    handshake(version, &pinned_key, pinned).unwrap();
+    let x_only = pinned.x_only_public_key().0.serialize();
+    handshake(version, &x_only, pinned).unwrap();
+    assert!(matches!(
+        handshake(version, &pinned_key, other).map_err(Error::into_kind),
+        Err(ErrorKind::StaticKeyNotPinned(key)) if key == other_key
+    ));
 
-    assert!(message == "ciao".as_bytes().to_vec());
+    // Keys that are not valid public keys are rejected, whatever their length
+    for version in [ProtocolVersion::default(), version] {
+        let mut initiator = Initiator::<DynCipher>::new_with_version(pk, version);
+        for key in [&[2; 33][..], &[0xff; 32]] {
+            assert!(matches!(
+                initiator
+                    .set_pinned_static_keys(&[key])
+                    .map_err(Error::into_kind),
+                Err(ErrorKind::InvalidPublicKey)
+            ));
+        }
+    }
 }
@@ -0,0 +1,163 @@
+use crate::signature_message::{CertificateError, SignatureNoiseMessage};
+use secp256k1::XOnlyPublicKey;
+
+/// Authority key trusted by the [`crate::Initiator`], it is only used to verify certificates
+/// between `not_before` and `not_after` (seconds since the unix epoch, both included)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrustedAuthority {
+    key: XOnlyPublicKey,
+    not_before: u32,
+    not_after: u32,
+    label: Option<String>,
+}
+
+impl TrustedAuthority {
+    /// Key always valid and without label
+    pub fn new(key: XOnlyPublicKey) -> Self {
+        Self {
+            key,
+            not_before: 0,
+            not_after: u32::MAX,
+            label: None,
+        }
+    }
+
+    /// Trust the key only between `not_before` and `not_after`
+    pub fn with_validity(mut self, not_before: u32, not_after: u32) -> Self {
+        self.not_before = not_before;
+        self.not_after = not_after;
+        self
+    }
+
+    /// Name of the key, e.g. to log which key signed a certificate
+    pub fn with_label(mut self, label: impl Into<String>) -> Self {
+        self.label = Some(label.into());
+        self
+    }
+
+    pub fn key(&self) -> &XOnlyPublicKey {
+        &self.key
+    }
+
+    pub fn label(&self) -> Option<&str> {
+        self.label.as_deref()
+    }
+
+    pub fn is_valid_at(&self, now: u32) -> bool {
+        self.not_before <= now && now <= self.not_after
+    }
+}
+
+/// Authority keys trusted by the [`crate::Initiator`]. A certificate is accepted if one of the
+/// keys valid now signed it, so a pool can roll its authority key by publishing the new key
+/// some time before the old one expires.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AuthorityTrustStore {
+    authorities: Vec<TrustedAuthority>,
+}
+
+impl AuthorityTrustStore {
+    /// Store without keys, it rejects every certificate
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_authority(mut self, authority: TrustedAuthority) -> Self {
+        self.add(authority);
+        self
+    }
+
+    pub fn add(&mut self, authority: TrustedAuthority) {
+        self.authorities.push(authority);
+    }
+
+    pub fn authorities(&self) -> &[TrustedAuthority] {
+        &self.authorities
+    }
+
+    /// Check that `certificate` is valid now for the responder with static public key
+    /// `static_key`. Return the authority that signed it.
+    pub fn verify(
+        &self,
+        certificate: &SignatureNoiseMessage,
+        static_key: &[u8],
+    ) -> Result<&TrustedAuthority, CertificateError> {
+        self.verify_at(certificate, static_key, SignatureNoiseMessage::now())
+    }
+
+    /// Same as [`AuthorityTrustStore::verify`] with `now` seconds since the unix epoch
+    pub fn verify_at(
+        &self,
+        certificate: &SignatureNoiseMessage,
+        static_key: &[u8],
+        now: u32,
+    ) -> Result<&TrustedAuthority, CertificateError> {
+        let mut error = CertificateError::SignatureMismatch;
+        for authority in &self.authorities {
+            match certificate.verify_at(&authority.key, static_key, now) {
+                Ok(()) if authority.is_valid_at(now) => return Ok(authority),
+                Ok(()) => error = CertificateError::AuthorityNotValid,
+                Err(CertificateError::SignatureMismatch) => (),
+                // Any other error does not depend on the key
+                Err(e) => return Err(e),
+            }
+        }
+        Err(error)
+    }
+}
+
+impl From<XOnlyPublicKey> for AuthorityTrustStore {
+    fn from(key: XOnlyPublicKey) -> Self {
+        Self::new().with_authority(TrustedAuthority::new(key))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Secp256k1;
+
+    #[test]
+    fn test_verify_at() {
+        let old = Secp256k1::generate_key();
+        let new = Secp256k1::generate_key();
+        let store = AuthorityTrustStore::new()
+            .with_authority(
+                TrustedAuthority::new(old.x_only_public_key().0)
+                    .with_validity(0, 1000)
+                    .with_label("old"),
+            )
+            .with_authority(
+                TrustedAuthority::new(new.x_only_public_key().0)
+                    .with_validity(500, u32::MAX)
+                    .with_label("new"),
+            );
+        let certificate = |authority| {
+            let mut message = [0; 74];
+            message[6..10].copy_from_slice(&u32::MAX.to_le_bytes());
+            SignatureNoiseMessage::sign(&mut message, &[2; 32], authority);
+            SignatureNoiseMessage::from(message)
+        };
+        let (old, new) = (certificate(&old), certificate(&new));
+        let label = |now, certificate| {
+            store
+                .verify_at(certificate, &[2; 32], now)
+                .map(|a| a.label().unwrap())
+        };
+
+        // Both keys are trusted during the overlap
+        assert!(label(100, &old) == Ok("old"));
+        assert!(label(700, &old) == Ok("old"));
+        assert!(label(700, &new) == Ok("new"));
+        assert!(label(100, &new) == Err(CertificateError::AuthorityNotValid));
+        assert!(label(2000, &old) == Err(CertificateError::AuthorityNotValid));
+        assert!(label(2000, &new) == Ok("new"));
+
+        let other = certificate(&Secp256k1::generate_key());
+        assert!(label(700, &other) == Err(CertificateError::SignatureMismatch));
+        assert!(
+            AuthorityTrustStore::new().verify_at(&new, &[2; 32], 700)
+                == Err(CertificateError::SignatureMismatch)
+        );
+    }
+}